@@ -0,0 +1,223 @@
+// ------------------------
+// --- CLI AGENT CLIENT ---
+// ------------------------
+
+use super::{
+    AgentTarget,
+    output::{ExitCode, Outcome},
+};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+/// Longer than the agent's default command timeout,
+/// so that the agent answers before the CLI gives up.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A response from the running agent
+#[derive(Debug, Clone)]
+pub struct HttpResp {
+    pub code: u16,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl HttpResp {
+    /// Parses a raw HTTP response. Header names are lowercased.
+    pub fn parse(raw: &str) -> Option<Self> {
+        let (head, body) = raw.split_once("\r\n\r\n")?;
+        let mut lines = head.lines();
+        let code = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+        let headers = parse_headers(lines);
+
+        Some(Self {
+            code,
+            headers,
+            body: body.to_string(),
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// The body parsed as JSON, or null
+    pub fn json(&self) -> Value {
+        serde_json::from_str(self.body.trim()).unwrap_or(Value::Null)
+    }
+
+    /// The `error` in the body of a failure response
+    pub fn error(&self) -> String {
+        match self.json()["error"].as_str() {
+            Some(error) => error.to_string(),
+            None => format!("The agent responded with HTTP status {}.", self.code),
+        }
+    }
+
+    /// Maps a failure response to an outcome with its exit code
+    pub fn failure(&self, command: &'static str) -> Outcome {
+        let error = self.error();
+
+        let outcome = match self.code {
+            400 | 404 => Outcome::new(command, "usage", ExitCode::Usage, error),
+            401 => Outcome::new(command, "unauthorized", ExitCode::Unauthorized, error),
+            403 if error.starts_with("not_connected") => {
+                Outcome::new(command, "connect_failed", ExitCode::ConnectFailed, error)
+            }
+            403 if error.starts_with("unauthorized") => {
+                Outcome::new(command, "unauthorized", ExitCode::Unauthorized, error)
+            }
+            408 => Outcome::new(command, "timeout", ExitCode::Timeout, error),
+            _ => Outcome::failed(command, error),
+        };
+
+        outcome.with("http_status", self.code)
+    }
+}
+
+/// The `X-Fleet-ID` and `X-Device-ID` headers that the agent validates
+pub fn device_headers(target: &AgentTarget) -> Vec<(&'static str, String)> {
+    vec![
+        ("X-Fleet-ID", target.fleet_id.clone()),
+        ("X-Device-ID", target.device_id.clone()),
+    ]
+}
+
+/// Maps an error talking to the agent's socket to an outcome
+pub fn io_failure(command: &'static str, e: &io::Error) -> Outcome {
+    match e.kind() {
+        ErrorKind::NotFound | ErrorKind::ConnectionRefused => Outcome::new(
+            command,
+            "not_running",
+            ExitCode::NotRunning,
+            "The agent is not running.",
+        ),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => Outcome::new(
+            command,
+            "timeout",
+            ExitCode::Timeout,
+            "The agent did not respond in time.",
+        ),
+        _ => Outcome::failed(command, format!("Failed to talk to the agent: {e}")),
+    }
+}
+
+/// Sends a request to the agent and reads the whole response
+pub fn request(
+    sock_file: &Path,
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: Option<&str>,
+) -> io::Result<HttpResp> {
+    let mut stream = send(sock_file, method, path, headers, body)?;
+
+    let mut buffer = String::new();
+    stream.read_to_string(&mut buffer)?;
+
+    HttpResp::parse(&buffer)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid response from the agent"))
+}
+
+/// Sends a request to the agent and reads the response head,
+/// leaving the body to be read from the returned reader.
+pub fn stream(
+    sock_file: &Path,
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+) -> io::Result<(u16, BufReader<UnixStream>)> {
+    let stream = send(sock_file, method, path, headers, None)?;
+    stream.set_read_timeout(None)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+
+    let code = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid response from the agent"))?;
+
+    // Skip past the headers
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    Ok((code, reader))
+}
+
+fn send(
+    sock_file: &Path,
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: Option<&str>,
+) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(sock_file)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut req = format!("{method} {path} HTTP/1.1\r\n");
+
+    for (name, value) in headers {
+        req.push_str(&format!("{name}: {value}\r\n"));
+    }
+
+    if let Some(body) = body {
+        req.push_str("Content-Type: application/json\r\n");
+        req.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+
+    req.push_str("\r\n");
+    req.push_str(body.unwrap_or_default());
+
+    stream.write_all(req.as_bytes())?;
+    Ok(stream)
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect()
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_resp() {
+        let raw = "HTTP/1.1 200 OK\r\nX-Mail-ID: abc\r\nContent-Type: application/json\r\n\r\n{\"connected\":true}";
+        let resp = HttpResp::parse(raw).unwrap();
+        assert_eq!(resp.code, 200);
+        assert_eq!(resp.header("x-mail-id"), Some("abc"));
+        assert_eq!(resp.json()["connected"], true);
+
+        let raw = "HTTP/1.1 403 Forbidden\r\n\r\n{\"error\":\"not_connected: Still connecting\"}";
+        let json = HttpResp::parse(raw).unwrap().failure("send").to_json();
+        assert_eq!(json["status"], "connect_failed");
+        assert_eq!(json["exit_code"], ExitCode::ConnectFailed as i32);
+        assert_eq!(json["http_status"], 403);
+
+        let raw = "HTTP/1.1 408 Request Timeout\r\n\r\n{\"error\":\"Operation timed out\"}";
+        let json = HttpResp::parse(raw).unwrap().failure("send").to_json();
+        assert_eq!(json["exit_code"], ExitCode::Timeout as i32);
+
+        assert!(HttpResp::parse("garbage").is_none());
+    }
+}
@@ -0,0 +1,273 @@
+// -----------------------
+// --- CLI ENVIRONMENT ---
+// -----------------------
+
+use crate::{
+    http_server::{Rate, RateLimits},
+    moonlight_codec::{CodecLimits, DEFAULT_DRAIN_TIMEOUT, DEFAULT_MAX_PENDING_TXNS},
+};
+use anyhow::{Context, Error, Result, anyhow};
+use std::{collections::HashMap, env::var, fs::read_to_string, path::Path, time::Duration};
+
+/// The `FOSTROM_*` settings, read from the environment variables,
+/// falling back to the config file passed with `--config`.
+/// When the running agent reloads its configuration, the config file
+/// takes precedence instead, falling back to the environment variables.
+///
+/// The config file has one `NAME=value` per line, like an env file:
+///
+/// ```text
+/// # Comments and blank lines are skipped
+/// FOSTROM_FLEET_ID=...
+/// export FOSTROM_DEVICE_ID="..."
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Env {
+    file: HashMap<String, String>,
+    file_first: bool,
+}
+
+impl Env {
+    pub fn load(config_file: Option<&Path>) -> Result<Self> {
+        match config_file {
+            None => Ok(Self::default()),
+            Some(path) => {
+                let contents = read_to_string(path).with_context(|| {
+                    format!("Failed to read the config file {}", path.display())
+                })?;
+                let file = parse_env_file(&contents)
+                    .with_context(|| format!("Invalid config file {}", path.display()))?;
+                Ok(Self {
+                    file,
+                    file_first: false,
+                })
+            }
+        }
+    }
+
+    /// Reads the config file before the environment variables, which
+    /// can't change while the agent runs, so that a reload applies the file
+    pub fn file_first(self) -> Self {
+        Self {
+            file_first: true,
+            ..self
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        match self.file_first {
+            true => self.in_file(name).or_else(|| var(name).ok()),
+            false => var(name).ok().or_else(|| self.in_file(name)),
+        }
+    }
+
+    /// The value in the config file, regardless of the environment variable
+    pub fn in_file(&self, name: &str) -> Option<String> {
+        self.file.get(name).cloned()
+    }
+
+    pub fn require(&self, name: &str) -> Result<String> {
+        self.get(name).ok_or_else(env_error)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|v| v == "true" || v == "1")
+    }
+
+    /// Reads the optional $FOSTROM_MAX_FRAME_SIZE and $FOSTROM_MAX_BUFFER_SIZE,
+    /// both in bytes, falling back to the defaults when unset.
+    pub fn codec_limits(&self) -> Result<CodecLimits> {
+        let defaults = CodecLimits::default();
+        let max_frame_size = self.size("FOSTROM_MAX_FRAME_SIZE", defaults.max_frame_size)?;
+        let max_buffer_size = self.size("FOSTROM_MAX_BUFFER_SIZE", defaults.max_buffer_size)?;
+
+        if max_buffer_size < max_frame_size {
+            return Err(anyhow!(
+                "$FOSTROM_MAX_BUFFER_SIZE ({max_buffer_size}) cannot be smaller than $FOSTROM_MAX_FRAME_SIZE ({max_frame_size})"
+            ));
+        }
+
+        Ok(CodecLimits {
+            max_frame_size,
+            max_buffer_size,
+        })
+    }
+
+    /// Reads the optional $FOSTROM_DRAIN_TIMEOUT, in seconds, which is how long
+    /// the agent waits for the requests awaiting a response when it's stopped.
+    pub fn drain_timeout(&self) -> Result<Duration> {
+        match self.get("FOSTROM_DRAIN_TIMEOUT") {
+            None => Ok(DEFAULT_DRAIN_TIMEOUT),
+            Some(value) => match value.trim().parse::<u64>() {
+                Ok(secs) => Ok(Duration::from_secs(secs)),
+                Err(_) => Err(anyhow!(
+                    "$FOSTROM_DRAIN_TIMEOUT must be a number of seconds"
+                )),
+            },
+        }
+    }
+
+    /// Reads the optional $FOSTROM_CLIENT_RATE_LIMIT and $FOSTROM_GLOBAL_RATE_LIMIT,
+    /// in requests per second, for each local client and across all of them.
+    /// Bursts of twice the rate are allowed.
+    pub fn rate_limits(&self) -> Result<RateLimits> {
+        let defaults = RateLimits::default();
+        let client = self.rate("FOSTROM_CLIENT_RATE_LIMIT", defaults.client)?;
+        let global = self.rate("FOSTROM_GLOBAL_RATE_LIMIT", defaults.global)?;
+        Ok(RateLimits { client, global })
+    }
+
+    /// Reads the optional $FOSTROM_MAX_PENDING_TXNS, which is how many
+    /// requests can await a response from Fostrom at once.
+    pub fn max_pending_txns(&self) -> Result<usize> {
+        match self.get("FOSTROM_MAX_PENDING_TXNS") {
+            None => Ok(DEFAULT_MAX_PENDING_TXNS),
+            Some(value) => match value.trim().parse::<usize>() {
+                Ok(count) if count > 0 => Ok(count),
+                _ => Err(anyhow!(
+                    "$FOSTROM_MAX_PENDING_TXNS must be a positive number"
+                )),
+            },
+        }
+    }
+
+    fn rate(&self, name: &str, default: Rate) -> Result<Rate> {
+        match self.get(name) {
+            None => Ok(default),
+            Some(value) => match value.trim().parse::<f64>() {
+                Ok(per_sec) if per_sec > 0.0 && per_sec.is_finite() => Ok(Rate::per_sec(per_sec)),
+                _ => Err(anyhow!(
+                    "${name} must be a positive number of requests per second"
+                )),
+            },
+        }
+    }
+
+    fn size(&self, name: &str, default: usize) -> Result<usize> {
+        match self.get(name) {
+            None => Ok(default),
+            Some(value) => match value.trim().parse::<usize>() {
+                Ok(size) if size > 0 => Ok(size),
+                _ => Err(anyhow!("${name} must be a positive number of bytes")),
+            },
+        }
+    }
+}
+
+pub fn env_error() -> Error {
+    anyhow!(
+        "To start the Fostrom Device Agent, you need to pass the following environment variables, or set them in the file passed with --config:\n\t$FOSTROM_FLEET_ID\t\tThe 8-character Fleet ID\n\t$FOSTROM_DEVICE_ID\t\tThe 10-character Device ID\n\t$FOSTROM_DEVICE_SECRET\t\tThe 36-character Device Secret, begins with `FOS-`\n\nYou can find these in the Fostrom Console under your device's settings."
+    )
+}
+
+fn parse_env_file(contents: &str) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(anyhow!("line {}: expected NAME=value", i + 1));
+        };
+
+        let name = name.trim();
+        let value = value.trim();
+
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow!("line {}: invalid name `{name}`", i + 1));
+        }
+
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+            .unwrap_or(value);
+
+        vars.insert(name.to_string(), value.to_string());
+    }
+
+    Ok(vars)
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_env_file() {
+        let vars = parse_env_file(
+            "# Fostrom\n\nFOSTROM_FLEET_ID=ABCDEFGH\nexport FOSTROM_DEVICE_ID=\"ABCDEFGHJK\"\nFOSTROM_LOCAL_MODE = '1'\n",
+        )
+        .unwrap();
+
+        assert_eq!(vars.len(), 3);
+        assert_eq!(vars["FOSTROM_FLEET_ID"], "ABCDEFGH");
+        assert_eq!(vars["FOSTROM_DEVICE_ID"], "ABCDEFGHJK");
+        assert_eq!(vars["FOSTROM_LOCAL_MODE"], "1");
+
+        assert!(parse_env_file("FOSTROM_FLEET_ID").is_err());
+        assert!(parse_env_file("BAD NAME=1").is_err());
+    }
+
+    impl Env {
+        fn from_file(file: HashMap<String, String>) -> Self {
+            Self {
+                file,
+                file_first: false,
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_first() {
+        // Set by the environment everywhere, including the test runner
+        let (name, value) = ("PATH", var("PATH").unwrap());
+        let file = HashMap::from([(name.to_string(), "/from/file".to_string())]);
+
+        let env = Env::from_file(file);
+        assert_eq!(env.get(name), Some(value.clone()));
+
+        let env = env.file_first();
+        assert_eq!(env.get(name).as_deref(), Some("/from/file"));
+
+        // Falling back to the environment when the file doesn't have it
+        assert_eq!(Env::default().file_first().get(name), Some(value));
+    }
+
+    #[test]
+    fn test_limits() {
+        let file = parse_env_file(
+            "FOSTROM_GLOBAL_RATE_LIMIT=500\nFOSTROM_CLIENT_RATE_LIMIT=0.5\nFOSTROM_MAX_PENDING_TXNS=1024\n",
+        )
+        .unwrap();
+        let env = Env::from_file(file);
+
+        let limits = env.rate_limits().unwrap();
+        assert_eq!(limits.global, Rate::per_sec(500.0));
+        assert_eq!(limits.global.burst, 1000.0);
+        assert_eq!(limits.client, Rate::per_sec(0.5));
+        assert_eq!(env.max_pending_txns().unwrap(), 1024);
+
+        // Defaults when unset
+        let env = Env::default();
+        assert_eq!(env.rate_limits().unwrap(), RateLimits::default());
+        assert_eq!(env.max_pending_txns().unwrap(), DEFAULT_MAX_PENDING_TXNS);
+
+        for bad in ["0", "-1", "fast", "inf"] {
+            let file = HashMap::from([("FOSTROM_GLOBAL_RATE_LIMIT".into(), bad.into())]);
+            assert!(Env::from_file(file).rate_limits().is_err());
+        }
+
+        let file = HashMap::from([("FOSTROM_MAX_PENDING_TXNS".into(), "0".into())]);
+        assert!(Env::from_file(file).max_pending_txns().is_err());
+    }
+}
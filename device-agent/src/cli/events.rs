@@ -0,0 +1,93 @@
+// --------------------------
+// --- CLI EVENTS HANDLER ---
+// --------------------------
+
+use super::{
+    AgentTarget,
+    client::{self, HttpResp, device_headers, io_failure},
+    output::{ExitCode, Outcome},
+};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Read},
+};
+
+/// Prints the agent's events, one per line. Without `follow`, only the
+/// first event (the current connection state) is printed. With `--json`,
+/// each event is printed as `{"command": "events", "event": ..., "data": ...}`.
+pub fn events(target: &AgentTarget, follow: bool, json: bool) -> i32 {
+    let headers = device_headers(target);
+    let sock_file = target.runtime_dir.sock_file();
+
+    let mut reader = match client::stream(&sock_file, "GET", "/events", &headers) {
+        Ok((200, reader)) => reader,
+        Ok((code, mut reader)) => {
+            let mut body = String::new();
+            let _ = reader.read_to_string(&mut body);
+            let resp = HttpResp {
+                code,
+                headers: HashMap::new(),
+                body,
+            };
+            return resp.failure("events").print(json);
+        }
+        Err(e) => return io_failure("events", &e).print(json),
+    };
+
+    let mut event = String::new();
+    let mut data = Vec::new();
+
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => return io_failure("events", &e).print(json),
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        if let Some(name) = line.strip_prefix("event:") {
+            event = name.trim().to_string();
+        } else if let Some(line) = line.strip_prefix("data:") {
+            data.push(line.trim_start().to_string());
+        } else if line.is_empty() && !event.is_empty() {
+            if event != "keep_alive" {
+                print_event(&event, &data.join("\n"), json);
+
+                if !follow {
+                    return ExitCode::Ok as i32;
+                }
+            }
+
+            event.clear();
+            data.clear();
+        }
+    }
+
+    Outcome::new(
+        "events",
+        "not_running",
+        ExitCode::NotRunning,
+        "The agent closed the event stream.",
+    )
+    .print(json)
+}
+
+fn print_event(event: &str, data: &str, json: bool) {
+    if json {
+        let data = match data {
+            "" => Value::Null,
+            data => serde_json::from_str(data).unwrap_or_else(|_| json!(data)),
+        };
+        println!(
+            "{}",
+            json!({"command": "events", "event": event, "data": data})
+        );
+    } else if data.is_empty() {
+        println!("{event}");
+    } else {
+        println!("{event}: {data}");
+    }
+}
@@ -0,0 +1,244 @@
+// ------------------
+// --- AGENT LOCK ---
+// ------------------
+
+// The running agent holds an exclusive lock on `agent.lock` for its whole
+// lifetime, which the kernel releases when the process exits, even with
+// SIGKILL. So the lock, rather than the PID file, tells whether an agent is
+// running. `start` and `run` also hold `start.lock` while checking for a
+// running agent and starting a new one, so two of them can't race.
+
+use super::RuntimeDir;
+use anyhow::{Result, anyhow};
+use std::{
+    fs::{File, OpenOptions, TryLockError, read_to_string, remove_file, write},
+    os::unix::{fs::OpenOptionsExt, net::UnixStream},
+    path::PathBuf,
+    process,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// How long the agent lock is retried before giving up
+const LOCK_RETRY_TIMEOUT: Duration = Duration::from_millis(200);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Another agent holds the lock in the runtime directory
+#[derive(Debug, thiserror::Error)]
+#[error("Another agent is already running in {0}")]
+pub struct AlreadyRunning(String);
+
+/// Held by the running agent until it exits
+#[derive(Debug)]
+pub struct AgentLock {
+    _file: File,
+}
+
+impl AgentLock {
+    /// The lock is retried for a moment, as `agent_running()` briefly takes
+    /// it to check whether it's free, such as while `stop` waits for cleanup.
+    pub fn acquire(runtime_dir: &RuntimeDir) -> Result<Self> {
+        let file = open_lock_file(runtime_dir.lock_file())?;
+        let deadline = Instant::now() + LOCK_RETRY_TIMEOUT;
+
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Self { _file: file }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    sleep(LOCK_RETRY_INTERVAL);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(AlreadyRunning(runtime_dir.path().display().to_string()).into());
+                }
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Held by `start` and `run` until the agent has started,
+/// waiting for any other `start` or `run` to finish first
+#[derive(Debug)]
+pub struct StartLock {
+    _file: File,
+}
+
+impl StartLock {
+    pub fn acquire(runtime_dir: &RuntimeDir) -> Result<Self> {
+        let file = open_lock_file(runtime_dir.start_lock_file())?;
+        file.lock()?;
+        Ok(Self { _file: file })
+    }
+}
+
+fn open_lock_file(path: PathBuf) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| anyhow!("Failed to open the lock file {}: {e}", path.display()))
+}
+
+/// Whether an agent holds the lock in this runtime directory.
+/// This takes the lock for a moment when it's free.
+pub fn agent_running(runtime_dir: &RuntimeDir) -> bool {
+    let Ok(file) = File::open(runtime_dir.lock_file()) else {
+        return false;
+    };
+
+    matches!(file.try_lock(), Err(TryLockError::WouldBlock))
+}
+
+/// Removes the files left behind by an agent that didn't exit cleanly,
+/// such as after a SIGKILL. Only to be called when no agent holds the lock.
+/// The socket is kept if something still accepts connections on it,
+/// like systemd with socket activation.
+pub fn remove_stale_files(runtime_dir: &RuntimeDir) {
+    if UnixStream::connect(runtime_dir.sock_file()).is_err() {
+        let _ = remove_file(runtime_dir.sock_file());
+    }
+
+    let _ = remove_file(runtime_dir.pid_file());
+    let _ = remove_file(runtime_dir.hash_file());
+}
+
+// ------------------------
+// --- PROCESS IDENTITY ---
+// ------------------------
+
+/// Creates the PID file, and deletes it when dropped.
+/// Only created by the process holding the agent lock.
+pub struct PidFileGuard(PathBuf);
+
+impl PidFileGuard {
+    pub fn create(runtime_dir: &RuntimeDir) -> Result<Self> {
+        let path = runtime_dir.pid_file();
+        write(&path, pid_file_contents())?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PidFileGuard {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+/// The contents of the PID file: the PID, and on Linux,
+/// the process start time to tell a reused PID apart.
+fn pid_file_contents() -> String {
+    let pid = process::id();
+
+    match start_time(pid) {
+        Some(start_time) => format!("{pid}\n{start_time}\n"),
+        None => format!("{pid}\n"),
+    }
+}
+
+/// Reads the PID of the running agent from the PID file,
+/// if the process with that PID is really the agent.
+pub fn agent_pid(runtime_dir: &RuntimeDir) -> Option<u32> {
+    let contents = read_to_string(runtime_dir.pid_file()).ok()?;
+    let mut lines = contents.lines();
+    let pid = lines.next()?.trim().parse::<u32>().ok()?;
+    let start_time = lines
+        .next()
+        .and_then(|line| line.trim().parse::<u64>().ok());
+
+    // The PID file is written by the agent once it holds the lock,
+    // so it's only current while the lock is held
+    (agent_running(runtime_dir) && is_agent_process(pid, start_time)).then_some(pid)
+}
+
+/// Checks the executable and the start time of the process, as the PID
+/// may have been reused by an unrelated process after the agent exited.
+#[cfg(target_os = "linux")]
+fn is_agent_process(pid: u32, recorded_start_time: Option<u64>) -> bool {
+    use std::{env::current_exe, fs::read_link};
+
+    let Ok(exe) = read_link(format!("/proc/{pid}/exe")) else {
+        return false;
+    };
+
+    // After an upgrade, the running agent's executable shows up as deleted
+    let exe = exe.to_string_lossy();
+    let exe = exe.strip_suffix(" (deleted)").unwrap_or(&exe);
+
+    current_exe().is_ok_and(|current| current.to_string_lossy() == exe)
+        && recorded_start_time.is_some()
+        && start_time(pid) == recorded_start_time
+}
+
+/// Without /proc, the lock being held is what identifies the agent
+#[cfg(not(target_os = "linux"))]
+fn is_agent_process(_pid: u32, _recorded_start_time: Option<u64>) -> bool {
+    true
+}
+
+/// The start time of the process, in clock ticks since boot,
+/// which is the 22nd field of /proc/<pid>/stat
+#[cfg(target_os = "linux")]
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name in parentheses may contain spaces
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_lock() {
+        let dir = std::env::temp_dir().join(format!("fostrom-lock-test-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runtime_dir = RuntimeDir::new(&dir);
+
+        assert!(!agent_running(&runtime_dir));
+
+        let lock = AgentLock::acquire(&runtime_dir).unwrap();
+        assert!(agent_running(&runtime_dir));
+        assert!(AgentLock::acquire(&runtime_dir).is_err());
+        drop(lock);
+
+        // The agent still gets the lock while it is being checked
+        let probe = File::open(runtime_dir.lock_file()).unwrap();
+        probe.try_lock().unwrap();
+        let release = std::thread::spawn(move || {
+            sleep(Duration::from_millis(50));
+            drop(probe);
+        });
+        let lock = AgentLock::acquire(&runtime_dir).unwrap();
+        release.join().unwrap();
+        assert!(agent_running(&runtime_dir));
+
+        // The PID file identifies this process, but only while the lock is held
+        let pid_guard = PidFileGuard::create(&runtime_dir).unwrap();
+        assert_eq!(agent_pid(&runtime_dir), Some(process::id()));
+        #[cfg(target_os = "linux")]
+        assert!(!is_agent_process(process::id(), Some(0)));
+
+        drop(lock);
+        assert!(!agent_running(&runtime_dir));
+        assert_eq!(agent_pid(&runtime_dir), None);
+
+        // Left behind, as if the agent was killed
+        std::mem::forget(pid_guard);
+        remove_stale_files(&runtime_dir);
+        assert!(!runtime_dir.pid_file().exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,130 @@
+// ---------------------------
+// --- CLI MAILBOX HANDLER ---
+// ---------------------------
+
+use super::{
+    AgentTarget,
+    client::{self, HttpResp, device_headers, io_failure},
+    output::{ExitCode, Outcome},
+};
+use crate::moonlight_codec::ClientLogic;
+use serde_json::{Value, json};
+
+#[derive(Debug, Clone)]
+pub enum MailboxCmd {
+    Next { header_only: bool },
+    Ack(String),
+    Reject(String),
+    Requeue(String),
+}
+
+pub fn mailbox(target: &AgentTarget, cmd: MailboxCmd, json: bool) -> i32 {
+    mailbox_outcome(target, cmd).print(json)
+}
+
+fn mailbox_outcome(target: &AgentTarget, cmd: MailboxCmd) -> Outcome {
+    let headers = device_headers(target);
+    let sock_file = target.runtime_dir.sock_file();
+
+    let (action, status, mail_id) = match cmd {
+        MailboxCmd::Next { header_only } => {
+            let method = if header_only { "HEAD" } else { "GET" };
+            return match client::request(&sock_file, method, "/mailbox/next", &headers, None) {
+                Ok(resp) if resp.code == 200 => next_outcome(&resp),
+                Ok(resp) => resp.failure("mailbox"),
+                Err(e) => io_failure("mailbox", &e),
+            };
+        }
+        MailboxCmd::Ack(mail_id) => ("ack", "acked", mail_id),
+        MailboxCmd::Reject(mail_id) => ("reject", "rejected", mail_id),
+        MailboxCmd::Requeue(mail_id) => ("requeue", "requeued", mail_id),
+    };
+
+    if let Err(e) = ClientLogic::uuidv7_u128(&mail_id) {
+        return Outcome::new("mailbox", "usage", ExitCode::Usage, e);
+    }
+
+    let path = format!("/mailbox/{action}/{mail_id}");
+
+    match client::request(&sock_file, "PUT", &path, &headers, None) {
+        Ok(resp) if resp.code == 200 => {
+            let mail_available = resp.json()["mail_available"] == true;
+            Outcome::new(
+                "mailbox",
+                status,
+                ExitCode::Ok,
+                format!("The mail {mail_id} was {status}."),
+            )
+            .with("mail_available", mail_available)
+        }
+        Ok(resp) => resp.failure("mailbox"),
+        Err(e) => io_failure("mailbox", &e),
+    }
+    .with("mail_id", mail_id)
+}
+
+/// The next mail is described by the `X-Mail-*` headers, with its payload as the body
+fn next_outcome(resp: &HttpResp) -> Outcome {
+    let mailbox_size = resp
+        .header("x-mailbox-size")
+        .and_then(|size| size.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let Some(mail_id) = resp.header("x-mail-id") else {
+        return Outcome::new("mailbox", "empty", ExitCode::Ok, "The mailbox is empty.")
+            .with("mailbox_size", mailbox_size)
+            .with("mail", Value::Null);
+    };
+
+    let name = resp.header("x-mail-name").unwrap_or_default();
+    let payload = resp.json();
+
+    let mut outcome = Outcome::new(
+        "mailbox",
+        "mail",
+        ExitCode::Ok,
+        format!("{name} (id: {mail_id}, mailbox size: {mailbox_size})"),
+    )
+    .with("mailbox_size", mailbox_size)
+    .with(
+        "mail",
+        json!({
+            "id": mail_id,
+            "name": name,
+            "has_payload": resp.header("x-mail-has-payload") == Some("true"),
+            "payload": payload,
+        }),
+    );
+
+    if !payload.is_null() {
+        outcome =
+            outcome.with_text_body(serde_json::to_string_pretty(&payload).unwrap_or_default());
+    }
+
+    outcome
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_outcome() {
+        let raw = "HTTP/1.1 200 OK\r\nX-Mailbox-Size: 0\r\nX-Mailbox-Empty: true\r\n\r\n";
+        let json = next_outcome(&HttpResp::parse(raw).unwrap()).to_json();
+        assert_eq!(json["status"], "empty");
+        assert_eq!(json["mail"], Value::Null);
+
+        let raw = "HTTP/1.1 200 OK\r\nX-Mailbox-Size: 2\r\nX-Mail-ID: 0198c2a4-5c1e-7a3b-8f00-000000000001\r\nX-Mail-Name: reboot\r\nX-Mail-Has-Payload: true\r\n\r\n{\"delay\":5}";
+        let json = next_outcome(&HttpResp::parse(raw).unwrap()).to_json();
+        assert_eq!(json["status"], "mail");
+        assert_eq!(json["mailbox_size"], 2);
+        assert_eq!(json["mail"]["name"], "reboot");
+        assert_eq!(json["mail"]["has_payload"], true);
+        assert_eq!(json["mail"]["payload"]["delay"], 5);
+    }
+}
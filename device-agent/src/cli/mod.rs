@@ -2,52 +2,404 @@
 // --- CLI ---
 // -----------
 
+mod client;
 mod daemon;
+mod env;
+mod events;
+mod lock;
+mod mailbox;
+mod output;
 mod parser;
+mod readiness;
+mod reload;
+mod send;
+mod service;
 mod start;
 mod status;
 mod stop;
+mod supervisor;
 mod test_conn;
 
-use crate::moonlight_codec::{ConnectMode, Creds};
-use start::{start_agent, start_daemon_child};
+use crate::{
+    http_server::RateLimits,
+    log::Level,
+    moonlight_codec::{CodecLimits, ConnectMode, Creds, PulseType},
+};
+use events::events;
+use mailbox::{MailboxCmd, mailbox};
+use send::send_pulse;
+use service::{InitSystem, install_service, uninstall_service};
+use start::{start_agent, start_daemon_child, start_worker};
 use status::agent_status;
-use std::process::exit;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
 use stop::stop_agent;
+use test_conn::TestConnConfig;
 
-pub static TMP_DIR: &str = "/tmp/fostrom";
-pub static HASH_FILE: &str = "/tmp/fostrom/config.hash";
-pub static PID_FILE: &str = "/tmp/fostrom/agent.pid";
-pub static SOCK_FILE: &str = "/tmp/fostrom/agent.sock";
-pub static STDOUT_LOG: &str = "/tmp/fostrom/stdout.log";
-pub static STDERR_LOG: &str = "/tmp/fostrom/stderr.log";
+pub static DEFAULT_RUNTIME_DIR: &str = "/tmp/fostrom";
+pub static DEFAULT_TCP_ADDR: &str = "127.0.0.1:8585";
+
+/// The directory holding the agent's socket, PID, hash, lock, crash and log files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeDir(PathBuf);
+
+impl RuntimeDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self(path.into())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn sock_file(&self) -> PathBuf {
+        self.0.join("agent.sock")
+    }
+
+    pub fn pid_file(&self) -> PathBuf {
+        self.0.join("agent.pid")
+    }
+
+    pub fn hash_file(&self) -> PathBuf {
+        self.0.join("config.hash")
+    }
+
+    pub fn lock_file(&self) -> PathBuf {
+        self.0.join("agent.lock")
+    }
+
+    pub fn start_lock_file(&self) -> PathBuf {
+        self.0.join("start.lock")
+    }
+
+    pub fn crash_file(&self) -> PathBuf {
+        self.0.join("crash.json")
+    }
+
+    pub fn stdout_log(&self) -> PathBuf {
+        self.0.join("stdout.log")
+    }
+
+    pub fn stderr_log(&self) -> PathBuf {
+        self.0.join("stderr.log")
+    }
+}
+
+impl Default for RuntimeDir {
+    fn default() -> Self {
+        Self::new(DEFAULT_RUNTIME_DIR)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct AgentConfig {
     pub creds: Creds,
+    pub runtime_dir: RuntimeDir,
     pub enable_unix_socket: bool,
     pub enable_tcp_socket: bool,
+    pub tcp_addr: SocketAddr,
     pub connect_mode: ConnectMode,
+    pub codec_limits: CodecLimits,
+    pub drain_timeout: Duration,
+    pub rate_limits: RateLimits,
+    pub max_pending_txns: usize,
+    pub log_level: Level,
+
+    /// Passed on to the daemon, which reads it again
+    pub config_file: Option<PathBuf>,
+
+    /// The $FOSTROM_RUNTIME_DIR in the config file when it was read.
+    /// The runtime directory is fixed once running, so a reload can't change it.
+    pub config_runtime_dir: Option<String>,
+
+    /// The options passed on the command line, which take precedence
+    /// over the environment and the config file when it is read again
+    pub args: Vec<String>,
+
+    /// Run the agent as a worker process, restarted by a supervisor if it crashes
+    pub supervise: bool,
+
     pub start_daemon: bool,
 }
 
+impl AgentConfig {
+    /// The flags to start another agent process with the same configuration,
+    /// or to read it again. The credentials and the settings that weren't
+    /// passed on the command line are read from the environment or the config file.
+    pub fn flags(&self) -> Vec<String> {
+        let mut flags = vec![format!(
+            "--runtime-dir={}",
+            self.runtime_dir.path().display()
+        )];
+
+        if let Some(config_file) = &self.config_file {
+            flags.push(format!("--config={}", config_file.display()));
+        }
+
+        flags.extend(self.args.iter().cloned());
+        flags
+    }
+
+    /// The effective settings, compared by `start` against the running agent's
+    pub fn fingerprint(&self) -> Fingerprint {
+        let endpoint = match self.connect_mode {
+            ConnectMode::Prod => "prod".to_string(),
+            ConnectMode::Local(port) => format!("local:{port}"),
+        };
+
+        let tcp = match self.enable_tcp_socket {
+            true => self.tcp_addr.to_string(),
+            false => "off".to_string(),
+        };
+
+        let settings = [
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("credentials", self.creds.hash()),
+            ("endpoint", endpoint),
+            ("unix_socket", self.enable_unix_socket.to_string()),
+            ("tcp", tcp),
+            ("log_level", self.log_level.to_string()),
+            ("supervise", self.supervise.to_string()),
+            ("drain_timeout", self.drain_timeout.as_secs().to_string()),
+            (
+                "max_frame_size",
+                self.codec_limits.max_frame_size.to_string(),
+            ),
+            (
+                "max_buffer_size",
+                self.codec_limits.max_buffer_size.to_string(),
+            ),
+            (
+                "client_rate_limit",
+                self.rate_limits.client.per_sec.to_string(),
+            ),
+            (
+                "global_rate_limit",
+                self.rate_limits.global.per_sec.to_string(),
+            ),
+            ("max_pending_txns", self.max_pending_txns.to_string()),
+        ];
+
+        Fingerprint(
+            settings
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+    }
+}
+
+/// The agent's settings, one `name=value` per line, as written to the
+/// hash file by the running agent. The credentials are only stored as a hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint(BTreeMap<String, String>);
+
+impl Fingerprint {
+    pub fn parse(contents: &str) -> Self {
+        let settings = contents
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        Self(settings)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    /// The names of the settings that differ from the previous fingerprint.
+    /// Settings missing from either of them count as changed.
+    pub fn changes(&self, prev: &Self) -> Vec<String> {
+        let mut names = self.0.keys().chain(prev.0.keys()).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        names
+            .into_iter()
+            .filter(|name| self.0.get(*name) != prev.0.get(*name))
+            .cloned()
+            .collect()
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|(name, value)| writeln!(f, "{name}={value}"))
+    }
+}
+
+/// The running agent that the send, mailbox and events commands talk to,
+/// along with the device headers that it validates.
+#[derive(Debug, Clone)]
+pub struct AgentTarget {
+    pub runtime_dir: RuntimeDir,
+    pub fleet_id: String,
+    pub device_id: String,
+}
+
 #[derive(Debug, Clone)]
 pub enum ParsedAction {
-    Start(AgentConfig),
-    Daemon(AgentConfig),
-    Stop,
-    Status,
-    TestConn,
+    Start {
+        config: AgentConfig,
+        json: bool,
+    },
+    Daemon {
+        config: AgentConfig,
+        /// The pipe to report readiness on, passed by `start`
+        ready_fd: Option<RawFd>,
+    },
+    Worker {
+        config: AgentConfig,
+        ready_fd: Option<RawFd>,
+    },
+    Stop {
+        runtime_dir: RuntimeDir,
+        json: bool,
+    },
+    Status {
+        runtime_dir: RuntimeDir,
+        json: bool,
+    },
+    TestConn {
+        config: TestConnConfig,
+        json: bool,
+    },
+    Send {
+        target: AgentTarget,
+        pulse_type: PulseType,
+        name: String,
+        payload: Option<String>,
+        json: bool,
+    },
+    Mailbox {
+        target: AgentTarget,
+        cmd: MailboxCmd,
+        json: bool,
+    },
+    Events {
+        target: AgentTarget,
+        follow: bool,
+        json: bool,
+    },
+    InstallService {
+        config: AgentConfig,
+        init: Option<InitSystem>,
+        json: bool,
+    },
+    UninstallService {
+        init: Option<InitSystem>,
+        json: bool,
+    },
 }
 
 pub fn exec() {
-    if let Some(action) = parser::parse() {
-        match action {
-            ParsedAction::Start(config) => start_agent(config),
-            ParsedAction::Daemon(config) => start_daemon_child(config),
-            ParsedAction::Stop => stop_agent(),
-            ParsedAction::Status => agent_status(),
-            ParsedAction::TestConn => exit(test_conn::run()),
+    let code = match parser::parse() {
+        Ok(ParsedAction::Start { config, json }) => start_agent(config, json),
+        Ok(ParsedAction::Daemon { config, ready_fd }) => start_daemon_child(config, ready_fd),
+        Ok(ParsedAction::Worker { config, ready_fd }) => start_worker(config, ready_fd),
+        Ok(ParsedAction::Stop { runtime_dir, json }) => stop_agent(&runtime_dir, json),
+        Ok(ParsedAction::Status { runtime_dir, json }) => agent_status(&runtime_dir, json),
+        Ok(ParsedAction::TestConn { config, json }) => test_conn::run(&config, json),
+        Ok(ParsedAction::Send {
+            target,
+            pulse_type,
+            name,
+            payload,
+            json,
+        }) => send_pulse(&target, pulse_type, name, payload, json),
+        Ok(ParsedAction::Mailbox { target, cmd, json }) => mailbox(&target, cmd, json),
+        Ok(ParsedAction::Events {
+            target,
+            follow,
+            json,
+        }) => events(&target, follow, json),
+        Ok(ParsedAction::InstallService { config, init, json }) => {
+            install_service(config, init, json)
         }
+        Ok(ParsedAction::UninstallService { init, json }) => uninstall_service(init, json),
+        Err(code) => code,
+    };
+
+    exit(code);
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_server::Rate,
+        moonlight_codec::{DEFAULT_DRAIN_TIMEOUT, DEFAULT_MAX_PENDING_TXNS},
+    };
+
+    fn config() -> AgentConfig {
+        AgentConfig {
+            creds: Creds::new(
+                "ABCDEFGH",
+                "ABCDEFGHJK",
+                "FOS-ABCDEFGHJKLMNPQRSTUVWXYZ23456789",
+                true,
+            )
+            .unwrap(),
+            runtime_dir: RuntimeDir::default(),
+            enable_unix_socket: true,
+            enable_tcp_socket: false,
+            tcp_addr: DEFAULT_TCP_ADDR.parse().unwrap(),
+            connect_mode: ConnectMode::Prod,
+            codec_limits: CodecLimits::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            rate_limits: RateLimits::default(),
+            max_pending_txns: DEFAULT_MAX_PENDING_TXNS,
+            log_level: Level::Info,
+            config_file: None,
+            config_runtime_dir: None,
+            args: vec![],
+            supervise: false,
+            start_daemon: true,
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_changes() {
+        let prev = config().fingerprint();
+        let written = Fingerprint::parse(&prev.to_string());
+        assert_eq!(written, prev);
+        assert!(prev.to_string().lines().all(|l| !l.contains("FOS-")));
+
+        let mut config = config();
+        config.enable_tcp_socket = true;
+        config.connect_mode = ConnectMode::Local(8484);
+        config.log_level = Level::Debug;
+        config.rate_limits.global = Rate::per_sec(500.0);
+        config.max_pending_txns = 1024;
+
+        assert_eq!(
+            config.fingerprint().changes(&written),
+            [
+                "endpoint",
+                "global_rate_limit",
+                "log_level",
+                "max_pending_txns",
+                "tcp"
+            ]
+        );
+
+        // An old hash file, with just the credential hash
+        let old = Fingerprint::parse(&format!("{}\n", config.creds.hash()));
+        assert_eq!(config.fingerprint().changes(&old).len(), 13);
     }
 }
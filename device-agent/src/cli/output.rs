@@ -0,0 +1,142 @@
+// ------------------
+// --- CLI OUTPUT ---
+// ------------------
+
+use serde_json::{Map, Value, json};
+
+/// Exit codes, shared by all commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// The command succeeded, or the agent is running
+    Ok = 0,
+    Failed = 1,
+    Usage = 2,
+    NotRunning = 3,
+    Unauthorized = 4,
+    ConnectFailed = 5,
+    Timeout = 6,
+    /// The daemon reported that it failed to start
+    StartFailed = 7,
+}
+
+/// The result of a command.
+///
+/// It is printed as a `<status>: <message>` line, or with `--json`,
+/// as a single JSON object on stdout with this schema:
+///
+/// ```json
+/// {
+///   "command": "status",        // the command that was run
+///   "status": "running",        // a stable snake_case status
+///   "exit_code": 0,             // the process exit code
+///   "message": "...",           // a human readable description
+///   ...                         // fields specific to the command
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    command: &'static str,
+    status: &'static str,
+    code: ExitCode,
+    message: String,
+    fields: Map<String, Value>,
+
+    /// Printed below the status line, but only without `--json`
+    text_body: Option<String>,
+}
+
+impl Outcome {
+    pub fn new(
+        command: &'static str,
+        status: &'static str,
+        code: ExitCode,
+        message: impl ToString,
+    ) -> Self {
+        Self {
+            command,
+            status,
+            code,
+            message: message.to_string(),
+            fields: Map::new(),
+            text_body: None,
+        }
+    }
+
+    pub fn failed(command: &'static str, message: impl ToString) -> Self {
+        Self::new(command, "failed", ExitCode::Failed, message)
+    }
+
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.fields.insert(key.to_string(), value.into());
+        self
+    }
+
+    pub fn with_text_body(mut self, body: impl ToString) -> Self {
+        self.text_body = Some(body.to_string());
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut obj = Map::new();
+        obj.insert("command".to_string(), json!(self.command));
+        obj.insert("status".to_string(), json!(self.status));
+        obj.insert("exit_code".to_string(), json!(self.code as i32));
+        obj.insert("message".to_string(), json!(self.message));
+
+        for (key, value) in &self.fields {
+            obj.insert(key.clone(), value.clone());
+        }
+
+        Value::Object(obj)
+    }
+
+    /// Prints the outcome and returns the exit code
+    pub fn print(&self, json: bool) -> i32 {
+        if json {
+            println!("{}", self.to_json());
+        } else {
+            let line = format!("{}: {}", self.status, self.message);
+
+            match self.code {
+                ExitCode::Failed | ExitCode::Usage | ExitCode::Timeout => eprintln!("{line}"),
+                _ => println!("{line}"),
+            }
+
+            if let Some(body) = &self.text_body {
+                println!("\n{body}");
+            }
+        }
+
+        self.code as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome_json() {
+        let outcome = Outcome::new(
+            "status",
+            "not_running",
+            ExitCode::NotRunning,
+            "Not running.",
+        )
+        .with("agent", Value::Null)
+        .with_text_body("ignored in json");
+
+        assert_eq!(
+            outcome.to_json(),
+            json!({
+                "command": "status",
+                "status": "not_running",
+                "exit_code": 3,
+                "message": "Not running.",
+                "agent": null,
+            })
+        );
+
+        assert_eq!(Outcome::failed("start", "oops").to_json()["exit_code"], 1);
+    }
+}
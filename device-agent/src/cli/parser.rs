@@ -2,128 +2,944 @@
 // --- CLI PARSER ---
 // ------------------
 
-use super::{AgentConfig, ParsedAction};
-use crate::moonlight_codec::{ConnectMode, Creds};
-use anyhow::{Error, Result, anyhow};
-use std::env::{args, var};
+use super::{
+    AgentConfig, AgentTarget, DEFAULT_RUNTIME_DIR, DEFAULT_TCP_ADDR, ParsedAction, RuntimeDir,
+    env::Env,
+    mailbox::MailboxCmd,
+    output::{ExitCode, Outcome},
+    readiness::{Readiness, StartupErrorKind, parse_ready_fd},
+    test_conn::{Endpoint, TestConnConfig},
+};
+use crate::{
+    log::Level,
+    moonlight_codec::{ConnectMode, Creds, PulseType},
+};
+use anyhow::{Result, anyhow};
+use std::{
+    collections::HashMap,
+    env::args,
+    net::SocketAddr,
+    path::{PathBuf, absolute},
+};
 
 const DEVICE_AGENT_VSN: &str = concat!("v", env!("CARGO_PKG_VERSION"));
+const BIN_NAME: &str = "fostrom-device-agent";
 
-const HELP_TEXT: &str = concat!(
+const HELP_HEADER: &str = concat!(
     "Fostrom Device Agent ",
     "v",
     env!("CARGO_PKG_VERSION"),
     r#"
 
 The Fostrom Device Agent is used by our Device SDKs.
-Visit the Fostrom Docs [https://fostrom.io/docs/] for more information.
-
-USAGE:
-    start               Start in daemon mode
-    run                 Start in blocking mode
-    test-conn           Test connectivity to Fostrom
-    status              Get the agent's status
-    stop                Stop the device agent
-    version             Print version
-    help                Print this help text"#
+Visit the Fostrom Docs [https://fostrom.io/docs/] for more information."#
 );
 
-pub fn parse() -> Option<ParsedAction> {
-    let args = args()
-        .collect::<Vec<String>>()
+const HELP_FOOTER: &str = r#"Run `fostrom-device-agent help <command>` to see the options of a command.
+Options can be passed as `--option value` or `--option=value`.
+
+Flags take precedence over environment variables,
+which take precedence over the file passed with --config.
+
+With --json, a single JSON object is printed with the fields
+command, status, exit_code and message (one object per event for events).
+
+EXIT CODES:
+    0                   Ok, or the agent is running
+    1                   Failed
+    2                   Invalid usage
+    3                   The agent is not running
+    4                   Unauthorized by Fostrom
+    5                   Failed to connect to Fostrom
+    6                   Timed out
+    7                   The agent failed to start"#;
+
+// ---------------------
+// --- COMMAND SPECS ---
+// ---------------------
+
+struct Flag {
+    name: &'static str,
+    /// The name of the flag's value, or None for a boolean flag
+    value: Option<&'static str>,
+    about: &'static str,
+}
+
+struct Command {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    args: &'static str,
+    about: &'static str,
+    flags: &'static [Flag],
+    /// Hidden from the help text, such as the daemon started by `start`
+    hidden: bool,
+}
+
+const CONFIG: Flag = Flag {
+    name: "config",
+    value: Some("path"),
+    about: "Read FOSTROM_* settings from an env file",
+};
+
+const RUNTIME_DIR: Flag = Flag {
+    name: "runtime-dir",
+    value: Some("path"),
+    about: "The directory for the agent's socket, PID and logs [default: /tmp/fostrom, env: FOSTROM_RUNTIME_DIR]",
+};
+
+const TCP: Flag = Flag {
+    name: "tcp",
+    value: None,
+    about: "Also listen on TCP [default address: 127.0.0.1:8585]",
+};
+
+const TCP_ADDR: Flag = Flag {
+    name: "tcp-addr",
+    value: Some("addr"),
+    about: "Listen on TCP on this address",
+};
+
+const LOCAL_PORT: Flag = Flag {
+    name: "local-port",
+    value: Some("port"),
+    about: "Connect to a local Fostrom server on this port, instead of production",
+};
+
+const LOG_LEVEL: Flag = Flag {
+    name: "log-level",
+    value: Some("level"),
+    about: "error, warn, info or debug [default: info, env: FOSTROM_LOG_LEVEL]",
+};
+
+const SUPERVISE: Flag = Flag {
+    name: "supervise",
+    value: None,
+    about: "Run the agent under a supervisor, which restarts it if it crashes",
+};
+
+const FOREGROUND: Flag = Flag {
+    name: "foreground",
+    value: None,
+    about: "Run in the foreground, like `run`",
+};
+
+const HEADER_ONLY: Flag = Flag {
+    name: "header-only",
+    value: None,
+    about: "Skip the mail's payload (mailbox next)",
+};
+
+const FOLLOW: Flag = Flag {
+    name: "follow",
+    value: None,
+    about: "Keep printing events until the agent stops",
+};
+
+const INIT: Flag = Flag {
+    name: "init",
+    value: Some("system"),
+    about: "systemd, openrc or runit [default: detected]",
+};
+
+const READY_FD: Flag = Flag {
+    name: "ready-fd",
+    value: Some("fd"),
+    about: "The pipe to report readiness on, passed by `start`",
+};
+
+const ENDPOINT: Flag = Flag {
+    name: "endpoint",
+    value: Some("host:port"),
+    about: "Test this endpoint over TLS, instead of production (test-conn)",
+};
+
+const AUTH: Flag = Flag {
+    name: "auth",
+    value: None,
+    about: "Also connect with the configured credentials and send a heartbeat (test-conn)",
+};
+
+const JSON: Flag = Flag {
+    name: "json",
+    value: None,
+    about: "Print the result as a JSON object",
+};
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "start",
+        aliases: &[],
+        args: "",
+        about: "Start in daemon mode",
+        flags: &[
+            CONFIG,
+            RUNTIME_DIR,
+            TCP,
+            TCP_ADDR,
+            LOCAL_PORT,
+            LOG_LEVEL,
+            SUPERVISE,
+            FOREGROUND,
+            JSON,
+        ],
+        hidden: false,
+    },
+    Command {
+        name: "run",
+        aliases: &[],
+        args: "",
+        about: "Start in blocking mode",
+        flags: &[
+            CONFIG,
+            RUNTIME_DIR,
+            TCP,
+            TCP_ADDR,
+            LOCAL_PORT,
+            LOG_LEVEL,
+            SUPERVISE,
+            JSON,
+        ],
+        hidden: false,
+    },
+    Command {
+        name: "daemon",
+        aliases: &[],
+        args: "",
+        about: "Run as the daemon spawned by `start`",
+        flags: &[
+            CONFIG,
+            RUNTIME_DIR,
+            TCP,
+            TCP_ADDR,
+            LOCAL_PORT,
+            LOG_LEVEL,
+            SUPERVISE,
+            READY_FD,
+        ],
+        hidden: true,
+    },
+    Command {
+        name: "worker",
+        aliases: &[],
+        args: "",
+        about: "Run as the worker restarted by the supervisor",
+        flags: &[
+            CONFIG,
+            RUNTIME_DIR,
+            TCP,
+            TCP_ADDR,
+            LOCAL_PORT,
+            LOG_LEVEL,
+            SUPERVISE,
+            READY_FD,
+        ],
+        hidden: true,
+    },
+    Command {
+        name: "test-conn",
+        aliases: &["test-connection"],
+        args: "",
+        about: "Test connectivity to Fostrom",
+        flags: &[CONFIG, LOCAL_PORT, ENDPOINT, AUTH, JSON],
+        hidden: false,
+    },
+    Command {
+        name: "status",
+        aliases: &[],
+        args: "",
+        about: "Get the agent's status",
+        flags: &[CONFIG, RUNTIME_DIR, JSON],
+        hidden: false,
+    },
+    Command {
+        name: "stop",
+        aliases: &[],
+        args: "",
+        about: "Stop the device agent",
+        flags: &[CONFIG, RUNTIME_DIR, JSON],
+        hidden: false,
+    },
+    Command {
+        name: "send",
+        aliases: &[],
+        args: "datapoint|msg <name> [<json>]",
+        about: "Send a datapoint or message through the running agent",
+        flags: &[CONFIG, RUNTIME_DIR, JSON],
+        hidden: false,
+    },
+    Command {
+        name: "mailbox",
+        aliases: &[],
+        args: "next | ack|reject|requeue <mail_id>",
+        about: "Read the next mail, or acknowledge, reject or requeue a mail",
+        flags: &[CONFIG, RUNTIME_DIR, HEADER_ONLY, JSON],
+        hidden: false,
+    },
+    Command {
+        name: "events",
+        aliases: &[],
+        args: "",
+        about: "Print the agent's events",
+        flags: &[CONFIG, RUNTIME_DIR, FOLLOW, JSON],
+        hidden: false,
+    },
+    Command {
+        name: "install-service",
+        aliases: &[],
+        args: "",
+        about: "Install a service that keeps the agent running, with these options",
+        flags: &[
+            CONFIG,
+            RUNTIME_DIR,
+            TCP,
+            TCP_ADDR,
+            LOCAL_PORT,
+            LOG_LEVEL,
+            SUPERVISE,
+            INIT,
+            JSON,
+        ],
+        hidden: false,
+    },
+    Command {
+        name: "uninstall-service",
+        aliases: &[],
+        args: "",
+        about: "Stop and remove the service installed by install-service",
+        flags: &[INIT, JSON],
+        hidden: false,
+    },
+    Command {
+        name: "version",
+        aliases: &[],
+        args: "",
+        about: "Print version",
+        flags: &[JSON],
+        hidden: false,
+    },
+    Command {
+        name: "help",
+        aliases: &[],
+        args: "[<command>]",
+        about: "Print this help text, or the help of a command",
+        flags: &[],
+        hidden: false,
+    },
+];
+
+fn find_command(name: &str) -> Option<&'static Command> {
+    let name = name.to_lowercase();
+    COMMANDS
         .iter()
-        .skip(1)
-        .map(|s| s.to_lowercase())
-        .collect::<Vec<String>>();
+        .find(|cmd| cmd.name == name || cmd.aliases.contains(&name.as_str()))
+}
 
-    if args.is_empty() {
-        return help();
-    }
+// -------------------------
+// --- ARGUMENT MATCHING ---
+// -------------------------
+
+/// The flags and positional arguments passed to a command
+#[derive(Debug, Default)]
+struct Matches {
+    flags: HashMap<&'static str, String>,
+    positionals: Vec<String>,
+    help: bool,
+}
 
-    if args.len() == 1 && (args[0] == "help") {
-        return help();
+impl Matches {
+    fn has(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
     }
 
-    if args.len() == 1 && (args[0] == "version") {
-        println!("{DEVICE_AGENT_VSN}");
-        return None;
+    fn value(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(String::as_str)
     }
+}
+
+/// Matches the arguments against the command's flags. Arguments are not
+/// lowercased, and everything after `--` is taken as a positional argument.
+fn match_args(cmd: &Command, args: &[String]) -> Result<Matches, String> {
+    let mut matches = Matches::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            matches.positionals.extend(args.by_ref().cloned());
+            break;
+        }
+
+        if arg == "--help" || arg == "-h" {
+            matches.help = true;
+            continue;
+        }
+
+        let Some(flag) = arg.strip_prefix("--") else {
+            matches.positionals.push(arg.clone());
+            continue;
+        };
+
+        let (name, inline_value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
 
-    if args.len() == 1 && args[0] == "stop" {
-        return Some(ParsedAction::Stop);
+        let Some(spec) = cmd.flags.iter().find(|f| f.name == name) else {
+            return Err(format!("Unknown option `--{name}` for `{}`", cmd.name));
+        };
+
+        let value = match (spec.value, inline_value) {
+            (None, None) => String::new(),
+            (None, Some(_)) => return Err(format!("Option `--{name}` does not take a value")),
+            (Some(_), Some(value)) => value,
+            (Some(value_name), None) => match args.next() {
+                Some(value) => value.clone(),
+                None => return Err(format!("Option `--{name}` needs a <{value_name}>")),
+            },
+        };
+
+        matches.flags.insert(spec.name, value);
     }
 
-    if args.len() == 1 && args[0] == "status" {
-        return Some(ParsedAction::Status);
+    Ok(matches)
+}
+
+// -------------
+// --- PARSE ---
+// -------------
+
+/// Parses the command line arguments. Returns the exit code if the
+/// command was already handled here, such as `help` or `version`.
+pub fn parse() -> Result<ParsedAction, i32> {
+    parse_args(&args().skip(1).collect::<Vec<String>>())
+}
+
+fn parse_args(args: &[String]) -> Result<ParsedAction, i32> {
+    // Whether to print errors as JSON, before the arguments are matched
+    let json = args.iter().any(|arg| arg == "--json");
+
+    let Some(first) = args.first() else {
+        return Err(help(None));
+    };
+
+    if first == "--help" || first == "-h" {
+        return Err(help(None));
     }
 
-    if !args.is_empty() && (args[0] == "test-conn" || args[0] == "test-connection") {
-        return Some(ParsedAction::TestConn);
+    let Some(cmd) = find_command(first) else {
+        if json {
+            return Err(usage("unknown", &format!("Unknown Command: {first}"), json));
+        }
+
+        eprintln!("Unknown Command: {first}");
+        eprintln!();
+        eprintln!("{}", help_text());
+        return Err(ExitCode::Usage as i32);
+    };
+
+    let matches = match match_args(cmd, &args[1..]) {
+        Ok(matches) => matches,
+        Err(e) => return Err(usage(cmd.name, &e, json)),
+    };
+
+    if matches.help {
+        return Err(help(Some(cmd)));
     }
 
-    if !args.is_empty() && (args[0] == "run" || args[0] == "start" || args[0] == "daemon") {
-        let start_daemon = args[0] == "start" || args[0] == "daemon";
-        let start_tcp = args.contains(&"--tcp".to_string());
-        match get_agent_config(start_daemon, start_tcp) {
-            Ok(config) => {
-                if args[0] == "daemon" {
-                    return Some(ParsedAction::Daemon(config));
-                } else {
-                    return Some(ParsedAction::Start(config));
+    let json = matches.has("json");
+    let pos = &matches.positionals;
+
+    match cmd.name {
+        "help" => match pos.as_slice() {
+            [] => Err(help(None)),
+            [name] => match find_command(name) {
+                Some(cmd) => Err(help(Some(cmd))),
+                None => Err(usage("help", &format!("Unknown Command: {name}"), json)),
+            },
+            _ => Err(usage_of(cmd, json)),
+        },
+
+        _ if !pos.is_empty() && cmd.args.is_empty() => Err(usage(
+            cmd.name,
+            &format!("Unexpected argument `{}` for `{}`", pos[0], cmd.name),
+            json,
+        )),
+
+        "version" => {
+            if json {
+                let outcome = Outcome::new("version", "ok", ExitCode::Ok, DEVICE_AGENT_VSN)
+                    .with("version", DEVICE_AGENT_VSN);
+                return Err(outcome.print(json));
+            }
+
+            println!("{DEVICE_AGENT_VSN}");
+            Err(ExitCode::Ok as i32)
+        }
+
+        "test-conn" => match get_test_conn_config(&matches) {
+            Ok(config) => Ok(ParsedAction::TestConn { config, json }),
+            Err(e) => Err(Outcome::failed(cmd.name, e).print(json)),
+        },
+
+        "stop" | "status" => match read_runtime_dir(&matches) {
+            Ok(runtime_dir) if cmd.name == "stop" => Ok(ParsedAction::Stop { runtime_dir, json }),
+            Ok(runtime_dir) => Ok(ParsedAction::Status { runtime_dir, json }),
+            Err(e) => Err(Outcome::failed(cmd.name, e).print(json)),
+        },
+
+        "start" | "run" | "daemon" | "worker" => {
+            let start_daemon = cmd.name != "run" && !matches.has("foreground");
+
+            if cmd.name == "daemon" || cmd.name == "worker" {
+                return parse_daemon(cmd, &matches);
+            }
+
+            match get_agent_config(&matches, start_daemon) {
+                Ok(config) => Ok(ParsedAction::Start { config, json }),
+                Err(e) => Err(Outcome::failed(cmd.name, e).print(json)),
+            }
+        }
+
+        "install-service" | "uninstall-service" => {
+            let init = match matches
+                .value("init")
+                .map(|init| init.to_lowercase().parse())
+            {
+                None => None,
+                Some(Ok(init)) => Some(init),
+                Some(Err(_)) => {
+                    let msg = "Invalid --init, expected systemd, openrc or runit";
+                    return Err(usage(cmd.name, msg, json));
                 }
+            };
+
+            if cmd.name == "uninstall-service" {
+                return Ok(ParsedAction::UninstallService { init, json });
+            }
+
+            match get_agent_config(&matches, false) {
+                Ok(config) => Ok(ParsedAction::InstallService { config, init, json }),
+                Err(e) => Err(Outcome::failed(cmd.name, e).print(json)),
+            }
+        }
+
+        "send" | "mailbox" | "events" => {
+            let Some(action) = parse_device_cmd(cmd, &matches) else {
+                return Err(usage_of(cmd, json));
+            };
+
+            match read_target(&matches) {
+                Ok(target) => Ok(action(target)),
+                Err(e) => Err(Outcome::failed(cmd.name, e).print(json)),
+            }
+        }
+
+        _ => Err(usage_of(cmd, json)),
+    }
+}
+
+type DeviceAction = Box<dyn FnOnce(AgentTarget) -> ParsedAction>;
+
+/// Parses the arguments of the commands that talk to the running agent,
+/// returning a function to complete the action with the agent to talk to.
+fn parse_device_cmd(cmd: &Command, matches: &Matches) -> Option<DeviceAction> {
+    let json = matches.has("json");
+    let pos = matches.positionals.clone();
+
+    match cmd.name {
+        "send" => {
+            let pulse_type = match pos.first()?.to_lowercase().as_str() {
+                "datapoint" => PulseType::Data,
+                "msg" => PulseType::Msg,
+                _ => return None,
+            };
+
+            if !(2..=3).contains(&pos.len()) {
+                return None;
             }
-            Err(e) => {
-                eprintln!("{e}");
+
+            Some(Box::new(move |target| ParsedAction::Send {
+                target,
+                pulse_type,
+                name: pos[1].clone(),
+                payload: pos.get(2).cloned(),
+                json,
+            }))
+        }
+
+        "mailbox" => {
+            let header_only = matches.has("header-only");
+
+            let cmd = match (pos.first()?.to_lowercase().as_str(), pos.get(1)) {
+                ("next", None) => MailboxCmd::Next { header_only },
+                ("ack", Some(id)) if !header_only => MailboxCmd::Ack(id.clone()),
+                ("reject", Some(id)) if !header_only => MailboxCmd::Reject(id.clone()),
+                ("requeue", Some(id)) if !header_only => MailboxCmd::Requeue(id.clone()),
+                _ => return None,
+            };
+
+            if pos.len() > 2 {
                 return None;
             }
+
+            Some(Box::new(move |target| ParsedAction::Mailbox {
+                target,
+                cmd,
+                json,
+            }))
+        }
+
+        "events" => {
+            let follow = matches.has("follow");
+            Some(Box::new(move |target| ParsedAction::Events {
+                target,
+                follow,
+                json,
+            }))
         }
+
+        _ => None,
     }
+}
+
+fn usage(command: &'static str, msg: &str, json: bool) -> i32 {
+    Outcome::new(command, "usage", ExitCode::Usage, msg).print(json)
+}
 
-    eprintln!("Unknown Command: {}", args.join(" "));
-    eprintln!();
-    eprintln!("{HELP_TEXT}");
-    None
+fn usage_of(cmd: &Command, json: bool) -> i32 {
+    let msg = format!("Expected: {BIN_NAME} {} {}", cmd.name, cmd.args);
+    usage(cmd.name, msg.trim_end(), json)
 }
 
-fn help() -> Option<ParsedAction> {
-    println!("{HELP_TEXT}");
-    None
+// ------------
+// --- HELP ---
+// ------------
+
+fn help(cmd: Option<&Command>) -> i32 {
+    match cmd {
+        None => println!("{}", help_text()),
+        Some(cmd) => println!("{}", command_help_text(cmd)),
+    }
+
+    ExitCode::Ok as i32
 }
 
-pub fn get_agent_config(start_daemon: bool, start_tcp: bool) -> Result<AgentConfig> {
-    let (fleet_id, device_id, device_secret, connect_mode) = read_env()?;
+fn help_text() -> String {
+    let commands = COMMANDS
+        .iter()
+        .filter(|cmd| !cmd.hidden)
+        .map(|cmd| format!("    {:<20}{}", cmd.name, cmd.about))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "{HELP_HEADER}\n\nUSAGE:\n    {BIN_NAME} <command> [options]\n\nCOMMANDS:\n{commands}\n\n{HELP_FOOTER}"
+    )
+}
+
+fn command_help_text(cmd: &Command) -> String {
+    let args = match cmd.args {
+        "" => String::new(),
+        args => format!("{args} "),
+    };
+
+    let mut text = format!(
+        "{}\n\nUSAGE:\n    {BIN_NAME} {} {args}[options]",
+        cmd.about, cmd.name
+    );
+
+    if !cmd.flags.is_empty() {
+        text.push_str("\n\nOPTIONS:");
+
+        for flag in cmd.flags {
+            let name = match flag.value {
+                None => format!("--{}", flag.name),
+                Some(value) => format!("--{} <{value}>", flag.name),
+            };
+
+            text.push_str(&format!("\n    {name:<24}{}", flag.about));
+        }
+    }
+
+    text
+}
+
+// ----------------------
+// --- AGENT SETTINGS ---
+// ----------------------
+
+fn config_file(matches: &Matches) -> Result<Option<PathBuf>> {
+    Ok(matches.value("config").map(absolute).transpose()?)
+}
+
+fn read_runtime_dir(matches: &Matches) -> Result<RuntimeDir> {
+    let env = Env::load(config_file(matches)?.as_deref())?;
+    runtime_dir(matches, &env)
+}
+
+/// The runtime directory is made absolute, as the daemon runs from within it
+fn runtime_dir(matches: &Matches, env: &Env) -> Result<RuntimeDir> {
+    let path = match matches.value("runtime-dir") {
+        Some(path) => path.to_string(),
+        None => env
+            .get("FOSTROM_RUNTIME_DIR")
+            .unwrap_or(DEFAULT_RUNTIME_DIR.to_string()),
+    };
+
+    if path.is_empty() {
+        return Err(anyhow!("The runtime directory cannot be empty"));
+    }
+
+    Ok(RuntimeDir::new(absolute(path)?))
+}
+
+fn read_target(matches: &Matches) -> Result<AgentTarget> {
+    let env = Env::load(config_file(matches)?.as_deref())?;
+
+    match (env.get("FOSTROM_FLEET_ID"), env.get("FOSTROM_DEVICE_ID")) {
+        (Some(fleet_id), Some(device_id)) => Ok(AgentTarget {
+            runtime_dir: runtime_dir(matches, &env)?,
+            fleet_id,
+            device_id,
+        }),
+        _ => Err(anyhow!(
+            "$FOSTROM_FLEET_ID and $FOSTROM_DEVICE_ID need to be set to talk to the agent"
+        )),
+    }
+}
+
+fn get_agent_config(matches: &Matches, start_daemon: bool) -> Result<AgentConfig> {
+    let env = Env::load(config_file(matches)?.as_deref())?;
+    agent_config(matches, &env, start_daemon)
+}
+
+fn agent_config(matches: &Matches, env: &Env, start_daemon: bool) -> Result<AgentConfig> {
+    let config_file = config_file(matches)?;
+
+    let fleet_id = env.require("FOSTROM_FLEET_ID")?;
+    let device_id = env.require("FOSTROM_DEVICE_ID")?;
+    let device_secret = env.require("FOSTROM_DEVICE_SECRET")?;
+
+    let connect_mode = connect_mode(matches, env)?;
     let prod = matches!(connect_mode, ConnectMode::Prod);
     let creds = Creds::new(fleet_id, device_id, device_secret, prod)?;
 
+    let tcp_addr = matches.value("tcp-addr").unwrap_or(DEFAULT_TCP_ADDR);
+    let tcp_addr = tcp_addr
+        .parse::<SocketAddr>()
+        .map_err(|_| anyhow!("Invalid --tcp-addr `{tcp_addr}`, expected an IP and port"))?;
+
+    let log_level = match matches.value("log-level") {
+        Some(level) => Some(level.to_string()),
+        None => env.get("FOSTROM_LOG_LEVEL"),
+    };
+
+    let log_level = match log_level {
+        None => Level::Info,
+        Some(level) => level.to_lowercase().parse::<Level>().map_err(|_| {
+            anyhow!("Invalid log level `{level}`, expected error, warn, info or debug")
+        })?,
+    };
+
     Ok(AgentConfig {
         creds,
+        runtime_dir: runtime_dir(matches, env)?,
         enable_unix_socket: true,
-        enable_tcp_socket: start_tcp,
+        enable_tcp_socket: matches.has("tcp") || matches.has("tcp-addr"),
+        tcp_addr,
         connect_mode,
+        codec_limits: env.codec_limits()?,
+        drain_timeout: env.drain_timeout()?,
+        rate_limits: env.rate_limits()?,
+        max_pending_txns: env.max_pending_txns()?,
+        log_level,
+        config_runtime_dir: env.in_file("FOSTROM_RUNTIME_DIR"),
+        config_file,
+        args: passed_args(matches),
+        supervise: matches.has("supervise"),
         start_daemon,
     })
 }
 
-fn env_error() -> Error {
-    anyhow!(
-        "To start the Fostrom Device Agent, you need to pass the following environment variables:\n\t$FOSTROM_FLEET_ID\t\tThe 8-character Fleet ID\n\t$FOSTROM_DEVICE_ID\t\tThe 10-character Device ID\n\t$FOSTROM_DEVICE_SECRET\t\tThe 36-character Device Secret, begins with `FOS-`\n\nYou can find these in the Fostrom Console under your device's settings."
-    )
+fn connect_mode(matches: &Matches, env: &Env) -> Result<ConnectMode> {
+    Ok(match matches.value("local-port") {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => ConnectMode::Local(port),
+            _ => return Err(anyhow!("Invalid --local-port `{port}`")),
+        },
+        None if env.flag("FOSTROM_LOCAL_MODE") => ConnectMode::Local(8484),
+        None => ConnectMode::Prod,
+    })
 }
 
-fn read_env() -> Result<(String, String, String, ConnectMode)> {
-    let fleet_id = var("FOSTROM_FLEET_ID").map_err(|_| env_error())?;
-    let device_id = var("FOSTROM_DEVICE_ID").map_err(|_| env_error())?;
-    let device_secret = var("FOSTROM_DEVICE_SECRET").map_err(|_| env_error())?;
+/// `test-conn` connects the way the agent would, unless given an
+/// `--endpoint`. The credentials are only required with `--auth`.
+fn get_test_conn_config(matches: &Matches) -> Result<TestConnConfig> {
+    let env = Env::load(config_file(matches)?.as_deref())?;
 
-    // Check if FOSTROM_LOCAL_MODE is set
-    let local = var("FOSTROM_LOCAL_MODE").unwrap_or("false".to_string());
-    let local = local == "true" || local == "1";
+    let endpoint = match matches.value("endpoint") {
+        Some(_) if matches.has("local-port") => {
+            return Err(anyhow!("--endpoint can't be used along with --local-port"));
+        }
+        Some(endpoint) => parse_endpoint(endpoint)?,
+        None => Endpoint::from(&connect_mode(matches, &env)?),
+    };
 
-    let connect_mode = if local {
-        ConnectMode::Local(8484)
+    let creds = if matches.has("auth") {
+        let prod = matches!(endpoint, Endpoint::Tls(..));
+        Some(Creds::new(
+            env.require("FOSTROM_FLEET_ID")?,
+            env.require("FOSTROM_DEVICE_ID")?,
+            env.require("FOSTROM_DEVICE_SECRET")?,
+            prod,
+        )?)
     } else {
-        ConnectMode::Prod
+        None
     };
 
-    Ok((fleet_id, device_id, device_secret, connect_mode))
+    Ok(TestConnConfig { endpoint, creds })
+}
+
+fn parse_endpoint(endpoint: &str) -> Result<Endpoint> {
+    let invalid = || anyhow!("Invalid --endpoint `{endpoint}`, expected a host and port");
+    let (host, port) = endpoint.rsplit_once(':').ok_or_else(invalid)?;
+
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 && !host.is_empty() => Ok(Endpoint::Tls(host.to_string(), port)),
+        _ => Err(invalid()),
+    }
+}
+
+/// The daemon and the worker report an invalid configuration
+/// back to `start`, over the pipe passed with `--ready-fd`.
+fn parse_daemon(cmd: &Command, matches: &Matches) -> Result<ParsedAction, i32> {
+    let ready_fd = match matches.value("ready-fd").map(parse_ready_fd) {
+        None => None,
+        Some(Ok(fd)) => Some(fd),
+        Some(Err(e)) => return Err(Outcome::failed(cmd.name, e).print(false)),
+    };
+
+    match get_agent_config(matches, true) {
+        Ok(config) if cmd.name == "worker" => Ok(ParsedAction::Worker { config, ready_fd }),
+        Ok(config) => Ok(ParsedAction::Daemon { config, ready_fd }),
+        Err(e) => {
+            Readiness::from_fd(ready_fd).failed(StartupErrorKind::InvalidConfig, &e);
+            Err(Outcome::failed(cmd.name, e).print(false))
+        }
+    }
+}
+
+/// The options of the agent's configuration that were passed on the command line
+fn passed_args(matches: &Matches) -> Vec<String> {
+    [TCP, TCP_ADDR, LOCAL_PORT, LOG_LEVEL, SUPERVISE]
+        .iter()
+        .filter_map(|flag| match (flag.value, matches.value(flag.name)?) {
+            (None, _) => Some(format!("--{}", flag.name)),
+            (Some(_), value) => Some(format!("--{}={value}", flag.name)),
+        })
+        .collect()
+}
+
+/// Reads the configuration of the running agent again. The options passed
+/// on the command line are kept, while the config file is read again.
+/// The environment can't have changed since the agent started, so the
+/// config file takes precedence over it, for the reload to apply the file.
+pub fn reload_config(config: &AgentConfig) -> Result<AgentConfig> {
+    let cmd = find_command("run").ok_or_else(|| anyhow!("Unknown command `run`"))?;
+    let matches = match_args(cmd, &config.flags()).map_err(|e| anyhow!(e))?;
+    let env = Env::load(config_file(&matches)?.as_deref())?.file_first();
+    agent_config(&matches, &env, config.start_daemon)
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_match_args() {
+        let start = find_command("START").unwrap();
+
+        let matches = match_args(
+            start,
+            &args(&[
+                "--runtime-dir=/var/run/Fostrom",
+                "--tcp-addr",
+                "0.0.0.0:9000",
+                "--tcp",
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(matches.value("runtime-dir"), Some("/var/run/Fostrom"));
+        assert_eq!(matches.value("tcp-addr"), Some("0.0.0.0:9000"));
+        assert!(matches.has("tcp"));
+        assert!(!matches.has("foreground"));
+        assert!(matches.positionals.is_empty());
+
+        assert!(match_args(start, &args(&["--unknown"])).is_err());
+        assert!(match_args(start, &args(&["--tcp=yes"])).is_err());
+        assert!(match_args(start, &args(&["--log-level"])).is_err());
+        assert!(match_args(start, &args(&["--help"])).unwrap().help);
+
+        let send = find_command("send").unwrap();
+        assert!(match_args(send, &args(&["--tcp"])).is_err());
+
+        let matches = match_args(send, &args(&["msg", "Alert", "--", "--json"])).unwrap();
+        assert_eq!(matches.positionals, args(&["msg", "Alert", "--json"]));
+        assert!(!matches.has("json"));
+
+        assert!(find_command("test-connection").is_some());
+    }
+
+    #[test]
+    fn test_passed_args() {
+        let run = find_command("run").unwrap();
+
+        let matches = match_args(
+            run,
+            &args(&[
+                "--config=/etc/fostrom/device-agent.env",
+                "--tcp",
+                "--log-level",
+                "debug",
+                "--json",
+            ]),
+        )
+        .unwrap();
+
+        // Only the options of the agent's configuration are passed on
+        assert_eq!(passed_args(&matches), args(&["--tcp", "--log-level=debug"]));
+        assert!(match_args(run, &passed_args(&matches)).is_ok());
+    }
+
+    #[test]
+    fn test_test_conn_config() {
+        let test_conn = find_command("test-conn").unwrap();
+        let config = |flags: &[&str]| {
+            let matches = match_args(test_conn, &args(flags)).unwrap();
+            get_test_conn_config(&matches)
+        };
+
+        let local = config(&["--local-port=9000"]).unwrap();
+        assert_eq!(local.endpoint, Endpoint::Tcp(9000));
+        assert_eq!(local.creds, None);
+
+        let custom = config(&["--endpoint", "staging.example.com:8443"]).unwrap();
+        assert_eq!(
+            custom.endpoint,
+            Endpoint::Tls("staging.example.com".to_string(), 8443)
+        );
+
+        assert!(config(&["--endpoint=localhost:1", "--local-port=2"]).is_err());
+        assert!(config(&["--endpoint=localhost"]).is_err());
+        assert!(config(&["--endpoint=:8443"]).is_err());
+        assert!(config(&["--endpoint=localhost:0"]).is_err());
+    }
 }
@@ -0,0 +1,240 @@
+// -----------------------
+// --- DAEMON READINESS ---
+// -----------------------
+
+// `start` spawns the daemon with the write end of a pipe, passed with the
+// hidden `--ready-fd` flag. The daemon writes a single line to it once its
+// servers are listening, or the reason it failed to start:
+//
+// ```text
+// ready
+// bind_failed Failed to listen on 127.0.0.1:8585: Address in use
+// ```
+//
+// If the daemon exits without writing anything, the pipe is closed,
+// so `start` doesn't have to wait for a timeout to find out.
+
+use super::lock::AlreadyRunning;
+use crate::{http_server::BindError, moonlight_codec::CredErr};
+use anyhow::{Error, Result, anyhow};
+use nix::{
+    fcntl::{F_SETFD, FdFlag, fcntl},
+    unistd::pipe,
+};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    process::Child,
+    sync::mpsc::{RecvTimeoutError, channel},
+    thread::spawn,
+    time::Duration,
+};
+use strum::{Display, EnumString, IntoStaticStr};
+
+/// Why the daemon failed to start, used as the status of `start`
+#[derive(Display, EnumString, IntoStaticStr, Debug, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum StartupErrorKind {
+    AlreadyRunning,
+    BindFailed,
+    PermissionDenied,
+    InvalidConfig,
+    StartFailed,
+}
+
+impl StartupErrorKind {
+    pub fn of(e: &Error) -> Self {
+        if let Some(e) = e.downcast_ref::<StartupError>() {
+            e.kind
+        } else if e.is::<AlreadyRunning>() {
+            Self::AlreadyRunning
+        } else if e.is::<BindError>() {
+            Self::BindFailed
+        } else if e.is::<CredErr>() {
+            Self::InvalidConfig
+        } else if e.chain().any(|cause| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == ErrorKind::PermissionDenied)
+        }) {
+            Self::PermissionDenied
+        } else {
+            Self::StartFailed
+        }
+    }
+}
+
+/// The daemon failed to start, as reported by it over the pipe
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct StartupError {
+    pub kind: StartupErrorKind,
+    pub message: String,
+}
+
+/// The daemon was spawned, but it did not become ready in time
+#[derive(Debug, thiserror::Error)]
+#[error("Timed out waiting for the agent to become ready.")]
+pub struct ReadinessTimeout;
+
+// ----------------------
+// --- PARENT (START) ---
+// ----------------------
+
+/// The pipe that the daemon reports back on
+pub struct ReadyPipe {
+    reader: File,
+    writer: OwnedFd,
+}
+
+impl ReadyPipe {
+    pub fn new() -> Result<Self> {
+        // Only the write end is inherited by the daemon
+        let (reader, writer) = pipe()?;
+        fcntl(&reader, F_SETFD(FdFlag::FD_CLOEXEC))?;
+        fcntl(&writer, F_SETFD(FdFlag::empty()))?;
+
+        Ok(Self {
+            reader: File::from(reader),
+            writer,
+        })
+    }
+
+    /// The `--ready-fd` flag to pass to the daemon
+    pub fn flag(&self) -> String {
+        format!("--ready-fd={}", self.writer.as_raw_fd())
+    }
+
+    /// Waits for the daemon to report back, after it has been spawned.
+    /// If it doesn't in time, it is killed.
+    pub fn wait(self, child: &mut Child, timeout: Duration) -> Result<()> {
+        // Close this process's write end, so the pipe closes when the daemon exits
+        drop(self.writer);
+
+        let (tx, rx) = channel();
+        let reader = self.reader;
+
+        spawn(move || {
+            let mut line = String::new();
+            let _ = BufReader::new(reader).read_line(&mut line);
+            let _ = tx.send(line);
+        });
+
+        let line = match rx.recv_timeout(timeout) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(ReadinessTimeout.into());
+            }
+        };
+
+        let line = line.trim_end();
+
+        if line == "ready" {
+            return Ok(());
+        }
+
+        match line.split_once(' ') {
+            Some((kind, message)) => Err(StartupError {
+                kind: kind.parse().unwrap_or(StartupErrorKind::StartFailed),
+                message: message.to_string(),
+            }
+            .into()),
+            None => {
+                let status = child
+                    .wait()
+                    .map_or("unknown".to_string(), |status| status.to_string());
+
+                Err(StartupError {
+                    kind: StartupErrorKind::StartFailed,
+                    message: format!("The agent exited while starting ({status})"),
+                }
+                .into())
+            }
+        }
+    }
+}
+
+// ---------------------
+// --- CHILD (DAEMON) ---
+// ---------------------
+
+/// Reports back to `start` over the pipe passed with `--ready-fd`.
+/// Only the first report is sent, and without a pipe, nothing is sent.
+#[derive(Debug, Default)]
+pub struct Readiness(Option<File>);
+
+impl Readiness {
+    pub fn from_fd(fd: Option<RawFd>) -> Self {
+        // Never take over stdin, stdout or stderr
+        match fd.filter(|fd| *fd > 2) {
+            Some(fd) => {
+                // SAFETY: `start` spawns the daemon with the write end of the pipe
+                // open as this fd, and it isn't used anywhere else in the daemon.
+                let file = unsafe { File::from_raw_fd(fd) };
+                // Not passed on to the processes started by the daemon
+                let _ = fcntl(&file, F_SETFD(FdFlag::FD_CLOEXEC));
+                Self(Some(file))
+            }
+            None => Self(None),
+        }
+    }
+
+    pub fn ready(&mut self) {
+        self.report("ready");
+    }
+
+    pub fn failed(&mut self, kind: StartupErrorKind, e: &Error) {
+        // Keep it to a single line
+        let message = format!("{e:#}").replace('\n', " ");
+        self.report(&format!("{kind} {message}"));
+    }
+
+    fn report(&mut self, line: &str) {
+        if let Some(mut pipe) = self.0.take() {
+            let _ = writeln!(pipe, "{line}");
+        }
+    }
+}
+
+/// Parses the value of `--ready-fd`
+pub fn parse_ready_fd(value: &str) -> Result<RawFd> {
+    value
+        .parse::<RawFd>()
+        .map_err(|_| anyhow!("Invalid --ready-fd `{value}`"))
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_ready_pipe() {
+        let pipe = ReadyPipe::new().unwrap();
+        let fd = pipe.writer.as_raw_fd();
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("echo 'bind_failed Address in use' >&{fd}"))
+            .spawn()
+            .unwrap();
+
+        let e = pipe.wait(&mut child, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(StartupErrorKind::of(&e), StartupErrorKind::BindFailed);
+        let e = e.downcast_ref::<StartupError>().unwrap();
+        assert_eq!(e.message, "Address in use");
+
+        // Exits without reporting back
+        let pipe = ReadyPipe::new().unwrap();
+        let mut child = Command::new("true").spawn().unwrap();
+        let e = pipe.wait(&mut child, Duration::from_secs(5)).unwrap_err();
+        let e = e.downcast_ref::<StartupError>().unwrap();
+        assert_eq!(e.kind, StartupErrorKind::StartFailed);
+    }
+}
@@ -0,0 +1,136 @@
+// ---------------------
+// --- CONFIG RELOAD ---
+// ---------------------
+
+// The running agent reads its configuration again on SIGHUP, or on
+// `PUT /config`. The options passed on the command line are kept, while
+// the config file is read again, taking precedence over the environment
+// variables that were set when the agent started, as those can't change.
+// When the credentials or the endpoint change, the client reconnects with
+// them, while the HTTP servers and the event stream subscribers carry on.
+// The runtime directory stays where the agent was started, as its sockets
+// can't move.
+
+use super::{AgentConfig, parser::reload_config};
+use crate::{
+    http_server::{RateLimiter, ReloadRequest},
+    log::{self, Level},
+    moonlight_codec::{ClientSettings, MoonlightClient},
+    notifycast::NotifyCast,
+};
+use anyhow::{Result, anyhow};
+use nix::sys::signal::{SigSet, Signal};
+use serde_json::{Value, json};
+use std::{
+    fs::write,
+    sync::{
+        Arc,
+        mpsc::{Sender, channel},
+    },
+    thread::spawn,
+};
+
+/// Blocks SIGHUP in the calling thread, and the threads spawned from it,
+/// so that it's only received by wait_for_sighup(). This has to be called
+/// before any other threads are spawned.
+pub fn block_sighup() -> Result<SigSet> {
+    let mut sigset = SigSet::empty();
+    sigset.add(Signal::SIGHUP);
+    sigset.thread_block()?;
+    Ok(sigset)
+}
+
+/// Starts the thread that owns the current configuration and applies
+/// the reload requests, one at a time.
+pub fn start_reloader(
+    config: AgentConfig,
+    client: MoonlightClient,
+    limiter: Arc<RateLimiter>,
+    notify: NotifyCast,
+) -> Sender<ReloadRequest> {
+    let (reload_tx, reload_rx) = channel::<ReloadRequest>();
+
+    spawn(move || {
+        let mut config = config;
+
+        for reply in reload_rx {
+            let result =
+                reload(&mut config, &client, &limiter, &notify).map_err(|e| format!("{e:#}"));
+            let _ = reply.send(result);
+        }
+    });
+
+    reload_tx
+}
+
+/// Waits for SIGHUP in a separate thread, and reloads the configuration
+pub fn wait_for_sighup(sigset: SigSet, reload: Sender<ReloadRequest>) {
+    spawn(move || {
+        while let Ok(Signal::SIGHUP) = sigset.wait() {
+            let (reply_tx, reply_rx) = channel();
+
+            if reload.send(reply_tx).is_err() {
+                break;
+            }
+
+            if let Ok(Err(e)) = reply_rx.recv()
+                && log::enabled(Level::Error)
+            {
+                eprintln!("config: reload failed error={e}");
+            }
+        }
+    });
+}
+
+fn reload(
+    config: &mut AgentConfig,
+    client: &MoonlightClient,
+    limiter: &RateLimiter,
+    notify: &NotifyCast,
+) -> Result<Value> {
+    let new_config = reload_config(config)?;
+
+    // The sockets, the lock and the PID file can't move while running
+    if new_config.config_runtime_dir != config.config_runtime_dir {
+        return Err(anyhow!(
+            "FOSTROM_RUNTIME_DIR in the config file can't be changed by a reload, restart the agent instead"
+        ));
+    }
+
+    let changed = new_config.fingerprint().changes(&config.fingerprint());
+
+    // Keep the hash file in sync, so `start` compares against the reloaded settings.
+    // Written before applying anything, so that a failed reload changes nothing.
+    write(
+        new_config.runtime_dir.hash_file(),
+        new_config.fingerprint().to_string(),
+    )?;
+
+    log::set_level(new_config.log_level);
+
+    let reconnecting = client.reconfigure(ClientSettings {
+        fleet_id: new_config.creds.fleet_id.clone(),
+        device_id: new_config.creds.device_id.clone(),
+        device_secret: new_config.creds.device_secret.clone(),
+        connect_mode: new_config.connect_mode.clone(),
+        codec_limits: new_config.codec_limits,
+        drain_timeout: new_config.drain_timeout,
+        max_pending_txns: new_config.max_pending_txns,
+    });
+
+    limiter.set_limits(new_config.rate_limits);
+
+    *config = new_config;
+
+    if log::enabled(Level::Info) {
+        println!(
+            "config: reloaded changed={} reconnecting={reconnecting}",
+            changed.join(",")
+        );
+    }
+
+    let data = json!({"changed": changed, "reconnecting": reconnecting});
+    notify.publish("config_reloaded".to_string(), data.to_string());
+
+    Ok(json!({"reloaded": true, "changed": changed, "reconnecting": reconnecting}))
+}
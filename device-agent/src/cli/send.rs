@@ -0,0 +1,62 @@
+// ------------------------
+// --- CLI SEND HANDLER ---
+// ------------------------
+
+use super::{
+    AgentTarget,
+    client::{self, device_headers, io_failure},
+    output::{ExitCode, Outcome},
+};
+use crate::{http_server::is_valid_pulse_name, moonlight_codec::PulseType};
+use serde_json::Value;
+
+/// Sends a pulse through the running agent.
+/// The payload, when given, must be valid JSON.
+pub fn send_pulse(
+    target: &AgentTarget,
+    pulse_type: PulseType,
+    name: String,
+    payload: Option<String>,
+    json: bool,
+) -> i32 {
+    send_outcome(target, pulse_type, name, payload).print(json)
+}
+
+fn send_outcome(
+    target: &AgentTarget,
+    pulse_type: PulseType,
+    name: String,
+    payload: Option<String>,
+) -> Outcome {
+    if !is_valid_pulse_name(&name) {
+        return Outcome::new("send", "usage", ExitCode::Usage, "Invalid Pulse Name");
+    }
+
+    if let Some(payload) = &payload
+        && serde_json::from_str::<Value>(payload).is_err()
+    {
+        return Outcome::new(
+            "send",
+            "usage",
+            ExitCode::Usage,
+            "The payload must be valid JSON",
+        );
+    }
+
+    let headers = device_headers(target);
+    let path = format!("/pulse/{pulse_type}/{name}");
+    let sock_file = target.runtime_dir.sock_file();
+
+    match client::request(&sock_file, "POST", &path, &headers, payload.as_deref()) {
+        Ok(resp) if resp.code == 200 => Outcome::new(
+            "send",
+            "sent",
+            ExitCode::Ok,
+            format!("The {pulse_type} `{name}` was sent."),
+        ),
+        Ok(resp) => resp.failure("send"),
+        Err(e) => io_failure("send", &e),
+    }
+    .with("type", pulse_type.to_string())
+    .with("name", name)
+}
@@ -0,0 +1,621 @@
+// ---------------------------
+// --- CLI SERVICE HANDLER ---
+// ---------------------------
+
+use super::{
+    AgentConfig,
+    output::{ExitCode, Outcome},
+    stop::terminate_agent,
+};
+use anyhow::{Result, anyhow};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    env::{current_exe, vars},
+    fs::{self, read_to_string},
+    io::{ErrorKind, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt, symlink},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use strum::{Display, EnumString};
+
+const SERVICE_NAME: &str = "fostrom-device-agent";
+
+/// Where the credentials are written when they come from the environment
+const ENV_FILE: &str = "/etc/fostrom/device-agent.env";
+
+/// The first line of every generated file, along with a checksum
+/// of the rest of the file, to detect files edited by hand.
+const MARKER: &str = "# Generated by `fostrom-device-agent install-service`";
+
+#[derive(Display, EnumString, Debug, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum InitSystem {
+    Systemd,
+    OpenRC,
+    Runit,
+}
+
+impl InitSystem {
+    fn detect() -> Option<Self> {
+        if Path::new("/run/systemd/system").is_dir() {
+            Some(Self::Systemd)
+        } else if Path::new("/run/openrc").is_dir() {
+            Some(Self::OpenRC)
+        } else if Path::new("/run/runit").is_dir() || Path::new("/etc/runit").is_dir() {
+            Some(Self::Runit)
+        } else {
+            None
+        }
+    }
+
+    fn service_file(&self) -> PathBuf {
+        match self {
+            Self::Systemd => format!("/etc/systemd/system/{SERVICE_NAME}.service").into(),
+            Self::OpenRC => format!("/etc/init.d/{SERVICE_NAME}").into(),
+            Self::Runit => format!("/etc/sv/{SERVICE_NAME}/run").into(),
+        }
+    }
+
+    fn service_file_mode(&self) -> u32 {
+        match self {
+            Self::Systemd => 0o644,
+            Self::OpenRC | Self::Runit => 0o755,
+        }
+    }
+
+    /// Renders the service definition, running `exe run <flags>`
+    fn render(&self, exe: &str, flags: &[String], runtime_dir: &Path) -> String {
+        // With --supervise, the watchdog pings come from the worker process
+        let notify_access = match flags.iter().any(|flag| flag == "--supervise") {
+            true => "\nNotifyAccess=all",
+            false => "",
+        };
+
+        let flags = flags.join(" ");
+        let runtime_dir = runtime_dir.display();
+
+        match self {
+            Self::Systemd => format!(
+                r#"[Unit]
+Description=Fostrom Device Agent
+Documentation=https://fostrom.io/docs/
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify{notify_access}
+ExecStartPre=+/bin/mkdir -p -m 0700 {runtime_dir}
+ExecStart={exe} run {flags}
+Restart=always
+RestartSec=5
+WatchdogSec=3min
+
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=read-only
+ReadWritePaths={runtime_dir}
+PrivateDevices=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6
+RestrictNamespaces=yes
+RestrictRealtime=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+
+[Install]
+WantedBy=multi-user.target
+"#
+            ),
+
+            Self::OpenRC => format!(
+                r#"#!/sbin/openrc-run
+
+name="Fostrom Device Agent"
+supervisor=supervise-daemon
+command="{exe}"
+command_args="run {flags}"
+respawn_delay=5
+respawn_max=0
+output_log="/var/log/{SERVICE_NAME}.log"
+error_log="/var/log/{SERVICE_NAME}.log"
+
+depend() {{
+    need net
+}}
+
+start_pre() {{
+    checkpath --directory --mode 0700 "{runtime_dir}"
+}}
+"#
+            ),
+
+            Self::Runit => format!(
+                r#"#!/bin/sh
+
+exec 2>&1
+mkdir -p -m 0700 "{runtime_dir}"
+exec "{exe}" run {flags}
+"#
+            ),
+        }
+    }
+
+    /// Enables the service to start on boot, and (re)starts it
+    fn enable(&self) -> Result<()> {
+        let service = format!("{SERVICE_NAME}.service");
+
+        match self {
+            Self::Systemd => {
+                run("systemctl", &["daemon-reload"])?;
+                run("systemctl", &["enable", &service])?;
+                run("systemctl", &["restart", &service])
+            }
+
+            Self::OpenRC => {
+                run("rc-update", &["add", SERVICE_NAME, "default"])?;
+                run("rc-service", &[SERVICE_NAME, "restart"])
+            }
+
+            // runsvdir starts the service within a few seconds of it being linked
+            Self::Runit => {
+                let link = runit_service_dir()?.join(SERVICE_NAME);
+
+                if link.exists() {
+                    run("sv", &["restart", &link.to_string_lossy()])
+                } else {
+                    symlink(runit_sv_dir(), &link)
+                        .map_err(|e| anyhow!("Failed to link {}: {e}", link.display()))
+                }
+            }
+        }
+    }
+
+    /// Stops the service and disables it from starting on boot
+    fn disable(&self) -> Result<()> {
+        match self {
+            Self::Systemd => run(
+                "systemctl",
+                &["disable", "--now", &format!("{SERVICE_NAME}.service")],
+            ),
+
+            Self::OpenRC => {
+                run("rc-service", &[SERVICE_NAME, "stop"])?;
+                run("rc-update", &["del", SERVICE_NAME, "default"])
+            }
+
+            Self::Runit => {
+                let link = runit_service_dir()?.join(SERVICE_NAME);
+
+                if link.exists() {
+                    run("sv", &["stop", &link.to_string_lossy()])?;
+                    fs::remove_file(&link)
+                        .map_err(|e| anyhow!("Failed to remove {}: {e}", link.display()))?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+fn runit_sv_dir() -> PathBuf {
+    Path::new("/etc/sv").join(SERVICE_NAME)
+}
+
+/// The directory watched by runsvdir, which differs between distributions
+fn runit_service_dir() -> Result<PathBuf> {
+    ["/var/service", "/etc/service", "/service"]
+        .iter()
+        .map(PathBuf::from)
+        .find(|dir| dir.is_dir())
+        .ok_or_else(|| anyhow!("Failed to find the runit service directory"))
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let cmd = format!("{program} {}", args.join(" "));
+
+    let status = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| anyhow!("Failed to run `{cmd}`: {e}"))?;
+
+    if !status.success() {
+        return Err(anyhow!("`{cmd}` failed with {status}"));
+    }
+
+    Ok(())
+}
+
+// -----------------------
+// --- INSTALL SERVICE ---
+// -----------------------
+
+/// A file written by install-service
+struct GeneratedFile {
+    path: PathBuf,
+    content: String,
+    mode: u32,
+}
+
+impl GeneratedFile {
+    fn new(path: impl Into<PathBuf>, content: &str, mode: u32) -> Self {
+        Self {
+            path: path.into(),
+            content: stamp(content),
+            mode,
+        }
+    }
+
+    fn write(&self) -> Result<()> {
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+
+            // The mode is set on creation, so that the credentials
+            // are never readable by others
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(self.mode)
+                .open(&self.path)?
+                .write_all(self.content.as_bytes())?;
+
+            fs::set_permissions(&self.path, PermissionsExt::from_mode(self.mode))
+        };
+
+        write().map_err(|e| anyhow!("Failed to write {}: {e}", self.path.display()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FileState {
+    Missing,
+    Generated,
+    Modified,
+}
+
+/// Installs a service for the detected init system, which runs the agent
+/// with the same configuration as this command. An agent started with
+/// `start` in the same runtime directory is stopped first.
+pub fn install_service(config: AgentConfig, init: Option<InitSystem>, json: bool) -> i32 {
+    install_outcome(config, init).print(json)
+}
+
+fn install_outcome(mut config: AgentConfig, init: Option<InitSystem>) -> Outcome {
+    let command = "install-service";
+
+    let Some(init) = init.or_else(InitSystem::detect) else {
+        return no_init_system(command);
+    };
+
+    let exe = match current_exe() {
+        Ok(exe) => exe.to_string_lossy().to_string(),
+        Err(e) => return Outcome::failed(command, format!("Failed to find the agent: {e}")),
+    };
+
+    let mut files = Vec::new();
+
+    // Credentials from the environment are written to a file only root can read,
+    // instead of the service definition.
+    if config.config_file.is_none() {
+        match env_file() {
+            Ok(content) => files.push(GeneratedFile::new(ENV_FILE, &content, 0o600)),
+            Err(e) => return Outcome::failed(command, e),
+        }
+
+        config.config_file = Some(ENV_FILE.into());
+    }
+
+    let flags = config.flags();
+
+    if let Some(arg) = std::iter::once(&exe)
+        .chain(&flags)
+        .find(|a| !is_safe_arg(a))
+    {
+        return Outcome::new(
+            command,
+            "usage",
+            ExitCode::Usage,
+            format!("`{arg}` cannot be used in a service definition, as it has spaces or quotes"),
+        );
+    }
+
+    let content = init.render(&exe, &flags, config.runtime_dir.path());
+    files.push(GeneratedFile::new(
+        init.service_file(),
+        &content,
+        init.service_file_mode(),
+    ));
+
+    let paths = files
+        .iter()
+        .map(|f| f.path.display().to_string())
+        .collect::<Vec<_>>();
+
+    if let Some(file) = files
+        .iter()
+        .find(|f| file_state(&f.path) == FileState::Modified)
+    {
+        return modified(command, &file.path);
+    }
+
+    let changed = files
+        .iter()
+        .filter(|f| read_to_string(&f.path).ok().as_deref() != Some(f.content.as_str()))
+        .collect::<Vec<_>>();
+
+    if changed.is_empty() {
+        return Outcome::new(
+            command,
+            "already_installed",
+            ExitCode::Ok,
+            format!("The {init} service is already installed."),
+        )
+        .with("init", init.to_string())
+        .with("files", json!(paths));
+    }
+
+    for file in changed {
+        if let Err(e) = file.write() {
+            return Outcome::failed(command, e);
+        }
+    }
+
+    terminate_agent(&config.runtime_dir);
+
+    if let Err(e) = init.enable() {
+        return Outcome::failed(
+            command,
+            format!("The {init} service was written, but failed to start: {e}"),
+        )
+        .with("init", init.to_string())
+        .with("files", json!(paths));
+    }
+
+    Outcome::new(
+        command,
+        "installed",
+        ExitCode::Ok,
+        format!("The {init} service was installed and started."),
+    )
+    .with("init", init.to_string())
+    .with("files", json!(paths))
+}
+
+/// The `FOSTROM_*` environment variables, in the format read by `--config`
+fn env_file() -> Result<String> {
+    let mut vars = vars()
+        .filter(|(name, _)| name.starts_with("FOSTROM_"))
+        .collect::<Vec<_>>();
+    vars.sort();
+
+    let mut content = String::new();
+
+    for (name, value) in vars {
+        if value.contains(['\n', '\r']) {
+            return Err(anyhow!("${name} cannot contain a newline"));
+        }
+
+        content.push_str(&format!("{name}={value}\n"));
+    }
+
+    Ok(content)
+}
+
+/// Arguments are written unquoted, so they are limited to characters
+/// that every init system passes through as is.
+fn is_safe_arg(arg: &str) -> bool {
+    !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./:=@+,[]".contains(c))
+}
+
+// -------------------------
+// --- UNINSTALL SERVICE ---
+// -------------------------
+
+/// Stops and removes the service installed by install-service,
+/// along with the credentials file that it wrote.
+pub fn uninstall_service(init: Option<InitSystem>, json: bool) -> i32 {
+    uninstall_outcome(init).print(json)
+}
+
+fn uninstall_outcome(init: Option<InitSystem>) -> Outcome {
+    let command = "uninstall-service";
+
+    let Some(init) = init.or_else(InitSystem::detect) else {
+        return no_init_system(command);
+    };
+
+    let service_file = init.service_file();
+    let env_file = PathBuf::from(ENV_FILE);
+
+    let service_state = file_state(&service_file);
+    let env_state = file_state(&env_file);
+
+    if service_state == FileState::Modified {
+        return modified(command, &service_file);
+    }
+
+    if service_state == FileState::Missing && env_state != FileState::Generated {
+        return Outcome::new(
+            command,
+            "not_installed",
+            ExitCode::Ok,
+            format!("The {init} service is not installed."),
+        )
+        .with("init", init.to_string());
+    }
+
+    let mut removed = Vec::new();
+
+    if service_state == FileState::Generated {
+        if let Err(e) = init.disable() {
+            return Outcome::failed(command, format!("Failed to stop the {init} service: {e}"));
+        }
+
+        let result = match init {
+            InitSystem::Runit => fs::remove_dir_all(runit_sv_dir()),
+            _ => fs::remove_file(&service_file),
+        };
+
+        if let Err(e) = result {
+            return Outcome::failed(
+                command,
+                format!("Failed to remove {}: {e}", service_file.display()),
+            );
+        }
+
+        if init == InitSystem::Systemd {
+            let _ = run("systemctl", &["daemon-reload"]);
+        }
+
+        removed.push(service_file.display().to_string());
+    }
+
+    // A config file passed to install-service is left alone
+    if env_state == FileState::Generated {
+        if let Err(e) = fs::remove_file(&env_file) {
+            return Outcome::failed(command, format!("Failed to remove {ENV_FILE}: {e}"));
+        }
+
+        removed.push(ENV_FILE.to_string());
+    }
+
+    Outcome::new(
+        command,
+        "uninstalled",
+        ExitCode::Ok,
+        format!("The {init} service was stopped and removed."),
+    )
+    .with("init", init.to_string())
+    .with("files", json!(removed))
+}
+
+// -----------------------
+// --- GENERATED FILES ---
+// -----------------------
+
+/// Adds the marker with the checksum of the content, after the shebang if any
+fn stamp(content: &str) -> String {
+    let marker = format!("{MARKER} sha256:{}", checksum(content));
+
+    match content.split_once('\n') {
+        Some((shebang, rest)) if shebang.starts_with("#!") => {
+            format!("{shebang}\n{marker}\n{rest}")
+        }
+        _ => format!("{marker}\n{content}"),
+    }
+}
+
+/// Whether the file is missing, generated by install-service and untouched,
+/// or modified (including files that were never generated)
+fn file_state(path: &Path) -> FileState {
+    let content = match read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return FileState::Missing,
+        Err(_) => return FileState::Modified,
+    };
+
+    let mut lines = content.split_inclusive('\n');
+    let mut rest = String::new();
+    let mut sum = None;
+
+    for line in lines.by_ref() {
+        match line.trim_end().strip_prefix(MARKER) {
+            Some(marker) if sum.is_none() => {
+                sum = marker.trim().strip_prefix("sha256:").map(str::to_string);
+            }
+            _ => rest.push_str(line),
+        }
+    }
+
+    match sum {
+        Some(sum) if sum == checksum(&rest) => FileState::Generated,
+        _ => FileState::Modified,
+    }
+}
+
+fn checksum(content: &str) -> String {
+    hex::encode(Sha256::digest(content.as_bytes()))
+}
+
+fn modified(command: &'static str, path: &Path) -> Outcome {
+    Outcome::new(
+        command,
+        "modified",
+        ExitCode::Failed,
+        format!(
+            "{} was not generated by install-service, or was edited by hand. Remove it first to continue.",
+            path.display()
+        ),
+    )
+    .with("file", path.display().to_string())
+}
+
+fn no_init_system(command: &'static str) -> Outcome {
+    Outcome::new(
+        command,
+        "usage",
+        ExitCode::Usage,
+        "Could not detect the init system. Pass --init with systemd, openrc or runit.",
+    )
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_files() {
+        let dir = std::env::temp_dir().join(format!("fostrom-service-{}", std::process::id()));
+        let path = dir.join("run");
+
+        assert_eq!(file_state(&path), FileState::Missing);
+
+        let flags = vec!["--runtime-dir=/tmp/fostrom".to_string()];
+        let content = InitSystem::Runit.render("/usr/bin/agent", &flags, Path::new("/tmp/fostrom"));
+        let file = GeneratedFile::new(&path, &content, 0o755);
+        assert!(
+            file.content
+                .starts_with(&format!("#!/bin/sh\n{MARKER} sha256:"))
+        );
+
+        file.write().unwrap();
+        assert_eq!(file_state(&path), FileState::Generated);
+
+        fs::write(&path, file.content.replace("exec 2>&1", "exec 2>/dev/null")).unwrap();
+        assert_eq!(file_state(&path), FileState::Modified);
+
+        fs::write(&path, content).unwrap();
+        assert_eq!(file_state(&path), FileState::Modified);
+
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(is_safe_arg("--tcp-addr=[::1]:8585"));
+        assert!(!is_safe_arg("--config=/home/my files/agent.env"));
+        assert!(!is_safe_arg("--config=\"agent.env\""));
+        assert_eq!("openrc".parse::<InitSystem>().unwrap(), InitSystem::OpenRC);
+
+        // The worker under a supervisor sends the watchdog pings
+        let unit = InitSystem::Systemd.render("/usr/bin/agent", &flags, Path::new("/tmp/fostrom"));
+        assert!(!unit.contains("NotifyAccess"));
+        let flags = vec!["--supervise".to_string()];
+        let unit = InitSystem::Systemd.render("/usr/bin/agent", &flags, Path::new("/tmp/fostrom"));
+        assert!(unit.contains("Type=notify\nNotifyAccess=all\n"));
+    }
+}
@@ -2,125 +2,285 @@
 // --- CLI START HANDLER ---
 // -------------------------
 
-use super::{HASH_FILE, PID_FILE, SOCK_FILE, TMP_DIR};
+use super::output::{ExitCode, Outcome};
 use crate::{
-    cli::{AgentConfig, daemon::start_daemon, stop::terminate_agent},
-    http_server::{self, SocketContext},
-    moonlight_codec::{Creds, MoonlightClient},
+    cli::{
+        AgentConfig, Fingerprint,
+        daemon::start_daemon,
+        lock::{AgentLock, PidFileGuard, StartLock},
+        readiness::{Readiness, ReadinessTimeout, StartupError, StartupErrorKind},
+        reload::{block_sighup, start_reloader, wait_for_sighup},
+        stop::terminate_agent,
+        supervisor::{exit_with_supervisor, report_crash, supervise},
+    },
+    http_server::{
+        IdempotencyStore, PulseSubmissions, RateLimiter, Shutdown, SocketContext, TcpServer,
+        UnixServer,
+    },
+    log,
+    moonlight_codec::MoonlightClient,
     notifycast::NotifyCast,
+    systemd,
 };
 use anyhow::Result;
+use nix::sys::signal::SigSet;
 use std::{
     fs::{create_dir_all, read_to_string, remove_file, set_permissions, write},
+    os::fd::RawFd,
     os::unix::{fs::PermissionsExt, net::UnixStream},
-    path::Path,
-    process,
-    sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
-        mpsc::channel,
-    },
-    thread::{JoinHandle, spawn},
+    path::PathBuf,
+    sync::{Arc, atomic::AtomicUsize, mpsc::channel},
+    thread::spawn,
 };
 
-struct PidFileGuard;
-
-impl PidFileGuard {
-    fn create() -> Result<Self> {
-        let pid = process::id();
-        write(PID_FILE, format!("{pid}\n"))?;
-        Ok(Self)
-    }
-}
-
-impl Drop for PidFileGuard {
-    fn drop(&mut self) {
-        let _ = remove_file(PID_FILE);
-    }
-}
-
-struct HashFileGuard;
+struct HashFileGuard(PathBuf);
 
 impl HashFileGuard {
-    fn create(creds: &Creds) -> Result<Self> {
-        let hash = creds.hash();
-        write(HASH_FILE, format!("{hash}\n"))?;
-        Ok(Self)
+    fn create(config: &AgentConfig) -> Result<Self> {
+        let path = config.runtime_dir.hash_file();
+        write(&path, config.fingerprint().to_string())?;
+        Ok(Self(path))
     }
 }
 
 impl Drop for HashFileGuard {
     fn drop(&mut self) {
-        let _ = remove_file(HASH_FILE);
+        let _ = remove_file(&self.0);
     }
 }
 
 enum Preflight {
     AlreadyStarted,
     StartFresh,
+    /// The running agent was stopped, as these settings changed
+    Restart(Vec<String>),
 }
 
 /// Called when cmd is `daemon` (which is started below).
 /// This function detaches the process and starts the agent in the background.
-pub fn start_daemon_child(config: AgentConfig) {
+pub fn start_daemon_child(config: AgentConfig, ready_fd: Option<RawFd>) -> i32 {
     use nix::unistd::setsid;
     // Detach into a new session; ignore any error
     let _ = setsid();
-    // Run the agent in the child process (blocking)
-    let _ = start_proc(config);
+
+    // Run the agent in the child process (blocking),
+    // reporting back to `start` once it's ready, or why it failed to start
+    let mut readiness = Readiness::from_fd(ready_fd);
+
+    let result = match config.supervise {
+        true => supervise(config, None, &mut readiness),
+        false => start_proc(config, None, &mut readiness),
+    };
+
+    exit_code(result, &mut readiness)
+}
+
+/// Called when cmd is `worker`, which is started by the supervisor.
+/// The supervisor holds the agent lock and the PID file.
+pub fn start_worker(config: AgentConfig, ready_fd: Option<RawFd>) -> i32 {
+    let mut readiness = Readiness::from_fd(ready_fd);
+
+    let result = block_sighup().and_then(|sighup| {
+        exit_with_supervisor();
+        systemd::act_for_parent();
+        run_agent(config, sighup, None, &mut readiness)
+    });
+
+    exit_code(result, &mut readiness)
+}
+
+/// Reports why the agent failed to start, if it wasn't ready yet
+fn exit_code(result: Result<()>, readiness: &mut Readiness) -> i32 {
+    match result {
+        Ok(()) => ExitCode::Ok as i32,
+        Err(e) => {
+            readiness.failed(StartupErrorKind::of(&e), &e);
+            eprintln!("{e:#}");
+            ExitCode::StartFailed as i32
+        }
+    }
 }
 
 /// Called when cmd is `start` or `run`.
 /// This function starts the agent in the foreground
 /// or spawns the daemon process, after conducting
 /// preflight checks.
-pub fn start_agent(config: AgentConfig) {
-    create_dir_all(TMP_DIR).expect("failed: Failed to create /tmp/fostrom directory");
+pub fn start_agent(config: AgentConfig, json: bool) -> i32 {
+    start_outcome(config).print(json)
+}
+
+fn start_outcome(config: AgentConfig) -> Outcome {
+    let command = if config.start_daemon { "start" } else { "run" };
+
+    let dir = config.runtime_dir.path();
+
+    if create_dir_all(dir).is_err() {
+        return Outcome::failed(
+            command,
+            format!("Failed to create the {} directory", dir.display()),
+        );
+    }
+
+    if set_permissions(dir, PermissionsExt::from_mode(0o700)).is_err() {
+        return Outcome::failed(
+            command,
+            format!(
+                "Failed to set permissions on the {} directory",
+                dir.display()
+            ),
+        );
+    }
 
-    set_permissions(TMP_DIR, PermissionsExt::from_mode(0o700))
-        .expect("failed: Failed to set permissions on /tmp/fostrom directory");
+    // Wait for any other start or run in this runtime directory, until the agent is up
+    let start_lock = match StartLock::acquire(&config.runtime_dir) {
+        Ok(lock) => lock,
+        Err(e) => return Outcome::failed(command, e),
+    };
 
     match preflight(&config) {
-        Preflight::AlreadyStarted => {
-            println!("already_started: The agent is already running with the same configuration.");
+        Preflight::AlreadyStarted => Outcome::new(
+            command,
+            "already_started",
+            ExitCode::Ok,
+            "The agent is already running with the same configuration.",
+        ),
+        Preflight::Restart(changed) if config.start_daemon => daemon_outcome(
+            start_daemon(config),
+            Outcome::new(
+                "start",
+                "restarted",
+                ExitCode::Ok,
+                format!(
+                    "The agent daemon was restarted, as these settings changed: {}.",
+                    changed.join(", ")
+                ),
+            )
+            .with("changed", changed),
+        ),
+        Preflight::StartFresh if config.start_daemon => daemon_outcome(
+            start_daemon(config),
+            Outcome::new(
+                "start",
+                "started",
+                ExitCode::Ok,
+                "The agent daemon is running.",
+            ),
+        ),
+        preflight => {
+            if let Preflight::Restart(changed) = preflight {
+                eprintln!(
+                    "Stopped the running agent, as these settings changed: {}",
+                    changed.join(", ")
+                );
+            }
+
+            let mut readiness = Readiness::default();
+
+            let result = match config.supervise {
+                true => supervise(config, Some(start_lock), &mut readiness),
+                false => start_proc(config, Some(start_lock), &mut readiness),
+            };
+
+            match result {
+                Ok(()) => Outcome::new(command, "stopped", ExitCode::Ok, "The agent has stopped."),
+                Err(e) => Outcome::failed(command, e),
+            }
         }
-        Preflight::StartFresh => if config.start_daemon {
-            start_daemon(config)
-        } else {
-            start_proc(config)
+    }
+}
+
+/// The outcome of `start`, given the outcome for when the daemon is ready
+fn daemon_outcome(result: Result<()>, ready: Outcome) -> Outcome {
+    match result {
+        Ok(()) => ready,
+        Err(e) if e.is::<ReadinessTimeout>() => {
+            Outcome::new("start", "timeout", ExitCode::Timeout, e)
         }
-        .unwrap_or_else(|e| eprintln!("failed: {e}")),
+        Err(e) => match e.downcast_ref::<StartupError>() {
+            Some(failure) => {
+                Outcome::new("start", failure.kind.into(), ExitCode::StartFailed, failure)
+            }
+            None => Outcome::failed("start", e),
+        },
     }
 }
 
-/// If the Device Agent is already running, compare the credhash
-/// to check whether to restart or not.
+/// If the Device Agent is already running, compare its settings from
+/// the hash file to check whether to restart or not. With socket activation,
+/// systemd owns the socket and ensures only one agent is running.
 fn preflight(config: &AgentConfig) -> Preflight {
-    if Path::new(SOCK_FILE).exists()
-        && Path::new(HASH_FILE).exists()
-        && let Ok(_) = UnixStream::connect(SOCK_FILE)
-        && let new_hash = config.creds.hash()
-        && let Some(prev_hash) = read_to_string(HASH_FILE).ok().map(|s| s.trim().to_string())
-        && prev_hash == new_hash
+    let runtime_dir = &config.runtime_dir;
+
+    if systemd::socket_activated() {
+        return Preflight::StartFresh;
+    }
+
+    if let Ok(_) = UnixStream::connect(runtime_dir.sock_file())
+        && let Ok(prev) = read_to_string(runtime_dir.hash_file())
     {
-        Preflight::AlreadyStarted
+        let changed = config.fingerprint().changes(&Fingerprint::parse(&prev));
+
+        if changed.is_empty() {
+            return Preflight::AlreadyStarted;
+        }
+
+        terminate_agent(runtime_dir);
+        Preflight::Restart(changed)
     } else {
-        terminate_agent();
+        terminate_agent(runtime_dir);
         Preflight::StartFresh
     }
 }
 
-fn start_proc(config: AgentConfig) -> Result<()> {
+/// Runs the agent until it's stopped. Once its servers are listening,
+/// readiness is reported, and the start lock, if any, is released.
+fn start_proc(
+    config: AgentConfig,
+    start_lock: Option<StartLock>,
+    readiness: &mut Readiness,
+) -> Result<()> {
+    // SIGHUP reloads the configuration, so it's blocked
+    // before any threads are spawned, and waited on below.
+    let sighup = block_sighup()?;
+
+    // Held until the agent exits, released by the kernel even if it is killed
+    let _agent_lock = AgentLock::acquire(&config.runtime_dir)?;
+
     // Create the PID file, and ensure it's deleted on exit.
     // Automatic cleanup is handled by the PidFileGuard's Drop impl.
-    let _pid_guard = PidFileGuard::create()?;
+    let _pid_guard = PidFileGuard::create(&config.runtime_dir)?;
 
+    run_agent(config, sighup, start_lock, readiness)
+}
+
+/// Runs the agent's servers and client, in the agent's process or in the
+/// worker started by the supervisor, which sends the recorded crash, if any.
+fn run_agent(
+    config: AgentConfig,
+    sighup: SigSet,
+    start_lock: Option<StartLock>,
+    readiness: &mut Readiness,
+) -> Result<()> {
     // Create the Hash file, and ensure it's deleted on exit.
     // Automatic cleanup is handled by the HashFileGuard's Drop impl.
-    let _hash_guard = HashFileGuard::create(&config.creds)?;
+    let _hash_guard = HashFileGuard::create(&config)?;
+
+    log::set_level(config.log_level);
+
+    // Bind the servers before starting anything else,
+    // so that a failure to listen is reported right away
+    let unix_server = match config.enable_unix_socket {
+        true => Some(UnixServer::bind(&config.runtime_dir.sock_file())?),
+        false => None,
+    };
+
+    let tcp_server = match config.enable_tcp_socket {
+        true => Some(TcpServer::bind(config.tcp_addr)?),
+        false => None,
+    };
 
-    let shutdown_flag = Arc::new(AtomicBool::new(false));
-    let s = shutdown_flag.clone();
+    let shutdown = Shutdown::new()?;
+    let s = shutdown.clone();
 
     // Setup the notification channel and its broadcast system
     let (notify_chan_tx, notify_chan_rx) = channel();
@@ -128,47 +288,70 @@ fn start_proc(config: AgentConfig) -> Result<()> {
     let notify_handle = notify.start_listener(notify_chan_rx);
 
     let mut client = MoonlightClient::new(
-        config.creds.fleet_id,
-        config.creds.device_id,
-        config.creds.device_secret,
-        config.connect_mode,
+        config.creds.fleet_id.clone(),
+        config.creds.device_id.clone(),
+        config.creds.device_secret.clone(),
+        config.connect_mode.clone(),
+        config.codec_limits,
+        config.drain_timeout,
+        config.max_pending_txns,
     );
 
     let client_clone = client.clone();
     ctrlc::set_handler(move || {
-        s.store(true, Ordering::SeqCst);
+        systemd::stopping();
+        s.trigger();
         client_clone.stop();
     })?;
 
+    if config.supervise {
+        report_crash(&config.runtime_dir, client.clone(), &notify);
+    }
+
+    let limiter = Arc::new(RateLimiter::new(config.rate_limits));
+    let reload = start_reloader(
+        config.clone(),
+        client.clone(),
+        limiter.clone(),
+        notify.clone(),
+    );
+    wait_for_sighup(sighup, reload.clone());
+
     let socket_context = SocketContext {
         notify,
         client: client.clone(),
-        shutdown_flag: shutdown_flag.clone(),
+        shutdown: shutdown.clone(),
+        connections: Arc::new(AtomicUsize::new(0)),
+        limiter,
+        idempotency: Arc::new(IdempotencyStore::new()),
+        submissions: Arc::new(PulseSubmissions::new()),
+        reload,
     };
 
-    let mut unix_handle: Option<JoinHandle<()>> = None;
-    let mut tcp_handle: Option<JoinHandle<()>> = None;
-
     // Start the UNIX Server
-    if config.enable_unix_socket {
+    let unix_handle = unix_server.map(|server| {
         let ctx = socket_context.clone();
-        unix_handle = Some(spawn(move || {
-            let _ = http_server::start_unix_server(&ctx);
-        }));
-    }
+        spawn(move || {
+            let _ = server.serve(&ctx);
+        })
+    });
 
     // Start the TCP Server
-    if config.enable_tcp_socket {
+    let tcp_handle = tcp_server.map(|server| {
         let ctx = socket_context.clone();
-        tcp_handle = Some(spawn(move || {
-            let _ = http_server::start_tcp_server(&ctx);
-        }));
-    }
+        spawn(move || {
+            let _ = server.serve(&ctx);
+        })
+    });
+
+    readiness.ready();
+    systemd::ready();
+    drop(start_lock);
 
     client.start(notify_chan_tx)?;
 
-    // Ensure shutdown flag is set so accept loops exit promptly
-    shutdown_flag.store(true, Ordering::SeqCst);
+    // Ensure shutdown is triggered so accept loops exit promptly
+    shutdown.trigger();
 
     // Close Threads
     if let Some(h) = unix_handle {
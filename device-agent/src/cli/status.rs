@@ -2,46 +2,111 @@
 // --- CLI STATUS HANDLER ---
 // --------------------------
 
-use super::SOCK_FILE;
-use anyhow::{Result, anyhow};
-use std::io::{Read, Write};
-use std::os::unix::net::UnixStream;
-use std::path::Path;
-
-pub fn agent_status() {
-    if Path::new(SOCK_FILE).exists() {
-        let status = req_status();
-        println!("running\n\n{status}");
-    } else {
-        println!("not_running");
-    }
+use super::{
+    RuntimeDir,
+    client::{self, io_failure},
+    output::{ExitCode, Outcome},
+};
+use serde_json::Value;
+
+pub fn agent_status(runtime_dir: &RuntimeDir, json: bool) -> i32 {
+    status_outcome(runtime_dir).print(json)
 }
 
-pub fn fetch_status() -> Result<()> {
-    match UnixStream::connect(SOCK_FILE) {
-        Ok(mut stream) => {
-            let _ = stream.write_all(b"GET / HTTP/1.1\r\n\r\n");
-            let mut buffer = String::new();
-            let _ = stream.read_to_string(&mut buffer);
-
-            if buffer.contains("200 OK") {
-                Ok(())
-            } else {
-                Err(anyhow!("Failed to fetch status"))
-            }
-        }
-        Err(e) => Err(anyhow!("error_sending_status_request: {e}")),
+fn status_outcome(runtime_dir: &RuntimeDir) -> Outcome {
+    let sock_file = runtime_dir.sock_file();
+
+    if !sock_file.exists() {
+        return not_running();
+    }
+
+    match client::request(&sock_file, "GET", "/status", &[], None) {
+        Ok(resp) if resp.code == 200 => running_outcome(resp.json()),
+        Ok(resp) => resp.failure("status").with("agent", resp.json()),
+        Err(e) => io_failure("status", &e).with("agent", Value::Null),
     }
 }
 
-pub fn req_status() -> String {
-    match UnixStream::connect(SOCK_FILE) {
-        Ok(mut stream) => {
-            let _ = stream.write_all(b"GET / HTTP/1.1\r\n\r\n");
-            let mut buffer = String::new();
-            let _ = stream.read_to_string(&mut buffer);
-            buffer
+fn not_running() -> Outcome {
+    Outcome::new(
+        "status",
+        "not_running",
+        ExitCode::NotRunning,
+        "The agent is not running.",
+    )
+    .with("agent", Value::Null)
+}
+
+fn running_outcome(body: Value) -> Outcome {
+    let text_body = serde_json::to_string_pretty(&body).unwrap_or_default();
+    let error = body["error"].as_str().unwrap_or_default();
+
+    // The agent's messages are already prefixed with the error code
+    let msg = body["msg"].as_str().unwrap_or("Unknown error.");
+    let msg = msg
+        .strip_prefix(&format!("{error}: "))
+        .unwrap_or(msg)
+        .to_string();
+
+    let outcome = if body["connected"] == true {
+        Outcome::new(
+            "status",
+            "running",
+            ExitCode::Ok,
+            "The agent is running and connected to Fostrom.",
+        )
+    } else {
+        match error {
+            "" => Outcome::new(
+                "status",
+                "running",
+                ExitCode::Ok,
+                "The agent is running and connecting to Fostrom.",
+            ),
+            "unauthorized" => Outcome::new("status", "unauthorized", ExitCode::Unauthorized, msg),
+            _ => Outcome::new("status", "connect_failed", ExitCode::ConnectFailed, msg),
         }
-        Err(e) => format!("error_sending_status_request: {e}"),
+    };
+
+    outcome.with("agent", body).with_text_body(text_body)
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_status_outcome() {
+        let body = json!({"connected": true});
+        assert_eq!(
+            running_outcome(body).to_json()["exit_code"],
+            ExitCode::Ok as i32
+        );
+
+        let body = json!({"connected": false});
+        assert_eq!(
+            running_outcome(body).to_json()["exit_code"],
+            ExitCode::Ok as i32
+        );
+
+        let body =
+            json!({"connected": false, "error": "unauthorized", "msg": "unauthorized: nope"});
+        let outcome = running_outcome(body);
+        assert_eq!(
+            outcome.to_json()["exit_code"],
+            ExitCode::Unauthorized as i32
+        );
+        assert_eq!(outcome.to_json()["message"], "nope");
+
+        let body = json!({"connected": false, "error": "connect_failed", "msg": "down"});
+        assert_eq!(
+            running_outcome(body).to_json()["exit_code"],
+            ExitCode::ConnectFailed as i32
+        );
     }
 }
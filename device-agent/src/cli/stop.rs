@@ -2,13 +2,17 @@
 // --- CLI STOP HANDLER ---
 // ------------------------
 
-use super::{HASH_FILE, PID_FILE, SOCK_FILE};
+use super::{
+    Fingerprint, RuntimeDir,
+    lock::{agent_pid, agent_running, remove_stale_files},
+    output::{ExitCode, Outcome},
+};
+use crate::moonlight_codec::DEFAULT_DRAIN_TIMEOUT;
 use nix::sys::signal::{Signal, kill};
 use nix::unistd::Pid;
-use std::fs::{read_to_string, remove_file};
+use std::fs::read_to_string;
 use std::io::{Read, Write};
 use std::os::unix::net::UnixStream;
-use std::path::Path;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
@@ -19,60 +23,101 @@ pub enum StopMode {
     Failed,
 }
 
-pub fn stop_agent() {
-    match terminate_agent() {
-        StopMode::NotRunning => println!("stopped: The agent was not running."),
-        StopMode::Stopped => println!("stopped: The agent has been stopped."),
-        StopMode::ForceKilled => println!("stopped: The agent has been stopped by forced kill."),
-        StopMode::Failed => eprintln!(
-            "timeout: The agent did not stop within the expected time and a PID file wasn't found to force kill the agent or the force kill failed."
+pub fn stop_agent(runtime_dir: &RuntimeDir, json: bool) -> i32 {
+    let outcome = match terminate_agent(runtime_dir) {
+        StopMode::NotRunning => Outcome::new(
+            "stop",
+            "not_running",
+            ExitCode::NotRunning,
+            "The agent was not running.",
         ),
-    }
+        StopMode::Stopped => Outcome::new(
+            "stop",
+            "stopped",
+            ExitCode::Ok,
+            "The agent has been stopped.",
+        )
+        .with("force_killed", false),
+        StopMode::ForceKilled => Outcome::new(
+            "stop",
+            "stopped",
+            ExitCode::Ok,
+            "The agent has been stopped by forced kill.",
+        )
+        .with("force_killed", true),
+        StopMode::Failed => Outcome::new(
+            "stop",
+            "timeout",
+            ExitCode::Timeout,
+            "The agent did not stop within the expected time, and its PID could not be confirmed to force kill it, or the force kill failed.",
+        ),
+    };
+
+    outcome.print(json)
 }
 
-pub fn terminate_agent() -> StopMode {
-    if Path::new(SOCK_FILE).exists() {
-        match UnixStream::connect(SOCK_FILE) {
-            Ok(mut stream) => {
-                let _ = stream.write_all(b"DELETE /stop-agent HTTP/1.1\r\n\r\n");
-                let mut buffer = String::new();
-                let _ = stream.read_to_string(&mut buffer);
+pub fn terminate_agent(runtime_dir: &RuntimeDir) -> StopMode {
+    let running = agent_running(runtime_dir);
 
-                if buffer.contains("200 OK") {
-                    wait_for_cleanup()
-                } else {
-                    force_kill_agent()
-                }
+    match UnixStream::connect(runtime_dir.sock_file()) {
+        Ok(mut stream) => {
+            let _ = stream.write_all(b"DELETE /stop-agent HTTP/1.1\r\n\r\n");
+            let mut buffer = String::new();
+            let _ = stream.read_to_string(&mut buffer);
+
+            if buffer.contains("200 OK") {
+                wait_for_cleanup(runtime_dir)
+            } else {
+                force_kill_agent(runtime_dir)
             }
-            Err(_) => force_kill_agent(),
         }
-    } else {
-        StopMode::NotRunning
+        Err(_) if running => force_kill_agent(runtime_dir),
+        Err(_) => {
+            remove_stale_files(runtime_dir);
+            StopMode::NotRunning
+        }
     }
 }
 
-fn wait_for_cleanup() -> StopMode {
+/// Waits for the agent to exit, which releases its lock
+fn wait_for_cleanup(runtime_dir: &RuntimeDir) -> StopMode {
+    // The agent first drains the requests awaiting a response
+    let timeout = drain_timeout(runtime_dir) + Duration::from_secs(5);
+
     let wait_start = Instant::now();
-    while Path::new(SOCK_FILE).exists() {
+    while agent_running(runtime_dir) {
         sleep(Duration::from_millis(25));
-        if wait_start.elapsed() > Duration::from_secs(5) {
-            return force_kill_agent();
+        if wait_start.elapsed() > timeout {
+            return force_kill_agent(runtime_dir);
         }
     }
     StopMode::Stopped
 }
 
-fn force_kill_agent() -> StopMode {
-    if Path::new(PID_FILE).exists()
-        && let Ok(contents) = read_to_string(PID_FILE)
-        && let trimmed = contents.trim()
-        && let Ok(raw_pid) = trimmed.parse::<i32>()
-        && let pid = Pid::from_raw(raw_pid)
-        && let Ok(_) = kill(pid, Some(Signal::SIGKILL))
+/// The running agent's drain timeout, from its hash file
+fn drain_timeout(runtime_dir: &RuntimeDir) -> Duration {
+    read_to_string(runtime_dir.hash_file())
+        .ok()
+        .and_then(|contents| {
+            let fingerprint = Fingerprint::parse(&contents);
+            fingerprint.get("drain_timeout")?.parse().ok()
+        })
+        .map_or(DEFAULT_DRAIN_TIMEOUT, Duration::from_secs)
+}
+
+/// Sends SIGKILL to the agent, only if the PID in the PID file
+/// is confirmed to be the running agent.
+fn force_kill_agent(runtime_dir: &RuntimeDir) -> StopMode {
+    if let Some(pid) = agent_pid(runtime_dir)
+        && let Ok(_) = kill(Pid::from_raw(pid as i32), Some(Signal::SIGKILL))
     {
-        let _ = remove_file(SOCK_FILE);
-        let _ = remove_file(PID_FILE);
-        let _ = remove_file(HASH_FILE);
+        // The lock is released once the process has exited
+        let wait_start = Instant::now();
+        while agent_running(runtime_dir) && wait_start.elapsed() < Duration::from_secs(2) {
+            sleep(Duration::from_millis(25));
+        }
+
+        remove_stale_files(runtime_dir);
         StopMode::ForceKilled
     } else {
         StopMode::Failed
@@ -0,0 +1,366 @@
+// ------------------
+// --- SUPERVISOR ---
+// ------------------
+
+// With `--supervise`, the agent's process becomes a supervisor. It holds the
+// agent lock and the PID file, and runs the agent in a worker process, which
+// it restarts with a backoff whenever it crashes. As a panic aborts the
+// worker, the supervisor passes the worker's stderr through while keeping
+// its last lines, and records each crash to `crash.json` in the runtime
+// directory. Once the restarted worker is connected, it sends the crash
+// as an `agent_crash` system pulse, and deletes the record.
+//
+// The worker's stdin is a pipe from the supervisor, so that the worker
+// exits along with the supervisor, even if the supervisor is killed.
+
+use super::{
+    AgentConfig, RuntimeDir,
+    daemon::READINESS_TIMEOUT,
+    lock::{AgentLock, PidFileGuard, StartLock},
+    output::ExitCode,
+    readiness::{Readiness, ReadyPipe},
+    reload::block_sighup,
+};
+use crate::{
+    log::{self, Level},
+    moonlight_codec::{ClientCmd, CmdOpts, MoonlightClient, PulseType, ReturnChanResult},
+    notifycast::NotifyCast,
+    systemd,
+};
+use anyhow::Result;
+use nix::{
+    sys::signal::{Signal, kill},
+    unistd::Pid,
+};
+use serde_json::{Value, json};
+use std::{
+    collections::VecDeque,
+    env::current_exe,
+    fs::{read_to_string, remove_file, write},
+    io::{self, BufRead, BufReader, Read},
+    process::{self, Child, ChildStdin, Command, ExitStatus, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+    },
+    thread::{JoinHandle, spawn},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The system pulse that reports a crash of the worker
+const CRASH_PULSE: &str = "agent_crash";
+
+/// How many lines of the worker's stderr are kept for the crash record
+const STDERR_LINES: usize = 20;
+
+/// The wait before each restart, in seconds, by the number of crashes in a row
+const RESTART_BACKOFF: [u64; 5] = [1, 2, 5, 10, 30];
+
+/// A worker that stays up for this long resets the backoff
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+/// Runs the agent in a worker process, restarting it until the agent is
+/// stopped. The first worker's readiness is reported as the agent's.
+pub fn supervise(
+    config: AgentConfig,
+    start_lock: Option<StartLock>,
+    readiness: &mut Readiness,
+) -> Result<()> {
+    // SIGHUP is passed on to the worker, which reloads its configuration
+    let sighup = block_sighup()?;
+
+    let _agent_lock = AgentLock::acquire(&config.runtime_dir)?;
+    let _pid_guard = PidFileGuard::create(&config.runtime_dir)?;
+
+    log::set_level(config.log_level);
+
+    let worker_pid = Arc::new(Mutex::new(None));
+    let stopping = Arc::new(AtomicBool::new(false));
+    let (stop_tx, stop_rx) = channel();
+
+    let pid = worker_pid.clone();
+    let stop = stopping.clone();
+    ctrlc::set_handler(move || {
+        stop.store(true, Ordering::SeqCst);
+        let _ = stop_tx.send(());
+        signal_worker(&pid, Signal::SIGTERM);
+    })?;
+
+    let pid = worker_pid.clone();
+    spawn(move || {
+        while let Ok(Signal::SIGHUP) = sighup.wait() {
+            signal_worker(&pid, Signal::SIGHUP);
+        }
+    });
+
+    let flags = config.flags();
+    let mut start_lock = start_lock;
+    let mut first = true;
+    let mut crashes = 0;
+
+    loop {
+        let ready_pipe = ReadyPipe::new()?;
+        let mut worker = Worker::spawn(&flags, &ready_pipe)?;
+        let started = Instant::now();
+        *worker_pid.lock().unwrap() = Some(worker.pid());
+
+        // Stopped while the worker was being spawned
+        if stopping.load(Ordering::SeqCst) {
+            signal_worker(&worker_pid, Signal::SIGTERM);
+        }
+
+        let ready = ready_pipe.wait(&mut worker.child, READINESS_TIMEOUT);
+
+        if first {
+            first = false;
+
+            if let Err(e) = ready {
+                worker.wait();
+                return Err(e);
+            }
+
+            readiness.ready();
+            systemd::ready();
+            drop(start_lock.take());
+        }
+
+        let pid = worker.pid();
+        let (status, stderr) = worker.wait();
+        *worker_pid.lock().unwrap() = None;
+
+        if status.success() || stopping.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        if started.elapsed() > STABLE_UPTIME {
+            crashes = 0;
+        }
+
+        let backoff = RESTART_BACKOFF[crashes.min(RESTART_BACKOFF.len() - 1)];
+        crashes += 1;
+
+        record_crash(&config.runtime_dir, pid, status, stderr);
+
+        if log::enabled(Level::Error) {
+            eprintln!(
+                "supervisor: worker crashed pid={pid} status=\"{status}\" restarting_in_s={backoff}"
+            );
+        }
+
+        systemd::status(&format!(
+            "The agent crashed ({status}), restarting in {backoff}s"
+        ));
+
+        // Stopped while waiting to restart
+        if stop_rx.recv_timeout(Duration::from_secs(backoff)).is_ok() {
+            return Ok(());
+        }
+    }
+}
+
+fn signal_worker(worker_pid: &Mutex<Option<Pid>>, signal: Signal) {
+    if let Some(pid) = *worker_pid.lock().unwrap() {
+        let _ = kill(pid, signal);
+    }
+}
+
+struct Worker {
+    child: Child,
+    /// Closed when the supervisor exits
+    _stdin: Option<ChildStdin>,
+    /// The last lines of the worker's stderr, once it has exited
+    stderr: Option<JoinHandle<VecDeque<String>>>,
+}
+
+impl Worker {
+    fn spawn(flags: &[String], ready_pipe: &ReadyPipe) -> Result<Self> {
+        let mut child = Command::new(current_exe()?)
+            .arg("worker")
+            .args(flags)
+            .arg(ready_pipe.flag())
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take();
+        let stderr = child.stderr.take().map(|stderr| spawn(|| tail(stderr)));
+
+        Ok(Self {
+            child,
+            _stdin: stdin,
+            stderr,
+        })
+    }
+
+    fn pid(&self) -> Pid {
+        Pid::from_raw(self.child.id() as i32)
+    }
+
+    fn wait(mut self) -> (ExitStatus, VecDeque<String>) {
+        let status = self.child.wait().unwrap_or_default();
+
+        let stderr = self
+            .stderr
+            .take()
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default();
+
+        (status, stderr)
+    }
+}
+
+/// Passes the worker's stderr through, and returns its last lines
+fn tail(stderr: impl Read) -> VecDeque<String> {
+    let mut lines = VecDeque::with_capacity(STDERR_LINES);
+    let mut reader = BufReader::new(stderr);
+    let mut buf = Vec::new();
+
+    while let Ok(n) = reader.read_until(b'\n', &mut buf)
+        && n > 0
+    {
+        let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+        eprintln!("{line}");
+
+        if lines.len() == STDERR_LINES {
+            lines.pop_front();
+        }
+
+        lines.push_back(line);
+        buf.clear();
+    }
+
+    lines
+}
+
+// --------------------
+// --- CRASH RECORD ---
+// --------------------
+
+/// Writes the crash record, counting the crashes that weren't reported yet
+fn record_crash(runtime_dir: &RuntimeDir, pid: Pid, status: ExitStatus, stderr: VecDeque<String>) {
+    let crashes = read_crash(runtime_dir)
+        .and_then(|crash| crash["crashes"].as_u64())
+        .unwrap_or(0)
+        + 1;
+
+    let crashed_at_ms = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+
+    let crash = json!({
+        "pid": pid.as_raw(),
+        "status": status.to_string(),
+        "reason": crash_reason(status, &stderr),
+        "crashed_at_ms": crashed_at_ms,
+        "crashes": crashes,
+        "stderr": stderr,
+    });
+
+    let _ = write(runtime_dir.crash_file(), crash.to_string());
+}
+
+/// The panic message, if the worker panicked, or else the exit status
+fn crash_reason(status: ExitStatus, stderr: &VecDeque<String>) -> String {
+    // The location is followed by the message on the next line
+    match stderr.iter().rposition(|line| line.contains("panicked at")) {
+        Some(i) => stderr
+            .iter()
+            .skip(i)
+            .take(2)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => status.to_string(),
+    }
+}
+
+fn read_crash(runtime_dir: &RuntimeDir) -> Option<Value> {
+    let contents = read_to_string(runtime_dir.crash_file()).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+// --------------
+// --- WORKER ---
+// --------------
+
+/// Exits the worker when the supervisor's end of stdin is closed
+pub fn exit_with_supervisor() {
+    spawn(|| {
+        let _ = io::copy(&mut io::stdin(), &mut io::sink());
+        process::exit(ExitCode::Failed as i32);
+    });
+}
+
+/// Sends the crash recorded by the supervisor as a system pulse once the
+/// worker is connected, and then deletes the record. If the pulse fails,
+/// it's sent again after the next reconnect.
+pub fn report_crash(runtime_dir: &RuntimeDir, client: MoonlightClient, notify: &NotifyCast) {
+    if !runtime_dir.crash_file().exists() {
+        return;
+    }
+
+    let (token, events) = notify.subscribe();
+    let runtime_dir = runtime_dir.clone();
+    let notify = notify.clone();
+
+    spawn(move || {
+        for (event, _) in events {
+            if event != "connected" {
+                continue;
+            }
+
+            let Some(crash) = read_crash(&runtime_dir) else {
+                break;
+            };
+
+            let opts = CmdOpts::default();
+            let timeout = opts.timeout;
+            let (result_tx, result_rx) = channel();
+            let cmd = ClientCmd::SendPulse(
+                None,
+                PulseType::System,
+                CRASH_PULSE.to_string(),
+                Some(crash),
+                result_tx,
+            );
+
+            client.send_cmd(cmd, opts);
+
+            if let Ok(ReturnChanResult::Ok) = result_rx.recv_timeout(timeout) {
+                let _ = remove_file(runtime_dir.crash_file());
+                break;
+            }
+        }
+
+        notify.unsubscribe(token);
+    });
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_crash_reason() {
+        let status = ExitStatus::from_raw(6);
+        let stderr = VecDeque::from([
+            "session: ended reason=disconnect: Socket Terminated".to_string(),
+            "thread 'main' panicked at src/moonlight_codec.rs:838:13:".to_string(),
+            "Mail name cannot be more than 255 characters".to_string(),
+            "note: run with `RUST_BACKTRACE=1` to display a backtrace".to_string(),
+        ]);
+
+        assert_eq!(
+            crash_reason(status, &stderr),
+            "thread 'main' panicked at src/moonlight_codec.rs:838:13: Mail name cannot be more than 255 characters"
+        );
+
+        assert_eq!(crash_reason(status, &VecDeque::new()), status.to_string());
+    }
+}
@@ -1,56 +1,224 @@
+use super::output::{ExitCode, Outcome};
 use crate::{
-    moonlight_codec::{Codec, MoonlightPacket},
-    moonlight_socket,
+    moonlight_codec::{
+        Capabilities, ClientLogic, Codec, CodecLimits, ConnectMode, Creds, DisconnectedReason,
+        MoonlightPacket, PROTOCOL_VERSION, ServerResp,
+    },
+    moonlight_socket::{self, Stream, TlsStream},
 };
 use anyhow::{Context, Result, anyhow};
-use rustls::{ClientConnection, StreamOwned};
+use either::Either;
 use sha2::{Digest, Sha256};
 use std::{
+    fmt,
     io::{ErrorKind, Read, Write},
-    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    net::{SocketAddr, ToSocketAddrs},
     time::{Duration, Instant},
 };
 
-const PROD_HOST: &str = "device.fostrom.dev";
-const PROD_PORT: u16 = 8484;
+pub const PROD_HOST: &str = "device.fostrom.dev";
+pub const PROD_PORT: u16 = 8484;
 
 const TOTAL_WAIT_FOR_SERVER_CLOSE: Duration = Duration::from_secs(5);
+const TOTAL_WAIT_FOR_RESPONSE: Duration = Duration::from_secs(5);
 const READ_TIMEOUT: Duration = Duration::from_millis(250);
 const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub fn run() -> i32 {
+/// Where the connection is tested: a local server over plain TCP,
+/// or production or a custom endpoint over TLS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(u16),
+    Tls(String, u16),
+}
+
+impl Endpoint {
+    fn host(&self) -> &str {
+        match self {
+            Self::Tcp(_) => "127.0.0.1",
+            Self::Tls(host, _) => host,
+        }
+    }
+
+    fn port(&self) -> u16 {
+        match self {
+            Self::Tcp(port) | Self::Tls(_, port) => *port,
+        }
+    }
+
+    fn transport(&self) -> &'static str {
+        match self {
+            Self::Tcp(_) => "tcp",
+            Self::Tls(..) => "tls",
+        }
+    }
+}
+
+impl From<&ConnectMode> for Endpoint {
+    fn from(connect_mode: &ConnectMode) -> Self {
+        match connect_mode {
+            ConnectMode::Local(port) => Self::Tcp(*port),
+            ConnectMode::Prod => Self::Tls(PROD_HOST.to_string(), PROD_PORT),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host(), self.port())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestConnConfig {
+    pub endpoint: Endpoint,
+    /// With `--auth`, the device connects with these credentials
+    /// and sends a heartbeat before closing the connection.
+    pub creds: Option<Creds>,
+}
+
+/// Collects the report lines, printing them as they come in text mode
+struct Report {
+    json: bool,
+    lines: Vec<String>,
+    /// The stage being tested, reported when it fails
+    stage: &'static str,
+    heartbeat_rtt_ms: Option<u64>,
+    /// The protocol version of the CONNECT packet the server accepted
+    protocol_version: Option<u8>,
+}
+
+impl Report {
+    fn line(&mut self, line: impl Into<String>) {
+        let line = line.into();
+        if !self.json {
+            println!("{line}");
+        }
+        self.lines.push(line);
+    }
+}
+
+pub fn run(config: &TestConnConfig, json: bool) -> i32 {
     let total_start = Instant::now();
-    let result = run_inner();
+    let mut report = Report {
+        json,
+        lines: Vec::new(),
+        stage: "dns",
+        heartbeat_rtt_ms: None,
+        protocol_version: None,
+    };
+
+    let result = run_inner(config, &mut report);
 
     let (status, exit_code) = match &result {
-        Ok(()) => ("OK", 0),
-        Err(_) => ("FAILED", 1),
+        Ok(()) => ("OK", ExitCode::Ok),
+        Err(e) => ("FAILED", failure_code(e)),
     };
 
-    if let Err(e) = result {
-        println!("failed: test-conn");
-        print_error_chain(&e);
+    if let Err(e) = &result {
+        report.line(format!("failed: test-conn stage={}", report.stage));
+        print_error_chain(e, &mut report);
     }
 
-    println!(
-        "summary: status={status} exit_code={exit_code} total_elapsed_ms={}",
-        total_start.elapsed().as_millis()
-    );
-    exit_code
+    let elapsed_ms = total_start.elapsed().as_millis() as u64;
+    report.line(format!(
+        "summary: status={status} exit_code={} total_elapsed_ms={elapsed_ms}",
+        exit_code as i32
+    ));
+
+    if !json {
+        return exit_code as i32;
+    }
+
+    let outcome = match &result {
+        Ok(()) if config.creds.is_some() => Outcome::new(
+            "test-conn",
+            "ok",
+            exit_code,
+            "Successfully connected and authenticated with Fostrom.",
+        ),
+        Ok(()) => Outcome::new(
+            "test-conn",
+            "ok",
+            exit_code,
+            "Successfully connected to Fostrom.",
+        ),
+        Err(e) if exit_code == ExitCode::Timeout => {
+            Outcome::new("test-conn", "timeout", exit_code, format!("{e:#}"))
+        }
+        Err(e) if exit_code == ExitCode::Unauthorized => {
+            Outcome::new("test-conn", "unauthorized", exit_code, format!("{e:#}"))
+        }
+        Err(e) => Outcome::new("test-conn", "connect_failed", exit_code, format!("{e:#}")),
+    };
+
+    let (errors, stage) = match &result {
+        Ok(()) => (Vec::new(), None),
+        Err(e) => (
+            e.chain().map(|cause| cause.to_string()).collect(),
+            Some(report.stage),
+        ),
+    };
+
+    outcome
+        .with("target", config.endpoint.to_string())
+        .with("transport", config.endpoint.transport())
+        .with("auth", config.creds.is_some())
+        .with("stage", stage)
+        .with("heartbeat_rtt_ms", report.heartbeat_rtt_ms)
+        .with("protocol_version", report.protocol_version)
+        .with("elapsed_ms", elapsed_ms)
+        .with("errors", errors)
+        .with("log", report.lines)
+        .print(json)
 }
 
-fn run_inner() -> Result<()> {
-    println!("test-conn: target={PROD_HOST}:{PROD_PORT}");
-    println!(
+/// Whether the connection test was refused by the server,
+/// failed by timing out, or otherwise
+fn failure_code(err: &anyhow::Error) -> ExitCode {
+    let refused = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<DisconnectedReason>());
+
+    if let Some(DisconnectedReason::Unauthorized(_)) = refused {
+        return ExitCode::Unauthorized;
+    }
+
+    let timed_out = err.chain().any(|cause| {
+        let msg = cause.to_string();
+        msg.starts_with("tls_handshake_timeout")
+            || msg.starts_with("timeout_waiting_for_")
+            || cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == ErrorKind::TimedOut)
+    });
+
+    if timed_out {
+        ExitCode::Timeout
+    } else {
+        ExitCode::ConnectFailed
+    }
+}
+
+fn run_inner(config: &TestConnConfig, report: &mut Report) -> Result<()> {
+    let endpoint = &config.endpoint;
+
+    report.line(format!(
+        "test-conn: target={endpoint} transport={} auth={}",
+        endpoint.transport(),
+        config.creds.is_some()
+    ));
+    report.line(format!(
         "env: version=v{} os={} arch={}",
         env!("CARGO_PKG_VERSION"),
         std::env::consts::OS,
         std::env::consts::ARCH
-    );
+    ));
 
+    report.stage = "dns";
     let dns_start = Instant::now();
-    let addrs = resolve_prod_addrs().context("dns_lookup_failed")?;
-    println!(
+    let addrs = resolve_addrs(endpoint).context("dns_lookup_failed")?;
+    report.line(format!(
         "dns: ok elapsed_ms={} addrs={}",
         dns_start.elapsed().as_millis(),
         addrs
@@ -58,35 +226,34 @@ fn run_inner() -> Result<()> {
             .map(|a| a.to_string())
             .collect::<Vec<_>>()
             .join(", ")
-    );
-
-    let start = Instant::now();
-    let mut stream = moonlight_socket::tls_open(PROD_PORT).context("tls_open_failed")?;
-    let open_elapsed = start.elapsed();
-
-    stream
-        .sock
-        .set_read_timeout(Some(READ_TIMEOUT))
-        .context("set_read_timeout_failed")?;
-
-    println!("tcp: connect_ms={}", open_elapsed.as_millis());
-    println!("tcp: read_timeout_ms={}", READ_TIMEOUT.as_millis());
+    ));
+
+    let mut stream = open_stream(endpoint, report)?;
+    let mut codec = Codec::new(CodecLimits::default());
+
+    if let Some(creds) = &config.creds {
+        report.stage = "auth";
+        let auth = match authenticate(&mut stream, &mut codec, creds, PROTOCOL_VERSION, report) {
+            // Retried with the legacy CONNECT, as the agent does,
+            // over a new connection since the server has closed this one
+            Err(e) if connect_rejected(&e) => {
+                report.line(format!(
+                    "auth: connect rejected, retrying protocol_version=1 error={e}"
+                ));
+                stream = open_stream(endpoint, report)?;
+                codec = Codec::new(CodecLimits::default());
+                report.stage = "auth";
+                authenticate(&mut stream, &mut codec, creds, 1, report)
+            }
+            auth => auth,
+        };
+        auth.context("auth_failed")?;
 
-    if let Ok(local) = stream.sock.local_addr() {
-        println!("tcp: local_addr={local}");
+        report.stage = "heartbeat";
+        heartbeat(&mut stream, &mut codec, report).context("heartbeat_failed")?;
     }
-    if let Ok(peer) = stream.sock.peer_addr() {
-        println!("tcp: peer_addr={peer}");
-    }
-
-    let hs_start = Instant::now();
-    force_tls_handshake(&mut stream, TLS_HANDSHAKE_TIMEOUT).context("tls_handshake_failed")?;
-    println!(
-        "tls: handshake_ok elapsed_ms={}",
-        hs_start.elapsed().as_millis()
-    );
-    print_tls_details(&stream);
 
+    report.stage = "close";
     let close_bytes = Codec::encode(&MoonlightPacket::client_close_connection())
         .context("encode_close_connection_failed")?;
 
@@ -94,41 +261,252 @@ fn run_inner() -> Result<()> {
     stream
         .write_all(&close_bytes)
         .context("write_close_connection_failed")?;
-    println!(
+    report.line(format!(
         "moonlight: sent_close ok bytes={} elapsed_ms={}",
         close_bytes.len(),
         write_start.elapsed().as_millis()
-    );
+    ));
 
     let wait_start = Instant::now();
-    println!(
+    report.line(format!(
         "moonlight: waiting_close_ack timeout_ms={}",
         TOTAL_WAIT_FOR_SERVER_CLOSE.as_millis()
-    );
-    wait_for_server_close(&mut stream, TOTAL_WAIT_FOR_SERVER_CLOSE)
-        .context("wait_for_server_close_failed")?;
+    ));
+
+    // Once authenticated, the server may send other packets before the close
+    if config.creds.is_some() {
+        wait_for_packet(
+            &mut stream,
+            &mut codec,
+            TOTAL_WAIT_FOR_SERVER_CLOSE,
+            "close_ack",
+            report,
+            |resp| *resp == ServerResp::Disconnected(DisconnectedReason::NormalDisconnect),
+        )
+        .map(|_| ())
+    } else {
+        wait_for_server_close(&mut stream, TOTAL_WAIT_FOR_SERVER_CLOSE, report)
+    }
+    .context("wait_for_server_close_failed")?;
 
-    println!(
+    report.line(format!(
         "moonlight: recv_close_ack ok waited_ms={}",
         wait_start.elapsed().as_millis()
-    );
+    ));
     Ok(())
 }
 
-fn resolve_prod_addrs() -> Result<Vec<SocketAddr>> {
-    let mut addrs = (PROD_HOST, PROD_PORT)
+/// Opens the TCP connection to the endpoint, and completes the TLS handshake
+fn open_stream(endpoint: &Endpoint, report: &mut Report) -> Result<Stream> {
+    report.stage = "tcp";
+    let start = Instant::now();
+    let mut stream: Stream = match endpoint {
+        Endpoint::Tcp(port) => {
+            Either::Left(moonlight_socket::tcp_open(*port).context("tcp_open_failed")?)
+        }
+        Endpoint::Tls(host, port) => {
+            Either::Right(moonlight_socket::tls_open_host(host, *port).context("tls_open_failed")?)
+        }
+    };
+    let open_elapsed = start.elapsed();
+
+    let sock = match &stream {
+        Either::Left(stream) => stream,
+        Either::Right(stream) => &stream.sock,
+    };
+
+    sock.set_read_timeout(Some(READ_TIMEOUT))
+        .context("set_read_timeout_failed")?;
+
+    report.line(format!("tcp: connect_ms={}", open_elapsed.as_millis()));
+    report.line(format!("tcp: read_timeout_ms={}", READ_TIMEOUT.as_millis()));
+
+    if let Ok(local) = sock.local_addr() {
+        report.line(format!("tcp: local_addr={local}"));
+    }
+    if let Ok(peer) = sock.peer_addr() {
+        report.line(format!("tcp: peer_addr={peer}"));
+    }
+
+    if let Either::Right(stream) = &mut stream {
+        report.stage = "tls";
+        let hs_start = Instant::now();
+        force_tls_handshake(stream, TLS_HANDSHAKE_TIMEOUT).context("tls_handshake_failed")?;
+        report.line(format!(
+            "tls: handshake_ok elapsed_ms={}",
+            hs_start.elapsed().as_millis()
+        ));
+        print_tls_details(stream, report);
+    }
+
+    Ok(stream)
+}
+
+fn resolve_addrs(endpoint: &Endpoint) -> Result<Vec<SocketAddr>> {
+    let mut addrs = (endpoint.host(), endpoint.port())
         .to_socket_addrs()
-        .with_context(|| format!("failed to resolve {PROD_HOST}:{PROD_PORT}"))?
+        .with_context(|| format!("failed to resolve {endpoint}"))?
         .collect::<Vec<_>>();
     addrs.sort();
     addrs.dedup();
     Ok(addrs)
 }
 
-fn force_tls_handshake(
-    stream: &mut StreamOwned<ClientConnection, TcpStream>,
-    timeout: Duration,
+/// Connects with the device's credentials, failing with
+/// the server's reason if the connection is refused.
+/// Protocol version 1 sends the legacy CONNECT packet.
+fn authenticate<S: Read + Write>(
+    stream: &mut S,
+    codec: &mut Codec,
+    creds: &Creds,
+    protocol_version: u8,
+    report: &mut Report,
 ) -> Result<()> {
+    let (fleet_id, device_id, device_secret) = (
+        creds.fleet_id.clone(),
+        creds.device_id.clone(),
+        creds.device_secret.clone(),
+    );
+
+    let (connect, _) = match protocol_version {
+        1 => MoonlightPacket::connect(fleet_id, device_id, device_secret, creds.prod)?,
+        _ => MoonlightPacket::connect_with_capabilities(
+            fleet_id,
+            device_id,
+            device_secret,
+            creds.prod,
+            protocol_version,
+            Capabilities::SUPPORTED,
+        )?,
+    };
+    let connect_bytes = Codec::encode(&connect).context("encode_connect_failed")?;
+
+    let start = Instant::now();
+    stream
+        .write_all(&connect_bytes)
+        .context("write_connect_failed")?;
+    report.line(format!(
+        "moonlight: sent_connect ok protocol_version={protocol_version} fleet_id={} device_id={} bytes={}",
+        creds.fleet_id,
+        creds.device_id,
+        connect_bytes.len()
+    ));
+
+    let resp = wait_for_packet(
+        stream,
+        codec,
+        TOTAL_WAIT_FOR_RESPONSE,
+        "connected",
+        report,
+        |resp| matches!(resp, ServerResp::Connected(..)),
+    )?;
+
+    if let ServerResp::Connected(mail_available, negotiated) = resp {
+        report.protocol_version = Some(protocol_version);
+        report.line(format!(
+            "auth: ok elapsed_ms={} protocol_version={} capabilities={} mail_available={mail_available}",
+            start.elapsed().as_millis(),
+            negotiated.protocol_version,
+            negotiated.capabilities.bits()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether the server may have turned away the CONNECT packet for its protocol
+/// version, in which case the agent retries with the legacy one
+fn connect_rejected(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|cause| match cause.downcast_ref::<DisconnectedReason>() {
+            Some(reason) => ClientLogic::connect_rejected(*reason),
+            None => cause.to_string().starts_with("server_closed_connection"),
+        })
+}
+
+/// Sends a heartbeat, and measures the time until it is acknowledged
+fn heartbeat<S: Read + Write>(
+    stream: &mut S,
+    codec: &mut Codec,
+    report: &mut Report,
+) -> Result<()> {
+    let heartbeat_bytes =
+        Codec::encode(&MoonlightPacket::heartbeat()).context("encode_heartbeat_failed")?;
+
+    let start = Instant::now();
+    stream
+        .write_all(&heartbeat_bytes)
+        .context("write_heartbeat_failed")?;
+
+    wait_for_packet(
+        stream,
+        codec,
+        TOTAL_WAIT_FOR_RESPONSE,
+        "heartbeat_ack",
+        report,
+        |resp| *resp == ServerResp::HeartbeatAck,
+    )?;
+
+    let rtt_ms = start.elapsed().as_millis() as u64;
+    report.heartbeat_rtt_ms = Some(rtt_ms);
+    report.line(format!("heartbeat: ok rtt_ms={rtt_ms}"));
+    Ok(())
+}
+
+/// Reads packets until the expected one arrives, skipping others such as
+/// new mail notifications. If the server disconnects instead, its reason
+/// is returned as the error.
+fn wait_for_packet<R: Read>(
+    reader: &mut R,
+    codec: &mut Codec,
+    total_timeout: Duration,
+    expected: &str,
+    report: &mut Report,
+    is_expected: impl Fn(&ServerResp) -> bool,
+) -> Result<ServerResp> {
+    let start = Instant::now();
+
+    while start.elapsed() < total_timeout {
+        let mut buf = [0u8; 8192];
+        match reader.read(&mut buf) {
+            Ok(0) => {
+                return Err(anyhow!("server_closed_connection: waiting_for={expected}"));
+            }
+            Ok(n) => {
+                report.line(format!("moonlight: rx bytes={n}"));
+                codec.feed(&buf[..n])?;
+
+                for frame in codec.process_packets()? {
+                    let resp = ServerResp::handle_packet(frame);
+
+                    if is_expected(&resp) {
+                        return Ok(resp);
+                    }
+
+                    match resp {
+                        ServerResp::Disconnected(reason) => return Err(anyhow!(reason)),
+                        ServerResp::ForceCloseSocket => {
+                            return Err(anyhow!("unexpected_packet: waiting_for={expected}"));
+                        }
+                        resp => report.line(format!("moonlight: skipped packet={resp:?}")),
+                    }
+                }
+            }
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => continue,
+                ErrorKind::Interrupted => continue,
+                _ => return Err(e).context("read_failed"),
+            },
+        }
+    }
+
+    Err(anyhow!(
+        "timeout_waiting_for_{expected}: timeout_ms={}",
+        total_timeout.as_millis()
+    ))
+}
+
+fn force_tls_handshake(stream: &mut TlsStream, timeout: Duration) -> Result<()> {
     let start = Instant::now();
     while stream.conn.is_handshaking() {
         if start.elapsed() > timeout {
@@ -146,7 +524,11 @@ fn force_tls_handshake(
     Ok(())
 }
 
-fn wait_for_server_close<R: Read>(reader: &mut R, total_timeout: Duration) -> Result<()> {
+fn wait_for_server_close<R: Read>(
+    reader: &mut R,
+    total_timeout: Duration,
+    report: &mut Report,
+) -> Result<()> {
     const EXPECTED_CLOSE_ACK_BYTES: [u8; 2] = [1, 1];
 
     let start = Instant::now();
@@ -166,7 +548,9 @@ fn wait_for_server_close<R: Read>(reader: &mut R, total_timeout: Duration) -> Re
             Ok(n) => {
                 total_reads += 1;
                 total_read += n;
-                println!("moonlight: rx bytes={n} total_bytes={total_read} reads={total_reads}");
+                report.line(format!(
+                    "moonlight: rx bytes={n} total_bytes={total_read} reads={total_reads}"
+                ));
 
                 received.extend_from_slice(&buf[..n]);
 
@@ -202,50 +586,50 @@ fn wait_for_server_close<R: Read>(reader: &mut R, total_timeout: Duration) -> Re
     ))
 }
 
-fn print_tls_details(stream: &StreamOwned<ClientConnection, TcpStream>) {
+fn print_tls_details(stream: &TlsStream, report: &mut Report) {
     if let Some(v) = stream.conn.protocol_version() {
-        println!("tls: protocol={v:?}");
+        report.line(format!("tls: protocol={v:?}"));
     } else {
-        println!("tls: protocol=unknown");
+        report.line("tls: protocol=unknown");
     }
 
     if let Some(cs) = stream.conn.negotiated_cipher_suite() {
-        println!("tls: cipher_suite={:?}", cs.suite());
+        report.line(format!("tls: cipher_suite={:?}", cs.suite()));
     } else {
-        println!("tls: cipher_suite=unknown");
+        report.line("tls: cipher_suite=unknown");
     }
 
     if let Some(alpn) = stream.conn.alpn_protocol() {
-        println!("tls: alpn={}", String::from_utf8_lossy(alpn));
+        report.line(format!("tls: alpn={}", String::from_utf8_lossy(alpn)));
     } else {
-        println!("tls: alpn=none");
+        report.line("tls: alpn=none");
     }
 
     match stream.conn.peer_certificates() {
-        None => println!("tls: peer_certs=none"),
+        None => report.line("tls: peer_certs=none"),
         Some(certs) => {
-            println!("tls: peer_certs_count={}", certs.len());
+            report.line(format!("tls: peer_certs_count={}", certs.len()));
             for (i, cert) in certs.iter().enumerate() {
                 let fp = Sha256::digest(cert.as_ref());
-                println!("tls: peer_cert_sha256[{i}]={}", hex(fp.as_slice()));
+                report.line(format!("tls: peer_cert_sha256[{i}]={}", hex(fp.as_slice())));
             }
         }
     }
 }
 
-fn print_error_chain(err: &anyhow::Error) {
+fn print_error_chain(err: &anyhow::Error, report: &mut Report) {
     for (i, cause) in err.chain().enumerate() {
-        println!("error[{i}]: {cause}");
+        report.line(format!("error[{i}]: {cause}"));
 
         if let Some(ioe) = cause.downcast_ref::<std::io::Error>() {
-            println!(
+            report.line(format!(
                 "error[{i}]_io: kind={:?} raw_os_error={:?}",
                 ioe.kind(),
                 ioe.raw_os_error()
-            );
+            ));
         }
 
-        println!("error[{i}]_debug: {cause:?}");
+        report.line(format!("error[{i}]_debug: {cause:?}"));
     }
 }
 
@@ -267,8 +651,166 @@ fn hex(bytes: &[u8]) -> String {
 mod tests {
     use super::*;
 
+    use crate::moonlight_codec::{ConnectFailedError, UnauthorizedError};
+    use std::io::Cursor;
+
     #[test]
     fn test_hex() {
         assert_eq!(hex(&[0x00, 0x0f, 0x10, 0xff]), "000f10ff");
     }
+
+    fn report() -> Report {
+        Report {
+            json: true,
+            lines: Vec::new(),
+            stage: "auth",
+            heartbeat_rtt_ms: None,
+            protocol_version: None,
+        }
+    }
+
+    fn wait_for(packets: &[MoonlightPacket], expected: ServerResp) -> Result<ServerResp> {
+        let bytes: Vec<u8> = packets
+            .iter()
+            .flat_map(|p| Codec::encode(p).unwrap())
+            .collect();
+
+        let mut codec = Codec::new(CodecLimits::default());
+        wait_for_packet(
+            &mut Cursor::new(bytes),
+            &mut codec,
+            TOTAL_WAIT_FOR_RESPONSE,
+            "test",
+            &mut report(),
+            |resp| *resp == expected,
+        )
+    }
+
+    #[test]
+    fn test_wait_for_packet() {
+        // Other packets are skipped
+        let packets = [
+            MoonlightPacket::new_mail_event(),
+            MoonlightPacket::heartbeat_ack(true),
+        ];
+        let resp = wait_for(&packets, ServerResp::HeartbeatAck).unwrap();
+        assert_eq!(resp, ServerResp::HeartbeatAck);
+
+        // The server's reason for refusing the connection is reported
+        let packets = [MoonlightPacket::unauthorized(
+            UnauthorizedError::DeviceSecretIncorrect,
+        )];
+        let err = wait_for(&packets, ServerResp::HeartbeatAck).unwrap_err();
+        assert_eq!(failure_code(&err), ExitCode::Unauthorized);
+        assert_eq!(
+            err.to_string(),
+            UnauthorizedError::DeviceSecretIncorrect.to_string()
+        );
+
+        let packets = [MoonlightPacket::connect_failed(
+            ConnectFailedError::ServiceUnavailable,
+        )];
+        let err = wait_for(&packets, ServerResp::HeartbeatAck).unwrap_err();
+        assert_eq!(failure_code(&err), ExitCode::ConnectFailed);
+
+        // The connection was closed before the response
+        let err = wait_for(&[], ServerResp::HeartbeatAck).unwrap_err();
+        assert!(err.to_string().starts_with("server_closed_connection"));
+    }
+
+    #[test]
+    fn test_failure_code() {
+        let err = anyhow!("timeout_waiting_for_heartbeat_ack: timeout_ms=5000");
+        assert_eq!(
+            failure_code(&err.context("heartbeat_failed")),
+            ExitCode::Timeout
+        );
+
+        let err = anyhow!("tls_handshake_timeout: timeout_ms=5000");
+        assert_eq!(failure_code(&err), ExitCode::Timeout);
+
+        let err = anyhow!(DisconnectedReason::Unauthorized(
+            UnauthorizedError::DeviceDisabled
+        ));
+        assert_eq!(
+            failure_code(&err.context("auth_failed")),
+            ExitCode::Unauthorized
+        );
+
+        assert_eq!(
+            failure_code(&anyhow!("read_failed")),
+            ExitCode::ConnectFailed
+        );
+    }
+
+    #[test]
+    fn test_legacy_connect_fallback() {
+        use std::net::TcpListener;
+
+        // A version 1 server, which fails any CONNECT other than the legacy one
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut versions = vec![];
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 256];
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 2);
+                versions.push(buf[2]);
+
+                if buf[1..3] != [1, 1] {
+                    let p = MoonlightPacket::connect_failed(ConnectFailedError::Unknown);
+                    stream.write_all(&Codec::encode(&p).unwrap()).unwrap();
+                    continue;
+                }
+
+                let p = MoonlightPacket::connected(false, true);
+                stream.write_all(&Codec::encode(&p).unwrap()).unwrap();
+
+                // Acks the heartbeat, then the close
+                let replies = [
+                    MoonlightPacket::heartbeat_ack(true),
+                    MoonlightPacket::server_close_connection(),
+                ];
+                for reply in replies {
+                    assert!(stream.read(&mut buf).unwrap() > 0);
+                    stream.write_all(&Codec::encode(&reply).unwrap()).unwrap();
+                }
+            }
+            versions
+        });
+
+        let config = TestConnConfig {
+            endpoint: Endpoint::Tcp(port),
+            creds: Some(
+                Creds::new(
+                    "ABCDEFGH",
+                    "ABCDEFGHJK",
+                    "FOS-ABCDEFGHJKLMNPQRSTUVWXYZ23456789",
+                    false,
+                )
+                .unwrap(),
+            ),
+        };
+
+        let mut report = report();
+        run_inner(&config, &mut report).unwrap();
+        assert_eq!(report.protocol_version, Some(1));
+        assert!(
+            report
+                .lines
+                .iter()
+                .any(|line| line.starts_with("auth: connect rejected, retrying protocol_version=1"))
+        );
+        assert_eq!(server.join().unwrap(), [PROTOCOL_VERSION, 1]);
+
+        // Other failures aren't retried
+        let err = anyhow!(DisconnectedReason::Unauthorized(
+            UnauthorizedError::DeviceDisabled
+        ));
+        assert!(!connect_rejected(&err));
+        let err = anyhow!("server_closed_connection: waiting_for=connected");
+        assert!(connect_rejected(&err.context("auth_failed")));
+    }
 }
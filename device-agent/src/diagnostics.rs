@@ -0,0 +1,213 @@
+// -------------------------
+// --- AGENT DIAGNOSTICS ---
+// -------------------------
+
+use serde_json::{Value, json};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The number of recent disconnects reported in the agent status
+const MAX_DISCONNECTS: usize = 10;
+
+/// Counters and timings about the agent and its connection to Fostrom,
+/// shared between the client, the client logic and the transport.
+/// They are reported in the agent status.
+#[derive(Debug)]
+pub struct Diagnostics {
+    started: Instant,
+
+    /// Bytes written to the transport channel, but not yet to the socket
+    queued_bytes: AtomicUsize,
+
+    pending_txns: AtomicUsize,
+
+    /// Set by the client loop on every refresh, and while waiting between
+    /// sessions. Cleared before each systemd watchdog ping.
+    refreshed: AtomicBool,
+
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    session_started: Option<Instant>,
+    reconnects: u64,
+    last_heartbeat_ack: Option<SystemTime>,
+    heartbeat_rtt: Option<Duration>,
+    endpoint: Option<String>,
+    peer_addr: Option<SocketAddr>,
+    tls_version: Option<String>,
+    tls_cipher: Option<String>,
+    disconnects: VecDeque<(SystemTime, String)>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            queued_bytes: AtomicUsize::new(0),
+            pending_txns: AtomicUsize::new(0),
+            refreshed: AtomicBool::new(false),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn transport_opened(&self, endpoint: String, peer_addr: Option<SocketAddr>) {
+        let mut state = self.state.lock().unwrap();
+        state.endpoint = Some(endpoint);
+        state.peer_addr = peer_addr;
+        state.tls_version = None;
+        state.tls_cipher = None;
+    }
+
+    pub fn tls_established(&self, version: String, cipher: String) {
+        let mut state = self.state.lock().unwrap();
+        state.tls_version = Some(version);
+        state.tls_cipher = Some(cipher);
+    }
+
+    pub fn bytes_queued(&self, n: usize) {
+        self.queued_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn bytes_written(&self, n: usize) {
+        let _ = self
+            .queued_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                Some(queued.saturating_sub(n))
+            });
+    }
+
+    /// The bytes sent to the transport that haven't been written to the socket yet
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn set_pending_txns(&self, n: usize) {
+        self.pending_txns.store(n, Ordering::Relaxed);
+    }
+
+    pub fn refreshed(&self) {
+        self.refreshed.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the client loop refreshed since the last call
+    pub fn take_refreshed(&self) -> bool {
+        self.refreshed.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn heartbeat_ack(&self, rtt: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        state.last_heartbeat_ack = Some(SystemTime::now());
+        if rtt.is_some() {
+            state.heartbeat_rtt = rtt;
+        }
+    }
+
+    pub fn session_started(&self) {
+        self.state.lock().unwrap().session_started = Some(Instant::now());
+    }
+
+    /// Records the end of a session, whether or not it was ever authenticated
+    pub fn session_ended(&self, reason: String) {
+        self.queued_bytes.store(0, Ordering::Relaxed);
+        self.pending_txns.store(0, Ordering::Relaxed);
+
+        let mut state = self.state.lock().unwrap();
+        state.session_started = None;
+        state.reconnects += 1;
+
+        if state.disconnects.len() == MAX_DISCONNECTS {
+            state.disconnects.pop_front();
+        }
+
+        state.disconnects.push_back((SystemTime::now(), reason));
+    }
+
+    pub fn to_json(&self) -> Value {
+        let state = self.state.lock().unwrap();
+
+        let disconnects = state
+            .disconnects
+            .iter()
+            .rev()
+            .map(|(at, reason)| json!({"at_ms": unix_ms(*at), "reason": reason}))
+            .collect::<Vec<_>>();
+
+        json!({
+            "uptime_ms": self.started.elapsed().as_millis() as u64,
+            "session_ms": state.session_started.map(|s| s.elapsed().as_millis() as u64),
+            "reconnects": state.reconnects,
+            "pending_txns": self.pending_txns.load(Ordering::Relaxed),
+            "queued_bytes": self.queued_bytes.load(Ordering::Relaxed),
+            "last_heartbeat_ack_ms": state.last_heartbeat_ack.map(unix_ms),
+            "heartbeat_rtt_ms": state.heartbeat_rtt.map(|rtt| rtt.as_millis() as u64),
+            "endpoint": state.endpoint,
+            "peer_addr": state.peer_addr.map(|addr| addr.to_string()),
+            "tls_version": state.tls_version,
+            "tls_cipher": state.tls_cipher,
+            "recent_disconnects": disconnects,
+        })
+    }
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostics() {
+        let diagnostics = Diagnostics::new();
+        let addr = "127.0.0.1:8484".parse().unwrap();
+        diagnostics.transport_opened("127.0.0.1:8484".into(), Some(addr));
+        diagnostics.session_started();
+        diagnostics.heartbeat_ack(Some(Duration::from_millis(42)));
+        diagnostics.heartbeat_ack(None);
+        diagnostics.bytes_queued(100);
+        diagnostics.bytes_written(60);
+        diagnostics.set_pending_txns(3);
+
+        assert!(!diagnostics.take_refreshed());
+        diagnostics.refreshed();
+        assert!(diagnostics.take_refreshed());
+        assert!(!diagnostics.take_refreshed());
+
+        let json = diagnostics.to_json();
+        assert_eq!(json["endpoint"], "127.0.0.1:8484");
+        assert_eq!(json["peer_addr"], "127.0.0.1:8484");
+        assert_eq!(json["heartbeat_rtt_ms"], 42);
+        assert!(json["last_heartbeat_ack_ms"].as_u64().unwrap() > 0);
+        assert!(json["session_ms"].is_u64());
+        assert_eq!(json["queued_bytes"], 40);
+        assert_eq!(json["pending_txns"], 3);
+        assert_eq!(json["reconnects"], 0);
+
+        for i in 0..MAX_DISCONNECTS + 2 {
+            diagnostics.session_ended(format!("reason-{i}"));
+        }
+
+        let json = diagnostics.to_json();
+        let disconnects = json["recent_disconnects"].as_array().unwrap();
+        assert_eq!(disconnects.len(), MAX_DISCONNECTS);
+        assert_eq!(
+            disconnects[0]["reason"],
+            format!("reason-{}", MAX_DISCONNECTS + 1)
+        );
+        assert_eq!(json["reconnects"], MAX_DISCONNECTS as u64 + 2);
+        assert_eq!(json["session_ms"], Value::Null);
+        assert_eq!(json["queued_bytes"], 0);
+        assert_eq!(json["pending_txns"], 0);
+    }
+}
@@ -2,20 +2,31 @@
 // --- MOONLIGHT COMMANDS ---
 // --------------------------
 
-use super::response::{FailureResp as FR, Resp};
+use super::{
+    idempotency::{Claim, IdempotencyStore},
+    response::{FailureResp as FR, Resp, StatusCode},
+    submissions::PulseSubmissions,
+};
 use crate::moonlight_codec::{
-    ClientCmd, ClientLogic, MailAckType, MoonlightClient, PulseType, ReturnChanResult as R,
+    ChildId, ClientCmd, ClientLogic, CmdOpts, GeneralErrors, MailAckType, MoonlightClient,
+    PulseErrorReason, PulseType, ReturnChanResult as R,
 };
+use crate::notifycast::NotifyCast;
 use serde_json::{Value, json};
 use std::{
-    sync::mpsc::{Receiver, RecvTimeoutError, channel},
-    thread::sleep,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc,
+        mpsc::{Receiver, RecvError, RecvTimeoutError, channel},
+    },
+    thread::{sleep, spawn},
     time::{Duration, Instant},
 };
+use strum::IntoEnumIterator;
 
-fn wait_for_connected(client: &MoonlightClient) -> bool {
+fn wait_for_connected(client: &MoonlightClient, timeout: Duration) -> bool {
     let start = Instant::now();
-    while start.elapsed() < Duration::from_secs(10) {
+    while start.elapsed() < timeout {
         if client.connected() {
             return true;
         }
@@ -24,36 +35,62 @@ fn wait_for_connected(client: &MoonlightClient) -> bool {
     client.connected()
 }
 
+/// Sends the command and waits for its result, all within the timeout in `opts`
 fn make_request(
     client: &MoonlightClient,
     cmd: ClientCmd,
     result_rx: Receiver<R>,
+    opts: CmdOpts,
 ) -> Result<R, Resp> {
+    let deadline = Instant::now() + opts.timeout;
+    dispatch(client, cmd, opts)?;
+
+    match result_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Err(RecvTimeoutError::Timeout) => Err(FR::timeout()),
+        Err(_) => Err(FR::internal_server_error("Failed to receive response")),
+        Ok(r) => into_result(r),
+    }
+}
+
+fn dispatch(client: &MoonlightClient, cmd: ClientCmd, mut opts: CmdOpts) -> Result<(), Resp> {
+    let start = Instant::now();
+
     // wait until a connection is established before making a request
-    if !wait_for_connected(client) {
+    if !wait_for_connected(client, opts.timeout) {
         return Err(FR::forbidden(
             "not_connected: Device Agent is still connecting to Fostrom",
         ));
     }
 
-    client.send_cmd(cmd);
+    // the time spent waiting for the connection counts towards the timeout
+    opts.timeout = opts.timeout.saturating_sub(start.elapsed());
+    client.send_cmd(cmd, opts);
+    Ok(())
+}
 
-    match result_rx.recv_timeout(Duration::from_secs(10)) {
-        Err(RecvTimeoutError::Timeout) => Err(FR::timeout()),
-        Err(_) => Err(FR::internal_server_error("Failed to receive response")),
-        Ok(R::Timeout) => Err(FR::timeout()),
-        Ok(R::Err(msg)) => Err(FR::forbidden(msg)),
-        Ok(r) => Ok(r),
+fn into_result(r: R) -> Result<R, Resp> {
+    match r {
+        R::Timeout => Err(FR::timeout()),
+        R::Err(msg) => Err(FR::forbidden(msg)),
+        R::Busy(msg) => Err(FR::service_unavailable(msg, 1)),
+        r => Ok(r),
     }
 }
 
-pub fn mail_op(client: &MoonlightClient, ack_type: MailAckType, mail_id: u128) -> Resp {
+pub fn mail_op(
+    client: &MoonlightClient,
+    child: Option<ChildId>,
+    ack_type: MailAckType,
+    mail_id: u128,
+    opts: CmdOpts,
+) -> Resp {
     let (result_tx, result_rx) = channel();
 
     match make_request(
         client,
-        ClientCmd::MailOp(ack_type, mail_id, result_tx),
+        ClientCmd::MailOp(child, ack_type, mail_id, result_tx),
         result_rx,
+        opts,
     ) {
         Err(resp) => resp,
         Ok(R::MailAckSuccessful(mail_available)) => {
@@ -65,10 +102,15 @@ pub fn mail_op(client: &MoonlightClient, ack_type: MailAckType, mail_id: u128) -
     }
 }
 
-pub fn mailbox_next(client: &MoonlightClient, header_only: bool) -> Resp {
+pub fn mailbox_next(
+    client: &MoonlightClient,
+    child: Option<ChildId>,
+    header_only: bool,
+    opts: CmdOpts,
+) -> Resp {
     let (result_tx, result_rx) = channel();
-    let cmd = ClientCmd::MailboxNext(header_only, result_tx);
-    match make_request(client, cmd, result_rx) {
+    let cmd = ClientCmd::MailboxNext(child, header_only, result_tx);
+    match make_request(client, cmd, result_rx, opts) {
         Err(resp) => resp,
 
         Ok(R::Mail(None)) => {
@@ -101,16 +143,246 @@ pub fn mailbox_next(client: &MoonlightClient, header_only: bool) -> Resp {
 
 pub fn send_pulse(
     client: &MoonlightClient,
+    child: Option<ChildId>,
+    pulse_type: PulseType,
+    name: String,
+    payload: Option<Value>,
+    opts: CmdOpts,
+) -> Resp {
+    let (result_tx, result_rx) = channel();
+    let cmd = ClientCmd::SendPulse(child, pulse_type, name, payload, result_tx);
+    pulse_resp(make_request(client, cmd, result_rx, opts))
+}
+
+/// Sends many pulses under a single transaction, and answers with the result of each.
+/// The batch is `ok` only if every pulse in it succeeded.
+pub fn send_pulse_batch(
+    client: &MoonlightClient,
+    child: Option<ChildId>,
+    pulses: Vec<(PulseType, String, Option<Value>)>,
+    opts: CmdOpts,
+) -> Resp {
+    let count = pulses.len();
+    let (result_tx, result_rx) = channel();
+    let cmd = ClientCmd::SendPulseBatch(child, pulses, result_tx);
+
+    match make_request(client, cmd, result_rx, opts) {
+        Err(resp) => resp,
+        Ok(R::BatchResults(mut results)) => {
+            // A result missing from the server's response is an unknown error
+            results.resize(count, Err(PulseErrorReason::Unknown));
+
+            let all_ok = results.iter().all(|r| r.is_ok());
+            let results = results
+                .into_iter()
+                .map(|r| match r {
+                    Ok(()) => json!({"ok": true}),
+                    Err(reason) => json!({"ok": false, "error": reason.to_string()}),
+                })
+                .collect::<Vec<_>>();
+
+            Resp::ok(json!({"ok": all_ok, "results": results}))
+        }
+        Ok(_) => FR::internal_server_error("Unexpected Response"),
+    }
+}
+
+/// Sends a pulse at most once per Idempotency-Key.
+///
+/// A repeat of a completed pulse gets the stored result, and a repeat of a pulse
+/// still in flight waits for its result. The result is awaited on a separate thread,
+/// so it gets stored even if this request times out before it arrives.
+#[allow(clippy::too_many_arguments)]
+pub fn send_pulse_idempotent(
+    client: &MoonlightClient,
+    store: &Arc<IdempotencyStore>,
+    key: &str,
+    child: Option<ChildId>,
+    pulse_type: PulseType,
+    name: String,
+    payload: Option<Value>,
+    opts: CmdOpts,
+) -> Resp {
+    let deadline = Instant::now() + opts.timeout;
+    let mut hasher = DefaultHasher::new();
+    (child.map(|c| c.to_string()), pulse_type.to_string(), &name).hash(&mut hasher);
+    payload.as_ref().map(|p| p.to_string()).hash(&mut hasher);
+    let fingerprint = hasher.finish();
+
+    match store.claim(key, fingerprint, opts.timeout) {
+        Claim::New => (),
+        Claim::Replay(r) => {
+            let mut resp = pulse_resp(into_result(r));
+            resp.add_header("Idempotent-Replayed", true);
+            return resp;
+        }
+        Claim::Conflict => {
+            return FR::bad_request(
+                "idempotency_key_reused: The Idempotency-Key was already used for a different request",
+            );
+        }
+        Claim::Timeout => return FR::timeout(),
+    }
+
+    let (result_tx, result_rx) = channel();
+    let cmd = ClientCmd::SendPulse(child, pulse_type, name, payload, result_tx);
+
+    if let Err(resp) = dispatch(client, cmd, opts) {
+        store.complete(key, None);
+        return resp;
+    }
+
+    let store_clone = store.clone();
+    let key_clone = key.to_string();
+    spawn(move || {
+        let result = idempotent_result(result_rx.recv());
+        store_clone.complete(&key_clone, result);
+    });
+
+    match store.wait(key, deadline.saturating_duration_since(Instant::now())) {
+        Some(r) => pulse_resp(into_result(r)),
+        None => FR::timeout(),
+    }
+}
+
+/// The result stored for an Idempotency-Key, which is only ever what Fostrom
+/// answered. A pulse that never reached Fostrom releases the key, so that a
+/// retry can send it. A pulse that was sent without a response may or may not
+/// have been recorded, so the key stays claimed with a timeout, which retries
+/// get back instead of sending the pulse again.
+fn idempotent_result(result: Result<R, RecvError>) -> Option<R> {
+    let outcome_unknown = GeneralErrors::PulseOutcomeUnknown.to_string();
+
+    match result {
+        Ok(R::Ok) => Some(R::Ok),
+        Ok(R::Err(e)) if PulseErrorReason::iter().any(|reason| reason.to_string() == e) => {
+            Some(R::Err(e))
+        }
+        Ok(R::Timeout) | Err(_) => Some(R::Timeout),
+        Ok(R::Err(e)) if e == outcome_unknown => Some(R::Timeout),
+        Ok(_) => None,
+    }
+}
+
+/// Sends a pulse without waiting for its result, answering 202 with a submission ID.
+/// The result can be looked up at `/pulse/status/<id>`, and is published as a
+/// `pulse_result` event once it arrives. Nor does it wait for a connection:
+/// while there is none, the client holds the pulse for the next session,
+/// until it runs out of time.
+#[allow(clippy::too_many_arguments)]
+pub fn send_pulse_async(
+    client: &MoonlightClient,
+    submissions: &Arc<PulseSubmissions>,
+    notify: &NotifyCast,
+    child: Option<ChildId>,
     pulse_type: PulseType,
     name: String,
     payload: Option<Value>,
+    opts: CmdOpts,
 ) -> Resp {
     let (result_tx, result_rx) = channel();
-    let cmd = ClientCmd::SendPulse(pulse_type, name, payload, result_tx);
+    let cmd = ClientCmd::SendPulse(child, pulse_type, name, payload, result_tx);
+
+    let id = submissions.create();
+    client.send_cmd(cmd, opts);
+    let status_url = format!("/pulse/status/{id}");
 
-    match make_request(client, cmd, result_rx) {
+    let submissions = submissions.clone();
+    let notify = notify.clone();
+    let submission_id = id.clone();
+    spawn(move || {
+        let status = submissions.complete(&submission_id, result_rx.recv().ok());
+        let data = status.to_json(&submission_id).to_string();
+        notify.publish("pulse_result".to_string(), data);
+    });
+
+    let body = json!({"ok": true, "submission_id": id, "status_url": status_url});
+    let mut resp = Resp::new(StatusCode::Accepted, body);
+    resp.add_header("Location", status_url);
+    resp
+}
+
+pub fn pulse_status(submissions: &PulseSubmissions, id: &str) -> Resp {
+    match submissions.status(id) {
+        Some(status) => Resp::ok(status.to_json(id)),
+        None => FR::not_found("Unknown Submission ID"),
+    }
+}
+
+fn pulse_resp(result: Result<R, Resp>) -> Resp {
+    match result {
         Err(resp) => resp,
         Ok(R::Ok) => Resp::ok(json!({"ok": true})),
         Ok(_) => FR::internal_server_error("Unexpected Response"),
     }
 }
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotent_timeout_then_retry() {
+        let store = IdempotencyStore::new();
+
+        // The pulse was sent, but no response arrived in time
+        assert_eq!(store.claim("a", 1, Duration::ZERO), Claim::New);
+        store.complete("a", idempotent_result(Ok(R::Timeout)));
+
+        // The retry gets the timeout back, instead of sending the pulse again
+        let claim = store.claim("a", 1, Duration::ZERO);
+        assert_eq!(claim, Claim::Replay(R::Timeout));
+
+        // Likewise if the request was dropped without a result
+        assert_eq!(store.claim("b", 1, Duration::ZERO), Claim::New);
+        store.complete("b", idempotent_result(Err(RecvError)));
+        assert_eq!(
+            store.claim("b", 1, Duration::ZERO),
+            Claim::Replay(R::Timeout)
+        );
+
+        // Or the connection dropped after it was sent
+        assert_eq!(store.claim("c", 1, Duration::ZERO), Claim::New);
+        let e = GeneralErrors::PulseOutcomeUnknown.to_string();
+        store.complete("c", idempotent_result(Ok(R::Err(e))));
+        assert_eq!(
+            store.claim("c", 1, Duration::ZERO),
+            Claim::Replay(R::Timeout)
+        );
+
+        // What Fostrom answered is kept
+        assert_eq!(store.claim("d", 1, Duration::ZERO), Claim::New);
+        let e = PulseErrorReason::PacketSchemaNotFound.to_string();
+        store.complete("d", idempotent_result(Ok(R::Err(e.clone()))));
+        assert_eq!(
+            store.claim("d", 1, Duration::ZERO),
+            Claim::Replay(R::Err(e))
+        );
+    }
+
+    #[test]
+    fn test_idempotent_not_sent_then_retry() {
+        let store = IdempotencyStore::new();
+
+        // A pulse that never reached Fostrom can be sent by the retry
+        let not_sent = [
+            R::Busy(GeneralErrors::TooManyPendingTxns.to_string()),
+            R::Busy(GeneralErrors::NotConnected.to_string()),
+            R::Busy(GeneralErrors::ShuttingDown.to_string()),
+            R::Err(GeneralErrors::Reconfigured.to_string()),
+            R::Err(GeneralErrors::VirtualDevicesUnsupported.to_string()),
+            R::Err("mailbox write failed".to_string()),
+        ];
+
+        for r in not_sent {
+            assert_eq!(store.claim("a", 1, Duration::ZERO), Claim::New);
+            store.complete("a", idempotent_result(Ok(r)));
+        }
+
+        assert_eq!(store.claim("a", 1, Duration::ZERO), Claim::New);
+    }
+}
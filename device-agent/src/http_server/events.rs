@@ -3,10 +3,7 @@
 // ----------------------------------
 
 use crate::http_server::{SocketContext, socket::Socket};
-use std::{
-    sync::atomic::Ordering,
-    time::{Duration, Instant, SystemTime},
-};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Server Sent Events Handler
 pub fn handle_event_stream(mut socket: Socket, ctx: &SocketContext) {
@@ -40,7 +37,7 @@ pub fn handle_event_stream(mut socket: Socket, ctx: &SocketContext) {
     }
 
     loop {
-        if ctx.shutdown_flag.load(Ordering::Relaxed) {
+        if ctx.shutdown.is_set() {
             break;
         }
 
@@ -0,0 +1,233 @@
+// -------------------------
+// --- IDEMPOTENCY STORE ---
+// -------------------------
+
+use crate::moonlight_codec::ReturnChanResult as R;
+use std::{
+    collections::HashMap,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long the result of a request is remembered for its Idempotency-Key
+const KEY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The most keys remembered at once. Beyond this, the oldest results are forgotten.
+const MAX_KEYS: usize = 1024;
+
+#[derive(Debug, Clone)]
+enum Entry {
+    InFlight {
+        fingerprint: u64,
+    },
+    Done {
+        fingerprint: u64,
+        result: R,
+        at: Instant,
+    },
+}
+
+impl Entry {
+    fn fingerprint(&self) -> u64 {
+        match self {
+            Self::InFlight { fingerprint } | Self::Done { fingerprint, .. } => *fingerprint,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// First time the key is seen, the request should be sent
+    New,
+
+    /// The key was already used for this request, and this is its result
+    Replay(R),
+
+    /// The key was already used for a different request
+    Conflict,
+
+    /// The first request with this key is still in flight
+    Timeout,
+}
+
+/// Remembers recent Idempotency-Keys and the results of their requests,
+/// so that retrying a request never sends it to Fostrom twice.
+///
+/// If a request fails before it is sent, the key is released so that a retry
+/// can send it. Once sent, the key stays claimed until it expires, even if no
+/// response arrives, as the request may have reached Fostrom.
+#[derive(Debug)]
+pub struct IdempotencyStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    changed: Condvar,
+}
+
+impl IdempotencyStore {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Claims the key for a request. If a request with the same key is in flight,
+    /// waits up to `timeout` for its result.
+    pub fn claim(&self, key: &str, fingerprint: u64, timeout: Duration) -> Claim {
+        let deadline = Instant::now() + timeout;
+        let mut entries = self.entries.lock().unwrap();
+        Self::evict(&mut entries);
+
+        loop {
+            match entries.get(key) {
+                None => {
+                    entries.insert(key.to_string(), Entry::InFlight { fingerprint });
+                    return Claim::New;
+                }
+                Some(entry) if entry.fingerprint() != fingerprint => return Claim::Conflict,
+                Some(Entry::Done { result, .. }) => return Claim::Replay(result.clone()),
+                Some(Entry::InFlight { .. }) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Claim::Timeout;
+                    }
+
+                    entries = self
+                        .changed
+                        .wait_timeout(entries, deadline - now)
+                        .unwrap()
+                        .0;
+                }
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the result of a claimed key.
+    /// Returns None if the key was released, or if no result arrived in time.
+    pub fn wait(&self, key: &str, timeout: Duration) -> Option<R> {
+        let entries = self.entries.lock().unwrap();
+
+        let (entries, _) = self
+            .changed
+            .wait_timeout_while(entries, timeout, |entries| {
+                matches!(entries.get(key), Some(Entry::InFlight { .. }))
+            })
+            .unwrap();
+
+        match entries.get(key) {
+            Some(Entry::Done { result, .. }) => Some(result.clone()),
+            _ => None,
+        }
+    }
+
+    /// Stores the result of a claimed key, or releases the key if the result is None
+    pub fn complete(&self, key: &str, result: Option<R>) {
+        let mut entries = self.entries.lock().unwrap();
+
+        match (result, entries.get(key)) {
+            (Some(result), Some(entry)) => {
+                let fingerprint = entry.fingerprint();
+                let at = Instant::now();
+                let entry = Entry::Done {
+                    fingerprint,
+                    result,
+                    at,
+                };
+                entries.insert(key.to_string(), entry);
+            }
+            _ => {
+                entries.remove(key);
+            }
+        }
+
+        self.changed.notify_all();
+    }
+
+    /// Forgets expired results, and the oldest results beyond MAX_KEYS.
+    /// Keys still in flight are never forgotten.
+    fn evict(entries: &mut HashMap<String, Entry>) {
+        entries.retain(|_, entry| match entry {
+            Entry::InFlight { .. } => true,
+            Entry::Done { at, .. } => at.elapsed() < KEY_TTL,
+        });
+
+        if entries.len() < MAX_KEYS {
+            return;
+        }
+
+        let mut done = entries
+            .iter()
+            .filter_map(|(key, entry)| match entry {
+                Entry::Done { at, .. } => Some((*at, key.clone())),
+                Entry::InFlight { .. } => None,
+            })
+            .collect::<Vec<_>>();
+
+        done.sort();
+
+        let excess = entries.len() + 1 - MAX_KEYS;
+        for (_, key) in done.into_iter().take(excess) {
+            entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread::spawn};
+
+    const NO_WAIT: Duration = Duration::ZERO;
+
+    #[test]
+    fn test_replay() {
+        let store = IdempotencyStore::new();
+        assert_eq!(store.claim("a", 1, NO_WAIT), Claim::New);
+        assert_eq!(store.claim("a", 1, NO_WAIT), Claim::Timeout);
+
+        store.complete("a", Some(R::Ok));
+        assert_eq!(store.claim("a", 1, NO_WAIT), Claim::Replay(R::Ok));
+        assert_eq!(store.claim("a", 2, NO_WAIT), Claim::Conflict);
+        assert_eq!(store.claim("b", 1, NO_WAIT), Claim::New);
+    }
+
+    #[test]
+    fn test_released() {
+        let store = IdempotencyStore::new();
+        assert_eq!(store.claim("a", 1, NO_WAIT), Claim::New);
+        store.complete("a", None);
+        assert_eq!(store.wait("a", NO_WAIT), None);
+        assert_eq!(store.claim("a", 1, NO_WAIT), Claim::New);
+    }
+
+    #[test]
+    fn test_retry_held_while_in_flight() {
+        let store = Arc::new(IdempotencyStore::new());
+        assert_eq!(store.claim("a", 1, NO_WAIT), Claim::New);
+
+        let s = store.clone();
+        let retry = spawn(move || s.claim("a", 1, Duration::from_secs(5)));
+
+        let result = R::Err("packet_schema_not_found".to_string());
+        store.complete("a", Some(result.clone()));
+        assert_eq!(retry.join().unwrap(), Claim::Replay(result.clone()));
+        assert_eq!(store.wait("a", NO_WAIT), Some(result));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let store = IdempotencyStore::new();
+        assert_eq!(store.claim("in-flight", 0, NO_WAIT), Claim::New);
+
+        for i in 0..MAX_KEYS {
+            let key = format!("key-{i}");
+            assert_eq!(store.claim(&key, 0, NO_WAIT), Claim::New);
+            store.complete(&key, Some(R::Ok));
+        }
+
+        let entries = store.entries.lock().unwrap();
+        assert!(entries.len() <= MAX_KEYS);
+        assert!(entries.contains_key("in-flight"));
+        assert!(entries.contains_key(&format!("key-{}", MAX_KEYS - 1)));
+        assert!(!entries.contains_key("key-0"));
+    }
+}
@@ -0,0 +1,282 @@
+// --------------------
+// --- RATE LIMITER ---
+// --------------------
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Requests per second for each local client, unless $FOSTROM_CLIENT_RATE_LIMIT is set
+const DEFAULT_CLIENT_RATE: Rate = Rate::per_sec(10.0);
+
+/// Requests per second across all local clients, unless $FOSTROM_GLOBAL_RATE_LIMIT is set
+const DEFAULT_GLOBAL_RATE: Rate = Rate::per_sec(25.0);
+
+/// Idle clients are forgotten once this many are being tracked
+const MAX_TRACKED_CLIENTS: usize = 256;
+
+/// Requests per second, and the burst allowed on top
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    /// Allows bursts of twice the rate
+    pub const fn per_sec(per_sec: f64) -> Self {
+        Self {
+            per_sec,
+            burst: per_sec * 2.0,
+        }
+    }
+}
+
+/// The rates that local clients are limited to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    /// For each local client
+    pub client: Rate,
+
+    /// Across all local clients
+    pub global: Rate,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            client: DEFAULT_CLIENT_RATE,
+            global: DEFAULT_GLOBAL_RATE,
+        }
+    }
+}
+
+/// A token bucket, refilled at a fixed rate up to the burst size
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: Rate, now: Instant) -> Self {
+        Self {
+            tokens: rate.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_sec).min(rate.burst);
+        self.updated = now;
+    }
+
+    /// Returns how long until a token is available, if none is right now
+    fn wait_time(&self, rate: Rate) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / rate.per_sec))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    /// This client is sending too many requests
+    Client(Duration),
+
+    /// All clients together are sending too many requests
+    Global(Duration),
+}
+
+impl Limited {
+    /// Seconds to wait before retrying, for the Retry-After header
+    pub fn retry_after(&self) -> u64 {
+        let (Self::Client(wait) | Self::Global(wait)) = self;
+        wait.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+/// Limits how fast local clients can send requests,
+/// both individually and all together.
+#[derive(Debug)]
+pub struct RateLimiter {
+    inner: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    limits: RateLimits,
+    global: Bucket,
+    clients: HashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self::new_at(limits, Instant::now())
+    }
+
+    fn new_at(limits: RateLimits, now: Instant) -> Self {
+        Self {
+            inner: Mutex::new(Buckets {
+                limits,
+                global: Bucket::full(limits.global, now),
+                clients: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Applies the rates to the following requests, when the config is reloaded.
+    /// The tokens already in the buckets are kept, up to the new burst sizes.
+    pub fn set_limits(&self, limits: RateLimits) {
+        let mut buckets = self.inner.lock().unwrap();
+        buckets.global.tokens = buckets.global.tokens.min(limits.global.burst);
+        for bucket in buckets.clients.values_mut() {
+            bucket.tokens = bucket.tokens.min(limits.client.burst);
+        }
+        buckets.limits = limits;
+    }
+
+    /// Takes a token for the client, unless a limit has been hit
+    pub fn check(&self, client: &str) -> Result<(), Limited> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: &str, now: Instant) -> Result<(), Limited> {
+        let mut buckets = self.inner.lock().unwrap();
+        let Buckets {
+            limits,
+            global,
+            clients,
+        } = &mut *buckets;
+        let RateLimits {
+            client: client_rate,
+            global: global_rate,
+        } = *limits;
+
+        if clients.len() >= MAX_TRACKED_CLIENTS {
+            clients.retain(|_, bucket| {
+                bucket.refill(client_rate, now);
+                bucket.tokens < client_rate.burst
+            });
+        }
+
+        let bucket = clients
+            .entry(client.to_string())
+            .or_insert_with(|| Bucket::full(client_rate, now));
+
+        bucket.refill(client_rate, now);
+        global.refill(global_rate, now);
+
+        if let Some(wait) = bucket.wait_time(client_rate) {
+            return Err(Limited::Client(wait));
+        }
+
+        if let Some(wait) = global.wait_time(global_rate) {
+            return Err(Limited::Global(wait));
+        }
+
+        bucket.tokens -= 1.0;
+        global.tokens -= 1.0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_limit() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new_at(RateLimits::default(), now);
+
+        for _ in 0..20 {
+            assert_eq!(limiter.check_at("a", now), Ok(()));
+        }
+
+        let limited = limiter.check_at("a", now).unwrap_err();
+        assert!(matches!(limited, Limited::Client(_)));
+        assert_eq!(limited.retry_after(), 1);
+
+        // Other clients are unaffected
+        assert_eq!(limiter.check_at("b", now), Ok(()));
+
+        // Tokens are refilled over time
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.check_at("a", later), Ok(()));
+        assert!(limiter.check_at("a", later).is_err());
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new_at(RateLimits::default(), now);
+
+        for i in 0..50 {
+            assert_eq!(limiter.check_at(&format!("client-{}", i % 5), now), Ok(()));
+        }
+
+        let limited = limiter.check_at("client-9", now).unwrap_err();
+        assert!(matches!(limited, Limited::Global(_)));
+    }
+
+    #[test]
+    fn test_idle_clients_forgotten() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new_at(RateLimits::default(), now);
+
+        for i in 0..MAX_TRACKED_CLIENTS {
+            limiter.inner.lock().unwrap().clients.insert(
+                format!("client-{i}"),
+                Bucket::full(DEFAULT_CLIENT_RATE, now),
+            );
+        }
+
+        assert_eq!(limiter.check_at("a", now), Ok(()));
+        assert_eq!(limiter.inner.lock().unwrap().clients.len(), 1);
+    }
+
+    #[test]
+    fn test_configured_limits() {
+        let now = Instant::now();
+        let limits = RateLimits {
+            client: Rate::per_sec(100.0),
+            global: Rate::per_sec(200.0),
+        };
+        let limiter = RateLimiter::new_at(limits, now);
+
+        // More than the default global burst from a single client
+        for _ in 0..200 {
+            assert_eq!(limiter.check_at("a", now), Ok(()));
+        }
+        assert!(matches!(
+            limiter.check_at("a", now),
+            Err(Limited::Client(_))
+        ));
+
+        // Lowered on reload, keeping no more tokens than the new bursts
+        let limiter = RateLimiter::new_at(RateLimits::default(), now);
+        limiter.set_limits(RateLimits {
+            client: Rate::per_sec(1.0),
+            global: Rate::per_sec(1.5),
+        });
+
+        assert_eq!(limiter.check_at("b", now), Ok(()));
+        assert_eq!(limiter.check_at("b", now), Ok(()));
+        assert!(matches!(
+            limiter.check_at("b", now),
+            Err(Limited::Client(_))
+        ));
+        assert_eq!(limiter.check_at("c", now), Ok(()));
+        assert!(matches!(
+            limiter.check_at("d", now),
+            Err(Limited::Global(_))
+        ));
+    }
+}
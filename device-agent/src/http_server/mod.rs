@@ -4,19 +4,18 @@
 
 mod cmd;
 mod events;
+mod idempotency;
+mod limiter;
 mod request;
 mod response;
 mod router;
 mod server;
 mod socket;
+mod submissions;
 
-use anyhow::Result;
-pub use socket::SocketContext;
-
-pub fn start_unix_server(ctx: &SocketContext) -> Result<()> {
-    server::unix_server(ctx)
-}
-
-pub fn start_tcp_server(ctx: &SocketContext) -> Result<()> {
-    server::tcp_server(ctx)
-}
+pub use idempotency::IdempotencyStore;
+pub use limiter::{Rate, RateLimiter, RateLimits};
+pub use router::is_valid_pulse_name;
+pub use server::{BindError, TcpServer, UnixServer};
+pub use socket::{ReloadRequest, Shutdown, SocketContext};
+pub use submissions::PulseSubmissions;
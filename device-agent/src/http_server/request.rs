@@ -23,7 +23,6 @@ pub enum Method {
 pub struct Req {
     pub method: Method,
     pub path: String,
-    #[allow(dead_code)]
     pub headers: HashMap<String, String>,
     pub body: Option<Value>,
 }
@@ -35,8 +34,9 @@ pub fn parse_request(
     let (method, path) = parse_request_line(buf_reader)?;
     let headers = parse_request_headers(buf_reader)?;
 
-    // Skip header validation for root and /stop-agent routes.
-    if (method == Method::GET && path == "/") || (method == Method::DELETE && path == "/stop-agent")
+    // Skip header validation for the status and /stop-agent routes.
+    if (method == Method::GET && (path == "/" || path == "/status"))
+        || (method == Method::DELETE && path == "/stop-agent")
     {
         return Ok(Req {
             method,
@@ -191,7 +191,7 @@ fn validate_headers(
         return Err(FR::bad_request("Header X-Fleet-ID is empty"));
     }
 
-    if fleet_id != &client.fleet_id {
+    if *fleet_id != client.fleet_id() {
         return Err(FR::unauthorized("Fleet ID mismatch"));
     }
 
@@ -203,7 +203,7 @@ fn validate_headers(
         return Err(FR::bad_request("Header X-Device-ID is empty"));
     }
 
-    if device_id != &client.device_id {
+    if *device_id != client.device_id() {
         return Err(FR::unauthorized("Device ID mismatch"));
     }
 
@@ -73,8 +73,8 @@ impl Resp {
         let body_len = self.body.len();
 
         self.add_header("X-Connected", client.connected())
-            .add_header("X-Device-ID", client.device_id.clone())
-            .add_header("X-Fleet-ID", client.fleet_id.clone())
+            .add_header("X-Device-ID", client.device_id())
+            .add_header("X-Fleet-ID", client.fleet_id())
             .add_header("Content-Length", body_len)
             .add_header("Date", fmt_http_date(SystemTime::now()));
 
@@ -143,6 +143,18 @@ impl FailureResp {
         Self::make(StatusCode::InternalServerError, error_msg)
     }
 
+    pub fn too_many_requests(error_msg: impl ToString, retry_after: u64) -> Resp {
+        let mut resp = Self::make(StatusCode::TooManyRequests, error_msg);
+        resp.add_header("Retry-After", retry_after);
+        resp
+    }
+
+    pub fn service_unavailable(error_msg: impl ToString, retry_after: u64) -> Resp {
+        let mut resp = Self::make(StatusCode::ServiceUnavailable, error_msg);
+        resp.add_header("Retry-After", retry_after);
+        resp
+    }
+
     pub fn version_not_supported() -> Resp {
         Self::make(
             StatusCode::VersionNotSupported,
@@ -157,26 +169,32 @@ impl FailureResp {
 
 pub enum StatusCode {
     Ok,                  // 200
+    Accepted,            // 202
     BadRequest,          // 400
     Unauthorized,        // 401
     Forbidden,           // 403
     NotFound,            // 404
     Timeout,             // 408
+    TooManyRequests,     // 429
     VersionNotSupported, // 505
     InternalServerError, // 500
+    ServiceUnavailable,  // 503
 }
 
 impl StatusCode {
     pub fn to_http(&self) -> &str {
         match self {
             StatusCode::Ok => "200 OK",
+            StatusCode::Accepted => "202 Accepted",
             StatusCode::BadRequest => "400 Bad Request",
             StatusCode::Unauthorized => "401 Unauthorized",
             StatusCode::Forbidden => "403 Forbidden",
             StatusCode::NotFound => "404 Not Found",
             StatusCode::Timeout => "408 Request Timeout",
+            StatusCode::TooManyRequests => "429 Too Many Requests",
             StatusCode::VersionNotSupported => "505 HTTP Version Not Supported",
             StatusCode::InternalServerError => "500 Internal Server Error",
+            StatusCode::ServiceUnavailable => "503 Service Unavailable",
         }
     }
 }
@@ -3,7 +3,10 @@
 // -------------------
 
 use super::{
-    cmd::{mail_op, mailbox_next, send_pulse},
+    cmd::{
+        mail_op, mailbox_next, pulse_status, send_pulse, send_pulse_async, send_pulse_batch,
+        send_pulse_idempotent,
+    },
     request::{
         Method::{DELETE, GET, HEAD, POST, PUT},
         Req, parse_request,
@@ -11,29 +14,55 @@ use super::{
     response::{FailureResp as FR, Resp},
 };
 use crate::{
-    http_server::{SocketContext, events::handle_event_stream, socket::Socket},
+    http_server::{SocketContext, events::handle_event_stream, limiter::Limited, socket::Socket},
     moonlight_codec::{
-        ClientLogic,
+        ChildId, ClientLogic, CmdOpts,
         MailAckType::{self, Ack, Reject, Requeue},
         PulseType::{self, Data, Msg, System},
     },
 };
-use serde_json::json;
-use std::io::BufReader;
-use std::sync::atomic::Ordering;
+use serde_json::{Value, json};
+use std::{io::BufReader, sync::mpsc::channel, time::Duration};
+
+/// The most pulses accepted in a single `POST /pulse/batch` request
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// The bounds of the `X-Timeout-Ms` header
+const MIN_TIMEOUT_MS: u64 = 100;
+const MAX_TIMEOUT_MS: u64 = 60_000;
+
+/// How long `PUT /config` waits for the configuration to be reloaded
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Pass a TCP/UNIX Stream
 /// and this function will handle the request.
 /// It'll parse the request, route it, and
 /// write the final response back to the stream.
 pub fn handle_request(mut socket: Socket, ctx: &SocketContext) {
+    let peer = socket.peer();
     let mut buf_reader = BufReader::new(&mut socket);
 
+    let mut request_id = None;
+
     let mut resp = match parse_request(&mut buf_reader, &ctx.client) {
-        Ok(req) => route(ctx, req),
+        Ok(req) => match req.headers.get("x-request-id") {
+            Some(id) if !is_valid_request_id(id) => FR::bad_request("Invalid X-Request-ID"),
+            id => {
+                request_id = id.cloned();
+                match rate_limit(ctx, &peer, &req) {
+                    Some(resp) => resp,
+                    None => route(ctx, req),
+                }
+            }
+        },
         Err(resp) => resp,
     };
 
+    // Echo the caller's request ID, so that the response can be matched to the logs
+    if let Some(request_id) = request_id {
+        resp.add_header("X-Request-ID", request_id);
+    }
+
     if !socket.send(resp.compile(&ctx.client).as_bytes()) {
         return;
     }
@@ -43,34 +72,151 @@ pub fn handle_request(mut socket: Socket, ctx: &SocketContext) {
     }
 }
 
+/// Requests that go out to Fostrom are rate limited, per client and
+/// across all clients. Status, event stream, pulse status and stop requests are not.
+fn rate_limit(ctx: &SocketContext, peer: &str, req: &Req) -> Option<Resp> {
+    if matches!(
+        req.path.as_str(),
+        "/" | "/status" | "/events" | "/stop-agent" | "/config"
+    ) || req.path.starts_with("/pulse/status/")
+    {
+        return None;
+    }
+
+    match ctx.limiter.check(peer) {
+        Ok(()) => None,
+        Err(limited @ Limited::Client(_)) => Some(FR::too_many_requests(
+            "rate_limited: Too many requests from this client",
+            limited.retry_after(),
+        )),
+        Err(limited @ Limited::Global(_)) => Some(FR::service_unavailable(
+            "rate_limited: Too many requests to the Device Agent",
+            limited.retry_after(),
+        )),
+    }
+}
+
 fn route(ctx: &SocketContext, req: Req) -> Resp {
     match (req.method.clone(), req.path.as_str()) {
-        (GET, "/") => Resp::ok(ctx.client.status()),
+        (GET, "/" | "/status") => agent_status(ctx),
         (HEAD, "/") => Resp::ok(""),
         (DELETE, "/stop-agent") => exec_stop_agent(ctx),
+        (PUT, "/config") => exec_reload_config(ctx),
         (GET, "/events") => Resp::event_stream(),
-        (GET, "/mailbox/next") => mailbox_next(&ctx.client, false),
-        (HEAD, "/mailbox/next") => mailbox_next(&ctx.client, true),
-        (PUT, path) if path.starts_with("/mailbox/ack/") => exec_mail_op(ctx, Ack, req),
-        (PUT, path) if path.starts_with("/mailbox/reject/") => exec_mail_op(ctx, Reject, req),
-        (PUT, path) if path.starts_with("/mailbox/requeue/") => exec_mail_op(ctx, Requeue, req),
-        (POST, path) if path.starts_with("/pulse/datapoint/") => exec_send_pulse(ctx, Data, req),
-        (POST, path) if path.starts_with("/pulse/msg/") => exec_send_pulse(ctx, Msg, req),
-        (POST, path) if path.starts_with("/pulse/system/") => exec_send_pulse(ctx, System, req),
+        (GET, p) if p.starts_with("/pulse/status/") => {
+            pulse_status(&ctx.submissions, p.trim_start_matches("/pulse/status/"))
+        }
+        (_, path) if path.starts_with("/devices/") => route_child_device(ctx, req),
+        _ => {
+            let path = req.path.clone();
+            route_device(ctx, None, &path, req)
+        }
+    }
+}
+
+/// Routes `/devices/<child_id>/...` to the mailbox and pulse
+/// routes of a virtual child device, when running as a gateway.
+fn route_child_device(ctx: &SocketContext, req: Req) -> Resp {
+    let Some((child_id, path)) = req.path.trim_start_matches("/devices/").split_once("/") else {
+        return FR::not_found("Not Found");
+    };
+
+    match ChildId::new(child_id) {
+        Ok(child) => {
+            let path = format!("/{path}");
+            route_device(ctx, Some(child), &path, req)
+        }
+        Err(e) => FR::bad_request(e),
+    }
+}
+
+/// Mailbox and pulse routes, for either this device or a virtual child device
+fn route_device(ctx: &SocketContext, child: Option<ChildId>, path: &str, req: Req) -> Resp {
+    let opts = match cmd_opts(&req) {
+        Ok(opts) => opts,
+        Err(resp) => return resp,
+    };
+
+    match (req.method.clone(), path) {
+        (GET, "/mailbox/next") => mailbox_next(&ctx.client, child, false, opts),
+        (HEAD, "/mailbox/next") => mailbox_next(&ctx.client, child, true, opts),
+        (PUT, p) if p.starts_with("/mailbox/ack/") => exec_mail_op(ctx, child, Ack, p, opts),
+        (PUT, p) if p.starts_with("/mailbox/reject/") => exec_mail_op(ctx, child, Reject, p, opts),
+        (PUT, p) if p.starts_with("/mailbox/requeue/") => {
+            exec_mail_op(ctx, child, Requeue, p, opts)
+        }
+        (POST, "/pulse/batch") => exec_send_pulse_batch(ctx, child, req, opts),
+        (POST, p) if p.starts_with("/pulse/datapoint/") => {
+            exec_send_pulse(ctx, child, Data, p, req, opts)
+        }
+        (POST, p) if p.starts_with("/pulse/msg/") => exec_send_pulse(ctx, child, Msg, p, req, opts),
+        (POST, p) if p.starts_with("/pulse/system/") => {
+            exec_send_pulse(ctx, child, System, p, req, opts)
+        }
         _ => FR::not_found("Not Found"),
     }
 }
 
+/// Reads the `X-Timeout-Ms` and `X-Request-ID` headers
+fn cmd_opts(req: &Req) -> Result<CmdOpts, Resp> {
+    let mut opts = CmdOpts {
+        request_id: req.headers.get("x-request-id").cloned(),
+        ..CmdOpts::default()
+    };
+
+    if let Some(timeout) = req.headers.get("x-timeout-ms") {
+        match timeout.trim().parse::<u64>() {
+            Ok(ms) if (MIN_TIMEOUT_MS..=MAX_TIMEOUT_MS).contains(&ms) => {
+                opts.timeout = Duration::from_millis(ms);
+            }
+            _ => {
+                return Err(FR::bad_request(format!(
+                    "Invalid X-Timeout-Ms: must be between {MIN_TIMEOUT_MS} and {MAX_TIMEOUT_MS}"
+                )));
+            }
+        }
+    }
+
+    Ok(opts)
+}
+
+/// The connection status and diagnostics of the agent
+fn agent_status(ctx: &SocketContext) -> Resp {
+    let mut status = ctx.client.status();
+    status["diagnostics"]["sse_subscribers"] = json!(ctx.notify.subscribers());
+    Resp::ok(status)
+}
+
 fn exec_stop_agent(ctx: &SocketContext) -> Resp {
-    ctx.shutdown_flag.store(true, Ordering::SeqCst);
+    ctx.shutdown.trigger();
     ctx.client.stop();
     Resp::ok(json!({"ok": true}))
 }
 
-fn exec_mail_op(ctx: &SocketContext, ack_type: MailAckType, req: Req) -> Resp {
-    if let Some((_, mail_id_str)) = req.path.trim_start_matches("/mailbox/").split_once("/") {
+fn exec_reload_config(ctx: &SocketContext) -> Resp {
+    let (reply_tx, reply_rx) = channel();
+
+    if ctx.reload.send(reply_tx).is_err() {
+        return FR::service_unavailable("reload_failed: The agent is shutting down", 1);
+    }
+
+    match reply_rx.recv_timeout(RELOAD_TIMEOUT) {
+        Ok(Ok(result)) => Resp::ok(result),
+        Ok(Err(e)) => FR::bad_request(format!("reload_failed: {e}")),
+        Err(_) => FR::timeout(),
+    }
+}
+
+fn exec_mail_op(
+    ctx: &SocketContext,
+    child: Option<ChildId>,
+    ack_type: MailAckType,
+    path: &str,
+    opts: CmdOpts,
+) -> Resp {
+    if let Some((_, mail_id_str)) = path.trim_start_matches("/mailbox/").split_once("/") {
         match ClientLogic::uuidv7_u128(mail_id_str) {
-            Ok(mail_id) => mail_op(&ctx.client, ack_type, mail_id),
+            Ok(mail_id) => mail_op(&ctx.client, child, ack_type, mail_id, opts),
             Err(e) => FR::bad_request(e),
         }
     } else {
@@ -78,21 +224,264 @@ fn exec_mail_op(ctx: &SocketContext, ack_type: MailAckType, req: Req) -> Resp {
     }
 }
 
-fn exec_send_pulse(ctx: &SocketContext, pulse_type: PulseType, req: Req) -> Resp {
-    if let Some((_, pulse_name)) = req.path.trim_start_matches("/pulse/").split_once("/") {
-        match is_valid_pulse_name(pulse_name.trim()) {
-            true => send_pulse(&ctx.client, pulse_type, pulse_name.to_string(), req.body),
-            false => FR::bad_request("Invalid Pulse Name"),
+fn exec_send_pulse(
+    ctx: &SocketContext,
+    child: Option<ChildId>,
+    pulse_type: PulseType,
+    path: &str,
+    req: Req,
+    opts: CmdOpts,
+) -> Resp {
+    if let Some((_, pulse_name)) = path.trim_start_matches("/pulse/").split_once("/") {
+        if !is_valid_pulse_name(pulse_name.trim()) {
+            return FR::bad_request("Invalid Pulse Name");
+        }
+
+        let name = pulse_name.to_string();
+
+        // Requests with an Idempotency-Key are always answered with the pulse's result,
+        // as that result is what a retry with the same key gets back.
+        match req.headers.get("idempotency-key") {
+            None if prefers_async(&req) => send_pulse_async(
+                &ctx.client,
+                &ctx.submissions,
+                &ctx.notify,
+                child,
+                pulse_type,
+                name,
+                req.body,
+                opts,
+            ),
+            None => send_pulse(&ctx.client, child, pulse_type, name, req.body, opts),
+            Some(key) if is_valid_idempotency_key(key) => send_pulse_idempotent(
+                &ctx.client,
+                &ctx.idempotency,
+                key,
+                child,
+                pulse_type,
+                name,
+                req.body,
+                opts,
+            ),
+            Some(_) => FR::bad_request("Invalid Idempotency-Key"),
         }
     } else {
         FR::bad_request("Pulse name missing in path after /pulse/<type>/")
     }
 }
 
-fn is_valid_pulse_name(name: &str) -> bool {
+/// The body is an array of `{"type": "datapoint" | "msg" | "system", "name": ..., "payload": ...}`
+fn exec_send_pulse_batch(
+    ctx: &SocketContext,
+    child: Option<ChildId>,
+    req: Req,
+    opts: CmdOpts,
+) -> Resp {
+    let Some(Value::Array(items)) = req.body else {
+        return FR::bad_request("Request body must be an array of pulses");
+    };
+
+    if items.is_empty() || items.len() > MAX_BATCH_SIZE {
+        return FR::bad_request(format!(
+            "A batch must have between 1 and {MAX_BATCH_SIZE} pulses"
+        ));
+    }
+
+    let mut pulses = Vec::with_capacity(items.len());
+
+    for (i, mut item) in items.into_iter().enumerate() {
+        let pulse_type = match item.get("type").and_then(Value::as_str) {
+            Some("datapoint") => Data,
+            Some("msg") => Msg,
+            Some("system") => System,
+            _ => return FR::bad_request(format!("Invalid Pulse Type at index {i}")),
+        };
+
+        let name = match item.get("name").and_then(Value::as_str) {
+            Some(name) if is_valid_pulse_name(name) => name.to_string(),
+            _ => return FR::bad_request(format!("Invalid Pulse Name at index {i}")),
+        };
+
+        let payload = item.get_mut("payload").map(Value::take);
+
+        pulses.push((pulse_type, name, payload));
+    }
+
+    send_pulse_batch(&ctx.client, child, pulses, opts)
+}
+
+/// Checks for `Prefer: respond-async` (RFC 7240)
+fn prefers_async(req: &Req) -> bool {
+    req.headers.get("prefer").is_some_and(|prefer| {
+        prefer
+            .split(',')
+            .any(|p| p.trim().eq_ignore_ascii_case("respond-async"))
+    })
+}
+
+fn is_valid_idempotency_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= 255 && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+pub fn is_valid_pulse_name(name: &str) -> bool {
     !name.is_empty()
         && name.len() <= 255
         && name
             .chars()
             .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
 }
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_server::{
+            Shutdown,
+            idempotency::IdempotencyStore,
+            limiter::{RateLimiter, RateLimits},
+            submissions::PulseSubmissions,
+        },
+        moonlight_codec::{
+            CodecLimits, ConnectMode, DEFAULT_DRAIN_TIMEOUT, DEFAULT_MAX_PENDING_TXNS,
+            MoonlightClient,
+        },
+        notifycast::NotifyCast,
+    };
+    use std::{
+        collections::HashMap,
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        sync::{Arc, atomic::AtomicUsize},
+        time::Instant,
+    };
+
+    fn make_client() -> MoonlightClient {
+        MoonlightClient::new(
+            "ABCDEFGH".to_string(),
+            "ABCDEFGHJK".to_string(),
+            "FOS-ABCDEFGHJKLMNPQRSTUVWXYZ23456789".to_string(),
+            ConnectMode::Local(8484),
+            CodecLimits::default(),
+            DEFAULT_DRAIN_TIMEOUT,
+            DEFAULT_MAX_PENDING_TXNS,
+        )
+    }
+
+    fn make_req(headers: &[(&str, &str)]) -> Req {
+        Req {
+            method: POST,
+            path: "/pulse/data/name".to_string(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+            body: None,
+        }
+    }
+
+    /// Sends the request through handle_request, and returns the response
+    fn roundtrip(request: &str) -> String {
+        let ctx = SocketContext {
+            client: make_client(),
+            notify: NotifyCast::new(),
+            shutdown: Shutdown::new().unwrap(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            limiter: Arc::new(RateLimiter::new(RateLimits::default())),
+            idempotency: Arc::new(IdempotencyStore::new()),
+            submissions: Arc::new(PulseSubmissions::new()),
+            reload: channel().0,
+        };
+
+        let (server, mut caller) = UnixStream::pair().unwrap();
+        caller.write_all(request.as_bytes()).unwrap();
+        caller.shutdown(std::net::Shutdown::Write).unwrap();
+
+        handle_request(Socket::UNIX(server), &ctx);
+
+        let mut resp = String::new();
+        caller.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    fn accepted(headers: &[(&str, &str)]) -> CmdOpts {
+        let Ok(opts) = cmd_opts(&make_req(headers)) else {
+            panic!("expected the headers to be accepted: {headers:?}");
+        };
+        opts
+    }
+
+    fn rejected(headers: &[(&str, &str)]) -> String {
+        let Err(mut resp) = cmd_opts(&make_req(headers)) else {
+            panic!("expected the headers to be rejected: {headers:?}");
+        };
+        resp.compile(&make_client())
+    }
+
+    #[test]
+    fn test_cmd_opts_timeout() {
+        let opts = accepted(&[]);
+        assert_eq!(opts.timeout, CmdOpts::default().timeout);
+        assert_eq!(opts.request_id, None);
+
+        // Both bounds are included
+        let opts = accepted(&[("x-timeout-ms", "100")]);
+        assert_eq!(opts.timeout, Duration::from_millis(100));
+        let opts = accepted(&[("x-timeout-ms", "60000")]);
+        assert_eq!(opts.timeout, Duration::from_millis(60_000));
+        let opts = accepted(&[("x-timeout-ms", " 2500 ")]);
+        assert_eq!(opts.timeout, Duration::from_millis(2500));
+
+        for timeout in ["99", "60001", "0", "-100", "abc", "1.5", ""] {
+            let resp = rejected(&[("x-timeout-ms", timeout)]);
+            assert!(resp.starts_with("HTTP/1.1 400"), "{timeout}: {resp}");
+            assert!(resp.contains("Invalid X-Timeout-Ms: must be between 100 and 60000"));
+        }
+    }
+
+    #[test]
+    fn test_cmd_opts_request_id() {
+        let opts = accepted(&[("x-request-id", "req-123")]);
+        assert_eq!(opts.request_id, Some("req-123".to_string()));
+    }
+
+    #[test]
+    fn test_request_id_echo() {
+        let resp = roundtrip("GET /status HTTP/1.1\r\nX-Request-ID: req-123\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+        assert!(resp.contains("X-Request-ID: req-123\r\n"), "{resp}");
+
+        // Not echoed when missing or invalid
+        let resp = roundtrip("GET /status HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+        assert!(!resp.contains("X-Request-ID"), "{resp}");
+
+        let id = "x".repeat(129);
+        let resp = roundtrip(&format!(
+            "GET /status HTTP/1.1\r\nX-Request-ID: {id}\r\n\r\n"
+        ));
+        assert!(resp.starts_with("HTTP/1.1 400"), "{resp}");
+        assert!(resp.contains("Invalid X-Request-ID"), "{resp}");
+        assert!(!resp.contains("X-Request-ID: "), "{resp}");
+    }
+
+    #[test]
+    fn test_async_pulse_not_connected() {
+        // Accepted right away, without waiting for a connection
+        let start = Instant::now();
+        let resp = roundtrip(
+            "POST /pulse/datapoint/name HTTP/1.1\r\nX-Fleet-ID: ABCDEFGH\r\nX-Device-ID: ABCDEFGHJK\r\nPrefer: respond-async\r\nX-Timeout-Ms: 5000\r\n\r\n",
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(resp.starts_with("HTTP/1.1 202"), "{resp}");
+        assert!(resp.contains("Location: /pulse/status/"), "{resp}");
+        assert!(resp.contains("\"submission_id\""), "{resp}");
+    }
+}
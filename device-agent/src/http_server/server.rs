@@ -2,78 +2,156 @@
 // --- HTTP SERVER ---
 // -------------------
 
-use crate::http_server::{SocketContext, socket::Socket};
+use crate::{
+    http_server::{SocketContext, socket::Socket},
+    systemd,
+};
 use anyhow::Result;
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+};
 use socket2::{Domain, Protocol, Socket as Socket2, Type};
 use std::{
     fs::{self, Permissions},
     io::ErrorKind,
     net::{SocketAddr, TcpListener},
-    os::unix::{fs::PermissionsExt, net::UnixListener},
-    sync::atomic::Ordering,
-    thread,
-    time::Duration,
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::{fs::PermissionsExt, net::UnixListener},
+    },
+    path::{Path, PathBuf},
 };
 
-/// Starts the UNIX Socket Server
-///
-/// Run this function after the /tmp/fostrom directory has been created
-pub fn unix_server(ctx: &SocketContext) -> Result<()> {
-    let socket_path = "/tmp/fostrom/agent.sock";
-    let _ = fs::remove_file(socket_path);
-    let listener = UnixListener::bind(socket_path)?;
-    fs::set_permissions(socket_path, Permissions::from_mode(0o600))?;
-    listener.set_nonblocking(true)?;
+/// Failed to listen on the agent's socket, such as when the address is in use
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to listen on {addr}: {error}")]
+pub struct BindError {
+    addr: String,
+    error: std::io::Error,
+}
 
-    loop {
-        if ctx.shutdown_flag.load(Ordering::SeqCst) {
-            break;
+impl BindError {
+    fn new(addr: impl std::fmt::Display, error: std::io::Error) -> Self {
+        Self {
+            addr: addr.to_string(),
+            error,
         }
+    }
+}
 
-        match listener.accept() {
-            Ok((stream, _addr)) => {
-                Socket::handle_unix_stream(stream, ctx);
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                thread::sleep(Duration::from_millis(50));
+/// The UNIX Socket Server, on the socket passed in by systemd if any.
+/// It is bound before serving, so that a failure is reported at startup,
+/// and the socket file is removed when it's dropped.
+pub struct UnixServer {
+    listener: UnixListener,
+    socket_path: PathBuf,
+    /// The socket passed in by systemd is managed by systemd
+    owned: bool,
+}
+
+impl UnixServer {
+    /// Run this function after the runtime directory has been created
+    pub fn bind(socket_path: &Path) -> Result<Self> {
+        let (listener, owned) = match systemd::take_listener()? {
+            Some(listener) => (listener, false),
+            None => {
+                let _ = fs::remove_file(socket_path);
+                let bind_error = |e| BindError::new(socket_path.display(), e);
+                let listener = UnixListener::bind(socket_path).map_err(bind_error)?;
+                fs::set_permissions(socket_path, Permissions::from_mode(0o600))
+                    .map_err(bind_error)?;
+                (listener, true)
             }
-            Err(e) => {
-                return Err(e.into());
+        };
+
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            socket_path: socket_path.to_path_buf(),
+            owned,
+        })
+    }
+
+    pub fn serve(self, ctx: &SocketContext) -> Result<()> {
+        while wait_for_connection(self.listener.as_fd(), ctx)? {
+            loop {
+                match self.listener.accept() {
+                    Ok((stream, _addr)) => {
+                        Socket::handle_unix_stream(stream, ctx);
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        return Err(e.into());
+                    }
+                }
             }
         }
+
+        Ok(())
+    }
+}
+
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        if self.owned {
+            let _ = fs::remove_file(&self.socket_path);
+        }
     }
+}
 
-    let _ = fs::remove_file(socket_path);
-    Ok(())
+/// The TCP Socket Server
+pub struct TcpServer {
+    listener: TcpListener,
 }
 
-/// Starts the TCP Socket Server
-pub fn tcp_server(ctx: &SocketContext) -> Result<()> {
-    let addr: SocketAddr = "127.0.0.1:8585".parse()?;
-    let socket = Socket2::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-    socket.set_reuse_address(true)?;
-    socket.bind(&addr.into())?;
-    socket.listen(1024)?;
-    let listener: TcpListener = socket.into();
-    listener.set_nonblocking(true)?;
+impl TcpServer {
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        let bind_error = |e| BindError::new(addr, e);
+        let socket = Socket2::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into()).map_err(bind_error)?;
+        socket.listen(1024).map_err(bind_error)?;
+        let listener: TcpListener = socket.into();
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener })
+    }
 
-    loop {
-        if ctx.shutdown_flag.load(Ordering::SeqCst) {
-            break;
+    pub fn serve(self, ctx: &SocketContext) -> Result<()> {
+        while wait_for_connection(self.listener.as_fd(), ctx)? {
+            loop {
+                match self.listener.accept() {
+                    Ok((stream, _addr)) => {
+                        Socket::handle_tcp_stream(stream, ctx);
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        return Err(e.into());
+                    }
+                }
+            }
         }
 
-        match listener.accept() {
-            Ok((stream, _addr)) => {
-                Socket::handle_tcp_stream(stream, ctx);
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                thread::sleep(Duration::from_millis(50));
-            }
-            Err(e) => {
-                return Err(e.into());
-            }
+        Ok(())
+    }
+}
+
+/// Blocks until the listener has connections waiting to be accepted,
+/// or until shutdown is triggered, in which case it returns false.
+fn wait_for_connection(listener: BorrowedFd, ctx: &SocketContext) -> Result<bool> {
+    let mut fds = [
+        PollFd::new(listener, PollFlags::POLLIN),
+        PollFd::new(ctx.shutdown.wake_fd(), PollFlags::POLLIN),
+    ];
+
+    loop {
+        match poll(&mut fds, PollTimeout::NONE) {
+            Ok(_) => break,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
         }
     }
 
-    Ok(())
+    Ok(!ctx.shutdown.is_set())
 }
@@ -2,17 +2,32 @@
 // --- TCP/UNIX SOCKETS ---
 // ------------------------
 
+use crate::http_server::idempotency::IdempotencyStore;
+use crate::http_server::limiter::RateLimiter;
+use crate::http_server::response::FailureResp as FR;
 use crate::http_server::router::handle_request;
+use crate::http_server::submissions::PulseSubmissions;
 use crate::moonlight_codec::MoonlightClient;
 use crate::notifycast::NotifyCast;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use serde_json::Value;
 use std::io::{Read, Result, Write};
+use std::os::fd::{AsFd, BorrowedFd};
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
 use std::thread::spawn;
 use std::{net::TcpStream, os::unix::net::UnixStream, time::Duration};
 
 const RW_TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
 
+/// The most bytes of a rejected request discarded before closing the connection
+const REJECT_DISCARD_LIMIT: usize = 64 * 1024;
+
+/// The maximum number of connections handled at once, across both servers.
+/// Event stream subscribers count towards this limit for as long as they stay connected.
+const MAX_CONNECTIONS: usize = 64;
+
 /// A simple enum to abstract over TCP and UNIX socket streams
 ///
 /// This allows us to write code that can handle both types of streams
@@ -29,7 +44,74 @@ pub enum Socket {
 pub struct SocketContext {
     pub client: MoonlightClient,
     pub notify: NotifyCast,
-    pub shutdown_flag: Arc<AtomicBool>,
+    pub shutdown: Shutdown,
+    pub connections: Arc<AtomicUsize>,
+    pub limiter: Arc<RateLimiter>,
+    pub idempotency: Arc<IdempotencyStore>,
+    pub submissions: Arc<PulseSubmissions>,
+    pub reload: Sender<ReloadRequest>,
+}
+
+/// Asks the agent to read its configuration again,
+/// with the channel to send the result back on.
+pub type ReloadRequest = Sender<std::result::Result<Value, String>>;
+
+/// Signals the servers to shut down.
+///
+/// Along with setting the flag, a byte is written into a socket pair,
+/// which wakes up the accept loops waiting on it in poll().
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    flag: Arc<AtomicBool>,
+    wake: Arc<(UnixStream, UnixStream)>,
+}
+
+impl Shutdown {
+    pub fn new() -> Result<Self> {
+        let (tx, rx) = UnixStream::pair()?;
+        tx.set_nonblocking(true)?;
+
+        Ok(Self {
+            flag: Arc::new(AtomicBool::new(false)),
+            wake: Arc::new((tx, rx)),
+        })
+    }
+
+    pub fn trigger(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+
+        // The byte is never read, so the socket stays readable for every waiter
+        let _ = (&self.wake.0).write(&[1]);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Becomes readable once shutdown has been triggered
+    pub fn wake_fd(&self) -> BorrowedFd<'_> {
+        self.wake.1.as_fd()
+    }
+}
+
+/// Holds a slot in the connection limit, and frees it when dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn acquire(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Socket {
@@ -42,7 +124,8 @@ impl Socket {
         Self::UNIX(stream).handle_request(ctx);
     }
 
-    /// Spawns a new thread to handle the request
+    /// Spawns a new thread to handle the request,
+    /// or answers with a 503 if too many connections are open.
     fn handle_request(self, ctx: &SocketContext) {
         // Set read and write timeouts to avoid hanging connections
         let read_timeout = self.set_read_timeout(RW_TIMEOUT);
@@ -53,8 +136,57 @@ impl Socket {
             return;
         }
 
+        let Some(slot) = ConnectionSlot::acquire(&ctx.connections) else {
+            let mut resp = FR::service_unavailable("Too many open connections", 1);
+            self.reject(resp.compile(&ctx.client).as_bytes());
+            return;
+        };
+
         let ctx = ctx.clone();
-        spawn(move || handle_request(self, &ctx));
+        spawn(move || {
+            handle_request(self, &ctx);
+            drop(slot);
+        });
+    }
+
+    /// Answers and closes the connection on the accept thread, without waiting on the request.
+    ///
+    /// Closing a connection with unread data resets it, which can lose the response
+    /// before the client reads it. So the write side is shut down after the response,
+    /// and only the part of the request that has already arrived is discarded.
+    fn reject(mut self, resp: &[u8]) {
+        if !self.send(resp) {
+            return;
+        }
+
+        let _ = self.shutdown(std::net::Shutdown::Write);
+
+        if self.set_nonblocking(true).is_ok() {
+            let mut buf = [0; 4096];
+            let mut discarded = 0;
+
+            while discarded < REJECT_DISCARD_LIMIT {
+                match self.read(&mut buf) {
+                    Ok(n) if n > 0 => discarded += n,
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    /// Identifies the local client on the other end, for rate limiting.
+    /// UNIX clients are told apart by their process, TCP clients by their address.
+    pub fn peer(&self) -> String {
+        match self {
+            Self::TCP(s) => match s.peer_addr() {
+                Ok(addr) => format!("tcp:{}", addr.ip()),
+                Err(_) => "tcp".to_string(),
+            },
+            Self::UNIX(s) => match getsockopt(s, PeerCredentials) {
+                Ok(cred) => format!("unix:{}:{}", cred.uid(), cred.pid()),
+                Err(_) => "unix".to_string(),
+            },
+        }
     }
 
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
@@ -71,6 +203,20 @@ impl Socket {
         }
     }
 
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            Self::TCP(s) => s.set_nonblocking(nonblocking),
+            Self::UNIX(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn shutdown(&self, how: std::net::Shutdown) -> Result<()> {
+        match self {
+            Self::TCP(s) => s.shutdown(how),
+            Self::UNIX(s) => s.shutdown(how),
+        }
+    }
+
     pub fn write_all(&mut self, buf: &[u8]) -> Result<()> {
         match self {
             Self::TCP(s) => s.write_all(buf),
@@ -114,3 +260,30 @@ impl Write for Socket {
         }
     }
 }
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_reject() {
+        // The client sees the response, whether or not it sent its request
+        for request in [&b"GET /status HTTP/1.1\r\n\r\n"[..], b""] {
+            let (server, mut client) = UnixStream::pair().unwrap();
+            client.write_all(request).unwrap();
+
+            let start = Instant::now();
+            Socket::UNIX(server).reject(b"HTTP/1.1 503 Service Unavailable\r\n\r\n");
+            assert!(start.elapsed() < Duration::from_millis(50));
+
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            assert_eq!(resp, "HTTP/1.1 503 Service Unavailable\r\n\r\n");
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// -------------------------
+// --- PULSE SUBMISSIONS ---
+// -------------------------
+
+use crate::moonlight_codec::ReturnChanResult as R;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long the result of an asynchronous pulse can be looked up
+const SUBMISSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The most submissions tracked at once. Beyond this, the oldest results are forgotten.
+const MAX_SUBMISSIONS: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmissionStatus {
+    Pending,
+    Succeeded,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+struct Submission {
+    status: SubmissionStatus,
+    at: Instant,
+}
+
+/// Tracks pulses sent asynchronously, so that their results can be looked up later
+#[derive(Debug)]
+pub struct PulseSubmissions {
+    submissions: Mutex<HashMap<String, Submission>>,
+}
+
+impl PulseSubmissions {
+    pub fn new() -> Self {
+        Self {
+            submissions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts tracking a new submission, and returns its ID
+    pub fn create(&self) -> String {
+        let mut submissions = self.submissions.lock().unwrap();
+        Self::evict(&mut submissions);
+
+        let id = loop {
+            let id = format!("{:016x}", rand::random::<u64>());
+            if !submissions.contains_key(&id) {
+                break id;
+            }
+        };
+
+        let submission = Submission {
+            status: SubmissionStatus::Pending,
+            at: Instant::now(),
+        };
+
+        submissions.insert(id.clone(), submission);
+        id
+    }
+
+    /// Records the result of a submission, and returns its status
+    pub fn complete(&self, id: &str, result: Option<R>) -> SubmissionStatus {
+        let status = match result {
+            Some(R::Ok) => SubmissionStatus::Succeeded,
+            Some(R::Err(msg) | R::Busy(msg)) => SubmissionStatus::Failed(msg),
+            _ => SubmissionStatus::Failed("timeout: No response was received from Fostrom".into()),
+        };
+
+        let submission = Submission {
+            status: status.clone(),
+            at: Instant::now(),
+        };
+
+        self.submissions
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), submission);
+
+        status
+    }
+
+    pub fn status(&self, id: &str) -> Option<SubmissionStatus> {
+        let submissions = self.submissions.lock().unwrap();
+        submissions.get(id).map(|s| s.status.clone())
+    }
+
+    /// Forgets expired results, and the oldest results beyond MAX_SUBMISSIONS.
+    /// Pending submissions are never forgotten.
+    fn evict(submissions: &mut HashMap<String, Submission>) {
+        submissions.retain(|_, s| {
+            s.status == SubmissionStatus::Pending || s.at.elapsed() < SUBMISSION_TTL
+        });
+
+        if submissions.len() < MAX_SUBMISSIONS {
+            return;
+        }
+
+        let mut done = submissions
+            .iter()
+            .filter(|(_, s)| s.status != SubmissionStatus::Pending)
+            .map(|(id, s)| (s.at, id.clone()))
+            .collect::<Vec<_>>();
+
+        done.sort();
+
+        let excess = submissions.len() + 1 - MAX_SUBMISSIONS;
+        for (_, id) in done.into_iter().take(excess) {
+            submissions.remove(&id);
+        }
+    }
+}
+
+impl SubmissionStatus {
+    /// The JSON body of `GET /pulse/status/<id>` and the `pulse_result` event
+    pub fn to_json(&self, id: &str) -> Value {
+        match self {
+            Self::Pending => json!({"submission_id": id, "status": "pending"}),
+            Self::Succeeded => json!({"submission_id": id, "status": "succeeded"}),
+            Self::Failed(error) => json!({
+                "submission_id": id,
+                "status": "failed",
+                "reason": error.split_once(':').map_or(error.as_str(), |(reason, _)| reason),
+                "error": error,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_submissions() {
+        let submissions = PulseSubmissions::new();
+        let id = submissions.create();
+        assert_eq!(submissions.status(&id), Some(SubmissionStatus::Pending));
+        assert_eq!(submissions.status("unknown"), None);
+
+        let error = "packet_schema_not_found: There is no Packet Schema".to_string();
+        let status = submissions.complete(&id, Some(R::Err(error.clone())));
+        assert_eq!(status, SubmissionStatus::Failed(error.clone()));
+        assert_eq!(submissions.status(&id), Some(status.clone()));
+
+        let json = status.to_json(&id);
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["reason"], "packet_schema_not_found");
+        assert_eq!(json["error"], error);
+
+        let id = submissions.create();
+        submissions.complete(&id, Some(R::Ok));
+        assert_eq!(submissions.status(&id), Some(SubmissionStatus::Succeeded));
+
+        let id = submissions.create();
+        let status = submissions.complete(&id, Some(R::Timeout));
+        assert_eq!(status.to_json(&id)["reason"], "timeout");
+    }
+
+    #[test]
+    fn test_eviction() {
+        let submissions = PulseSubmissions::new();
+        let pending = submissions.create();
+
+        for _ in 0..MAX_SUBMISSIONS {
+            let id = submissions.create();
+            submissions.complete(&id, Some(R::Ok));
+        }
+
+        assert!(submissions.submissions.lock().unwrap().len() <= MAX_SUBMISSIONS);
+        assert_eq!(
+            submissions.status(&pending),
+            Some(SubmissionStatus::Pending)
+        );
+    }
+}
@@ -0,0 +1,26 @@
+// -----------------
+// --- LOG LEVEL ---
+// -----------------
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use strum::{Display, EnumString};
+
+/// How much the agent writes to its logs, set with `--log-level`
+#[derive(Display, EnumString, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[strum(serialize_all = "lowercase")]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
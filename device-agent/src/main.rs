@@ -1,8 +1,11 @@
 mod cli;
+mod diagnostics;
 mod http_server;
+mod log;
 mod moonlight_codec;
 mod moonlight_socket;
 mod notifycast;
+mod systemd;
 
 fn main() {
     cli::exec();
@@ -13,22 +13,34 @@
 /// * Each packet's second byte is always a `flags` byte, which for now is mostly zero
 ///   in most packets. However, each flag represents something different for each packet.
 ///
-/// * That being said, **the high-watermark of the byte (the first bit)
-///   is reserved for future use**. The specific use of that bit is to represent
-///   a second byte of flags if needed, just like how variable-length encoding
-///   works in protocols such as MQTT.
+/// * That being said, **the high-watermark of the byte (the first bit)**
+///   signals that another flags byte follows, just like how variable-length
+///   encoding works in protocols such as MQTT. The Codec reads and writes these
+///   extended flags bytes for every packet, so flags added later do not break
+///   older decoders. See the `Flags` struct.
 ///
-/// * The **second high-watermark bit** is reserved for future use,
-///   to indicate if the request is for a virtual device instead of the current device.
+/// * The **second high-watermark bit** indicates that the packet is for a virtual
+///   child device instead of the current device. This lets a gateway send pulses
+///   and receive mail on behalf of devices that cannot run an agent. When set,
+///   the 10-byte Device ID of the child device follows the flags byte. It is used
+///   by PULSE, PULSE_BATCH, MAILBOX_NEXT, ACK_MAIL and NEW_MAIL_EVENT packets, and only once
+///   the server has accepted the `virtual_devices` capability.
 ///
 /// * If a packet needs to indicate success, that should be the low-watermark flag
 ///   (last bit) of the flags byte. This should be 1 in case of success and 0 in
 ///   case of failure.
+///
+/// * The CONNECT packet's `protocol_version` is the highest version the client speaks,
+///   and its flags byte carries the client's capability flags. A server that supports
+///   negotiation sets the `negotiated` flag in CONNECTED and appends the selected
+///   protocol version and capabilities. Older servers reply without that flag,
+///   and the session falls back to protocol version 1 without any capabilities.
 //
 //
 // ---------------
 // --- IMPORTS ---
 // ---------------
+use crate::log::{self, Level};
 use anyhow::{Result, anyhow};
 use deku::prelude::*;
 use serde::Serialize;
@@ -128,9 +140,22 @@ pub enum DisconnectedReason {
     Unauthorized(#[from] UnauthorizedError),
     #[error(transparent)]
     ConnectFailed(#[from] ConnectFailedError),
+    #[error(transparent)]
+    FrameLimitExceeded(#[from] FrameLimitError),
+    #[error("disconnect: Reconnecting with the reloaded configuration")]
+    Reconfigured,
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameLimitError {
+    #[error("frame_too_large: Received a {size} byte frame, exceeding the limit of {limit} bytes.")]
+    FrameTooLarge { size: usize, limit: usize },
+
+    #[error("buffer_overflow: Buffered {size} bytes, exceeding the limit of {limit} bytes.")]
+    BufferOverflow { size: usize, limit: usize },
 }
 
-#[derive(Error, Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[derive(Error, EnumIter, Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
 #[deku(id_type = "u8", ctx = "endian: deku::ctx::Endian")]
 pub enum PulseErrorReason {
     #[deku(id = 0)]
@@ -189,6 +214,37 @@ pub enum GeneralErrors {
 
     #[error("duplicate_request: A request with the same transaction ID has already been queued.")]
     DuplicateReq,
+
+    #[error(
+        "virtual_devices_unsupported: The server has not enabled virtual devices for this connection."
+    )]
+    VirtualDevicesUnsupported,
+
+    #[error("batch_unsupported: The server has not enabled batched pulses for this connection.")]
+    BatchUnsupported,
+
+    #[error(
+        "too_many_pending_requests: Too many requests are waiting for a response from Fostrom."
+    )]
+    TooManyPendingTxns,
+
+    #[error("shutting_down: The Device Agent is shutting down.")]
+    ShuttingDown,
+
+    #[error(
+        "pulse_outcome_unknown: The connection dropped before Fostrom answered, so the pulse may or may not have been recorded."
+    )]
+    PulseOutcomeUnknown,
+
+    #[error(
+        "reconfigured: The device's credentials or endpoint changed before the request was answered."
+    )]
+    Reconfigured,
+
+    #[error(
+        "not_connected: The request ran out of time before the Device Agent could reach Fostrom."
+    )]
+    NotConnected,
 }
 
 // -------------------
@@ -203,6 +259,87 @@ pub struct Mail {
     pub mailbox_size: u16,
 }
 
+// -------------------
+// --- NEGOTIATION ---
+// -------------------
+
+/// The highest Moonlight protocol version this agent speaks
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Capability flags, advertised by the client in the CONNECT packet's flags byte
+/// and selected by the server in the CONNECTED packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    pub const EXTENDED_FLAGS: Self = Self(0b0000_0001);
+    pub const BATCH: Self = Self(0b0000_0010);
+    pub const COMPRESSION: Self = Self(0b0000_0100);
+    pub const VIRTUAL_DEVICES: Self = Self(0b0000_1000);
+
+    /// Capabilities implemented by this agent
+    pub const SUPPORTED: Self =
+        Self(Self::EXTENDED_FLAGS.0 | Self::BATCH.0 | Self::VIRTUAL_DEVICES.0);
+
+    /// Only five bits fit between the reserved bits and `keep_alive`
+    /// in the CONNECT flags byte.
+    const MASK: u8 = 0b0001_1111;
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits & Self::MASK)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (Self::EXTENDED_FLAGS, "extended_flags"),
+            (Self::BATCH, "batch"),
+            (Self::COMPRESSION, "compression"),
+            (Self::VIRTUAL_DEVICES, "virtual_devices"),
+        ]
+        .into_iter()
+        .filter(|(cap, _)| cap.0 != 0 && self.contains(*cap))
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+/// The protocol version and capabilities in effect for a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Negotiated {
+    pub protocol_version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl Negotiated {
+    /// What a session runs with when the server does not negotiate
+    pub const LEGACY: Self = Self {
+        protocol_version: 1,
+        capabilities: Capabilities::NONE,
+    };
+
+    /// Clamp the server's selection to what this agent advertised
+    fn select(protocol_version: u8, capabilities: u8) -> Self {
+        Self {
+            protocol_version: protocol_version.clamp(1, PROTOCOL_VERSION),
+            capabilities: Capabilities::SUPPORTED
+                .intersection(Capabilities::from_bits(capabilities)),
+        }
+    }
+}
+
 // -------------
 // --- CREDS ---
 // -------------
@@ -241,6 +378,30 @@ pub enum CredErr {
     DeviceSecretInvalid,
 }
 
+// ----------------
+// --- CHILD ID ---
+// ----------------
+
+/// The Device ID of a virtual child device,
+/// on whose behalf a gateway sends pulses and receives mail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChildId([u8; 10]);
+
+impl ChildId {
+    pub fn new(device_id: &str) -> Result<Self, CredErr> {
+        Creds::validate_device_id(device_id)?;
+        let mut id = [0u8; 10];
+        id.copy_from_slice(device_id.as_bytes());
+        Ok(Self(id))
+    }
+}
+
+impl std::fmt::Display for ChildId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Creds {
     pub fleet_id: String,
@@ -267,13 +428,13 @@ impl Creds {
         Ok(creds)
     }
 
+    /// A hash of the credentials, to compare them without storing the secret.
+    /// The endpoint is compared separately.
     pub fn hash(&self) -> String {
         let mut hasher = Sha256::new();
         hasher.update(self.fleet_id.as_bytes());
         hasher.update(self.device_id.as_bytes());
         hasher.update(self.device_secret.as_bytes());
-        hasher.update(self.prod.to_string().as_bytes());
-        hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
         hex::encode(hasher.finalize())
     }
 
@@ -333,7 +494,10 @@ pub enum MoonlightPacket {
 
     #[deku(id = "2")]
     Connect {
-        #[deku(bits = "1", pad_bits_before = "7")]
+        #[deku(bits = "5", pad_bits_before = "2")]
+        capabilities: u8,
+
+        #[deku(bits = "1")]
         keep_alive: bool,
 
         protocol_version: u8,
@@ -352,11 +516,20 @@ pub enum MoonlightPacket {
 
     #[deku(id = "3")]
     Connected {
-        #[deku(bits = "1", pad_bits_before = "6")]
+        #[deku(bits = "1", pad_bits_before = "5")]
+        negotiated: bool,
+
+        #[deku(bits = "1")]
         mail_available: bool,
 
         #[deku(bits = "1")]
         keep_alive: bool,
+
+        #[deku(cond = "*negotiated")]
+        protocol_version: Option<u8>,
+
+        #[deku(cond = "*negotiated")]
+        capabilities: Option<u8>,
     },
 
     #[deku(id = "4")]
@@ -382,7 +555,12 @@ pub enum MoonlightPacket {
 
     #[deku(id = "10")]
     Pulse {
-        #[deku(pad_bytes_before = "1")]
+        #[deku(bits = "1", pad_bits_before = "1", pad_bits_after = "6")]
+        virtual_device: bool,
+
+        #[deku(cond = "*virtual_device")]
+        child_id: Option<[u8; 10]>,
+
         pulse_type: PulseType,
 
         txn_id: u64,
@@ -405,13 +583,54 @@ pub enum MoonlightPacket {
         error_reason: Option<PulseErrorReason>,
     },
 
+    /// Many pulses under a single transaction.
+    /// Only sent once the server has accepted the `batch` capability.
+    #[deku(id = "12")]
+    PulseBatch {
+        #[deku(bits = "1", pad_bits_before = "1", pad_bits_after = "6")]
+        virtual_device: bool,
+
+        #[deku(cond = "*virtual_device")]
+        child_id: Option<[u8; 10]>,
+
+        txn_id: u64,
+
+        count: u16,
+        #[deku(count = "count")]
+        pulses: Vec<BatchPulse>,
+    },
+
+    /// The result of each pulse in a PULSE_BATCH, in the same order
+    #[deku(id = "13")]
+    PulseBatchResp {
+        #[deku(pad_bytes_before = "1")]
+        txn_id: u64,
+
+        count: u16,
+        #[deku(count = "count")]
+        results: Vec<BatchPulseResult>,
+    },
+
     #[deku(id = "20")]
-    NewMailEvent(u8),
+    NewMailEvent {
+        #[deku(bits = "1", pad_bits_before = "1", pad_bits_after = "6")]
+        virtual_device: bool,
+
+        #[deku(cond = "*virtual_device")]
+        child_id: Option<[u8; 10]>,
+    },
 
     #[deku(id = "21")]
     MailboxNext {
-        #[deku(bits = "1", pad_bits_before = "7")]
+        #[deku(bits = "1", pad_bits_before = "1")]
+        virtual_device: bool,
+
+        #[deku(bits = "1", pad_bits_before = "5")]
         header_only: bool,
+
+        #[deku(cond = "*virtual_device")]
+        child_id: Option<[u8; 10]>,
+
         txn_id: u64,
     },
 
@@ -449,7 +668,12 @@ pub enum MoonlightPacket {
 
     #[deku(id = "25")]
     AckMail {
-        #[deku(pad_bytes_before = "1")]
+        #[deku(bits = "1", pad_bits_before = "1", pad_bits_after = "6")]
+        virtual_device: bool,
+
+        #[deku(cond = "*virtual_device")]
+        child_id: Option<[u8; 10]>,
+
         pulse_id: u128,
         ack_type: MailAckType,
     },
@@ -464,6 +688,71 @@ pub enum MoonlightPacket {
     },
 }
 
+/// A single pulse within a PULSE_BATCH packet
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(ctx = "endian: deku::ctx::Endian", endian = "endian")]
+pub struct BatchPulse {
+    pulse_type: PulseType,
+
+    name_len: u8,
+    #[deku(count = "name_len")]
+    name: Vec<u8>,
+
+    payload_len: u32,
+    #[deku(count = "payload_len")]
+    payload: Vec<u8>,
+}
+
+impl BatchPulse {
+    pub fn new(pulse_type: PulseType, name: String, payload: String) -> Self {
+        if name.len() > 255 {
+            panic!("Pulse name cannot be more than 255 characters");
+        }
+
+        Self {
+            pulse_type,
+            name_len: name.len() as u8,
+            payload_len: payload.len() as u32,
+            name: name.into_bytes(),
+            payload: payload.into_bytes(),
+        }
+    }
+}
+
+/// The result of a single pulse within a PULSE_BATCH_RESP packet
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(ctx = "endian: deku::ctx::Endian", endian = "endian")]
+pub struct BatchPulseResult {
+    #[deku(bits = "1", pad_bits_before = "7")]
+    successful: bool,
+
+    #[deku(cond = "!*successful")]
+    error_reason: Option<PulseErrorReason>,
+}
+
+impl BatchPulseResult {
+    pub fn new(result: Result<(), PulseErrorReason>) -> Self {
+        match result {
+            Ok(()) => Self {
+                successful: true,
+                error_reason: None,
+            },
+            Err(reason) => Self {
+                successful: false,
+                error_reason: Some(reason),
+            },
+        }
+    }
+
+    pub fn result(&self) -> Result<(), PulseErrorReason> {
+        if self.successful {
+            Ok(())
+        } else {
+            Err(self.error_reason.unwrap_or(PulseErrorReason::Unknown))
+        }
+    }
+}
+
 // ---------------------------
 // --- PACKET CONSTRUCTORS ---
 // ---------------------------
@@ -477,12 +766,33 @@ impl MoonlightPacket {
         device_id: String,
         device_secret: String,
         prod: bool,
+    ) -> Result<(Self, Creds), CredErr> {
+        Self::connect_with_capabilities(
+            fleet_id,
+            device_id,
+            device_secret,
+            prod,
+            1,
+            Capabilities::NONE,
+        )
+    }
+
+    /// Same as connect(), but advertises the given protocol version
+    /// as the highest the client speaks, along with its capabilities.
+    pub fn connect_with_capabilities(
+        fleet_id: String,
+        device_id: String,
+        device_secret: String,
+        prod: bool,
+        protocol_version: u8,
+        capabilities: Capabilities,
     ) -> Result<(Self, Creds), CredErr> {
         let creds = Creds::new(&fleet_id, &device_id, &device_secret, prod)?;
 
         let connect_packet = Self::Connect {
+            capabilities: capabilities.bits(),
             keep_alive: true,
-            protocol_version: 1,
+            protocol_version,
             serialization_format: SerializationFormat::JSON,
             fleet_id: fleet_id.as_bytes().to_vec(),
             device_id: device_id.as_bytes().to_vec(),
@@ -502,8 +812,26 @@ impl MoonlightPacket {
 
     pub fn connected(mail_available: bool, keep_alive: bool) -> Self {
         Self::Connected {
+            negotiated: false,
             mail_available,
             keep_alive,
+            protocol_version: None,
+            capabilities: None,
+        }
+    }
+
+    pub fn connected_negotiated(
+        mail_available: bool,
+        keep_alive: bool,
+        protocol_version: u8,
+        capabilities: Capabilities,
+    ) -> Self {
+        Self::Connected {
+            negotiated: true,
+            mail_available,
+            keep_alive,
+            protocol_version: Some(protocol_version),
+            capabilities: Some(capabilities.bits()),
         }
     }
 
@@ -529,12 +857,14 @@ impl MoonlightPacket {
         }
 
         Self::Pulse {
+            virtual_device: false,
+            child_id: None,
             pulse_type,
             txn_id,
             name_len: name.len() as u8,
             payload_len: payload.len() as u32,
-            name: name.as_bytes().to_vec(),
-            payload: payload.as_bytes().to_vec(),
+            name: name.into_bytes(),
+            payload: payload.into_bytes(),
         }
     }
 
@@ -554,13 +884,40 @@ impl MoonlightPacket {
         }
     }
 
+    pub fn pulse_batch(txn_id: u64, pulses: Vec<BatchPulse>) -> Self {
+        if pulses.len() > u16::MAX as usize {
+            panic!("A pulse batch cannot have more than 65535 pulses");
+        }
+
+        Self::PulseBatch {
+            virtual_device: false,
+            child_id: None,
+            txn_id,
+            count: pulses.len() as u16,
+            pulses,
+        }
+    }
+
+    pub fn pulse_batch_resp(txn_id: u64, results: Vec<Result<(), PulseErrorReason>>) -> Self {
+        Self::PulseBatchResp {
+            txn_id,
+            count: results.len() as u16,
+            results: results.into_iter().map(BatchPulseResult::new).collect(),
+        }
+    }
+
     pub fn new_mail_event() -> Self {
-        Self::NewMailEvent(0)
+        Self::NewMailEvent {
+            virtual_device: false,
+            child_id: None,
+        }
     }
 
     pub fn mailbox_next(header_only: bool, txn_id: u64) -> Self {
         Self::MailboxNext {
+            virtual_device: false,
             header_only,
+            child_id: None,
             txn_id,
         }
     }
@@ -606,7 +963,7 @@ impl MoonlightPacket {
             mailbox_size,
             pulse_id: Some(pulse_id),
             name_len: Some(name.len() as u8),
-            name: Some(name.as_bytes().to_vec()),
+            name: Some(name.into_bytes()),
             payload_len: None,
             payload: None,
         }
@@ -630,14 +987,19 @@ impl MoonlightPacket {
             mailbox_size,
             pulse_id: Some(pulse_id),
             name_len: Some(name.len() as u8),
-            name: Some(name.as_bytes().to_vec()),
+            name: Some(name.into_bytes()),
             payload_len: Some(payload.len() as u32),
-            payload: Some(payload.as_bytes().to_vec()),
+            payload: Some(payload.into_bytes()),
         }
     }
 
     pub fn ack_mail(pulse_id: u128, ack_type: MailAckType) -> Self {
-        Self::AckMail { pulse_id, ack_type }
+        Self::AckMail {
+            virtual_device: false,
+            child_id: None,
+            pulse_id,
+            ack_type,
+        }
     }
 
     pub fn ack_mail_resp(mailbox_size: u16, pulse_id: u128, ack_type: MailAckType) -> Self {
@@ -657,57 +1019,366 @@ impl MoonlightPacket {
             ack_type,
         }
     }
+
+    /// Address the packet to a virtual child device instead of the current device.
+    /// Packets that cannot be addressed to a child device are returned unchanged.
+    pub fn for_child(mut self, child: Option<ChildId>) -> Self {
+        if let Some(ChildId(id)) = child {
+            match &mut self {
+                Self::Pulse {
+                    virtual_device,
+                    child_id,
+                    ..
+                }
+                | Self::PulseBatch {
+                    virtual_device,
+                    child_id,
+                    ..
+                }
+                | Self::NewMailEvent {
+                    virtual_device,
+                    child_id,
+                }
+                | Self::MailboxNext {
+                    virtual_device,
+                    child_id,
+                    ..
+                }
+                | Self::AckMail {
+                    virtual_device,
+                    child_id,
+                    ..
+                } => {
+                    *virtual_device = true;
+                    *child_id = Some(id);
+                }
+                _ => (),
+            }
+        }
+
+        self
+    }
+}
+
+// -------------
+// --- FLAGS ---
+// -------------
+
+/// The maximum number of flags bytes in a packet, including the first one
+const MAX_FLAGS_BYTES: usize = 8;
+
+/// The flags of a packet, with the continuation bits stripped.
+///
+/// The low seven bits of each flags byte carry flags, and the high bit signals
+/// that another flags byte follows. Every MoonlightPacket variant lays out the
+/// first flags byte on its own, while the extended flags bytes after it are read
+/// and written by the Codec. A flag that this agent does not know about is kept
+/// here instead of failing the decode.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Flags(Vec<u8>);
+
+impl Flags {
+    const CONTINUATION: u8 = 0b1000_0000;
+
+    fn is_extended(&self) -> bool {
+        self.0.len() > 1
+    }
+
+    /// The flags bytes after the first one
+    fn extended(&self) -> &[u8] {
+        self.0.get(1..).unwrap_or_default()
+    }
+
+    /// Reads the flags bytes that follow the packet type byte.
+    /// Returns the flags and the number of flags bytes read,
+    /// or None if the bytes end before the last flags byte.
+    fn read(bytes: &[u8]) -> Result<Option<(Self, usize)>> {
+        let mut flags = Vec::new();
+
+        for &byte in bytes.iter().skip(1) {
+            if flags.len() == MAX_FLAGS_BYTES {
+                return Err(anyhow!("Failed to decode packet: Too many flags bytes"));
+            }
+
+            flags.push(byte & !Self::CONTINUATION);
+
+            if byte & Self::CONTINUATION == 0 {
+                let len = flags.len();
+                return Ok(Some((Self(flags), len)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Writes the extended flags bytes into an encoded packet.
+    /// The first flags byte always comes from the packet itself.
+    fn write_extended(&self, bytes: &mut Vec<u8>) {
+        if !self.is_extended() || bytes.len() < 2 {
+            return;
+        }
+
+        bytes[1] |= Self::CONTINUATION;
+
+        let last = self.0.len() - 1;
+        let extended = self.0.iter().enumerate().skip(1).map(|(i, byte)| {
+            let byte = byte & !Self::CONTINUATION;
+            if i < last {
+                byte | Self::CONTINUATION
+            } else {
+                byte
+            }
+        });
+
+        bytes.splice(2..2, extended);
+    }
+}
+
+/// A decoded packet, along with all of its flags
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub packet: MoonlightPacket,
+    pub flags: Flags,
+}
+
+impl From<MoonlightPacket> for Frame {
+    fn from(packet: MoonlightPacket) -> Self {
+        Self {
+            packet,
+            flags: Flags::default(),
+        }
+    }
 }
 
 // -------------
 // --- CODEC ---
 // -------------
 
+/// Limits on how much the decoder buffers before giving up on the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CodecLimits {
+    /// The largest single packet accepted, in bytes
+    pub max_frame_size: usize,
+
+    /// The most bytes held in the buffer at once, in bytes
+    pub max_buffer_size: usize,
+}
+
+impl Default for CodecLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 4 * 1024 * 1024,
+            max_buffer_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl CodecLimits {
+    /// Returns the total length of a frame as declared by its length prefixes,
+    /// so that oversized frames are rejected before their payload is buffered.
+    /// Returns None for fixed-size packets, or if the prefixes are incomplete.
+    /// Expects the extended flags bytes to have been cut out already.
+    fn declared_frame_len(bytes: &[u8]) -> Option<usize> {
+        let flags = *bytes.get(1)?;
+        let read_u8 = |at: usize| bytes.get(at).map(|b| *b as usize);
+        let read_u32 = |at: usize| {
+            let b = bytes.get(at..at + 4)?;
+            Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        };
+
+        match bytes[0] {
+            // PULSE: [child_id], pulse_type, txn_id, name_len, name, payload_len, payload
+            10 => {
+                let child_id_len = if flags & 0b0100_0000 != 0 { 10 } else { 0 };
+                let name_len_at = 2 + child_id_len + 1 + 8;
+                let payload_len_at = name_len_at + 1 + read_u8(name_len_at)?;
+                Some(payload_len_at + 4 + read_u32(payload_len_at)?)
+            }
+
+            // MAILBOX_NEXT_RESP: txn_id, mailbox_size, pulse_id, name_len, name, payload_len, payload
+            22 => {
+                let header_only = flags & 0b0000_0010 != 0;
+                let successful = flags & 0b0000_0001 != 0;
+                let mailbox_size = bytes.get(10..12)?;
+
+                if !successful || mailbox_size == [0, 0] {
+                    return None;
+                }
+
+                let name_len_at = 2 + 8 + 2 + 16;
+                let payload_len_at = name_len_at + 1 + read_u8(name_len_at)?;
+
+                if header_only {
+                    Some(payload_len_at)
+                } else {
+                    Some(payload_len_at + 4 + read_u32(payload_len_at)?)
+                }
+            }
+
+            _ => None,
+        }
+    }
+}
+
 /// Implements the encoder and streaming decoder
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Codec {
     buffer: Vec<u8>,
+    limits: CodecLimits,
 }
 
 impl Codec {
-    pub fn new() -> Self {
+    const INITIAL_CAPACITY: usize = 64 * 1024;
+
+    pub fn new(limits: CodecLimits) -> Self {
         Self {
-            buffer: Vec::with_capacity(1024 * 1024),
+            buffer: Vec::with_capacity(limits.max_buffer_size.min(Self::INITIAL_CAPACITY)),
+            limits,
         }
     }
 
-    pub fn feed(&mut self, data: &[u8]) {
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), FrameLimitError> {
+        let size = self.buffer.len() + data.len();
+        let limit = self.limits.max_buffer_size;
+
+        if size > limit {
+            return Err(FrameLimitError::BufferOverflow { size, limit });
+        }
+
         self.buffer.extend_from_slice(data);
+        Ok(())
     }
 
     /// Encodes a single packet
     pub fn encode(packet: &MoonlightPacket) -> Result<Vec<u8>> {
-        packet
+        Self::encode_with_flags(packet, &Flags::default())
+    }
+
+    /// Encodes a single packet along with extended flags
+    pub fn encode_with_flags(packet: &MoonlightPacket, flags: &Flags) -> Result<Vec<u8>> {
+        let mut bytes = packet
             .to_bytes()
-            .map_err(|e| anyhow!("Invalid Packet: {}", e))
+            .map_err(|e| anyhow!("Invalid Packet: {}", e))?;
+
+        flags.write_extended(&mut bytes);
+        Ok(bytes)
     }
 
-    /// Decodes a single packet
+    /// Decodes a single packet, dropping its extended flags
+    #[cfg(test)]
     fn decode(bytes: &[u8]) -> Result<Option<(MoonlightPacket, usize)>> {
-        match MoonlightPacket::from_bytes((bytes, 0)) {
-            Ok(((rest, _bit_offset), packet)) => {
-                let consumed = bytes.len() - rest.len();
-                Ok(Some((packet, consumed)))
+        let decoded = Self::decode_frame(bytes, CodecLimits::default())?;
+        Ok(decoded.map(|(frame, consumed)| (frame.packet, consumed)))
+    }
+
+    /// How many bytes of an extended frame are copied at first,
+    /// which covers the length prefixes of every packet type
+    const HEADER_PEEK: usize = 512;
+
+    /// Decodes a single packet along with its flags
+    fn decode_frame(bytes: &[u8], limits: CodecLimits) -> Result<Option<(Frame, usize)>> {
+        let Some((flags, flags_len)) = Flags::read(bytes)? else {
+            return Ok(None);
+        };
+
+        let limit = limits.max_frame_size;
+        let extended = flags.is_extended();
+        let extended_len = flags_len - 1;
+
+        // Packet layouts only know about the first flags byte,
+        // so the extended flags bytes are cut out of a copy of
+        // the packet's first `len` bytes before decoding.
+        let (packet_type, first_flags) = (bytes[0], flags.0[0]);
+        let body = &bytes[1 + flags_len..];
+        let available = 2 + body.len();
+        let normalized = |len: usize| {
+            let body = &body[..len.saturating_sub(2).min(body.len())];
+            [&[packet_type, first_flags], body].concat()
+        };
+
+        let declared_len = if extended {
+            CodecLimits::declared_frame_len(&normalized(Self::HEADER_PEEK))
+        } else {
+            CodecLimits::declared_frame_len(bytes)
+        };
+
+        if let Some(len) = declared_len {
+            let size = len + extended_len;
+            if size > limit {
+                return Err(FrameLimitError::FrameTooLarge { size, limit }.into());
+            }
+
+            // The rest of the frame hasn't arrived yet
+            if len > available {
+                return Ok(None);
+            }
+        }
+
+        // Only one frame's worth of bytes is copied. Without a declared
+        // length, the copy starts small and doubles while it's incomplete.
+        let max_len = available.min(limit + 2);
+        let mut len = declared_len.unwrap_or(Self::HEADER_PEEK);
+
+        loop {
+            let owned: Vec<u8>;
+            let packet_bytes = if extended {
+                owned = normalized(len);
+                &owned[..]
+            } else {
+                bytes
+            };
+
+            match MoonlightPacket::from_bytes((packet_bytes, 0)) {
+                Ok(((rest, _bit_offset), packet)) => {
+                    // Counted within the packet bytes, as `rest` may be cut short
+                    let consumed = packet_bytes.len() - rest.len() + extended_len;
+                    return Ok(Some((Frame { packet, flags }, consumed)));
+                }
+                Err(DekuError::Incomplete(_)) if packet_bytes.len() < max_len => {
+                    len = len.saturating_mul(2);
+                }
+                Err(DekuError::Incomplete(_)) if bytes.len() > limit => {
+                    let size = bytes.len();
+                    return Err(FrameLimitError::FrameTooLarge { size, limit }.into());
+                }
+                Err(DekuError::Incomplete(_)) => return Ok(None),
+                Err(e) => return Err(anyhow!("Failed to decode packet: {}", e)),
             }
-            Err(DekuError::Incomplete(_)) => Ok(None),
-            Err(e) => Err(anyhow!("Failed to decode packet: {}", e)),
         }
     }
 
-    fn process_packets(&mut self) -> Result<Vec<MoonlightPacket>> {
-        let mut packets = Vec::new();
+    /// Decodes all complete frames in the buffer.
+    ///
+    /// Frames are decoded behind a read cursor, and the buffer is compacted once
+    /// at the end, so that a burst of small packets doesn't shift the remaining
+    /// bytes after every single packet.
+    pub fn process_packets(&mut self) -> Result<Vec<Frame>> {
+        let mut frames = Vec::new();
+        let mut cursor = 0;
 
-        while let Some((packet, consumed)) = Codec::decode(&self.buffer)? {
-            packets.push(packet);
-            self.buffer.drain(..consumed);
+        while let Some((frame, consumed)) =
+            Codec::decode_frame(&self.buffer[cursor..], self.limits)?
+        {
+            frames.push(frame);
+            cursor += consumed;
         }
 
-        Ok(packets)
+        self.compact(cursor);
+        Ok(frames)
+    }
+
+    /// Drops the decoded bytes before the cursor, and releases the memory
+    /// held on to after a large frame once the buffer is empty again.
+    fn compact(&mut self, cursor: usize) {
+        if cursor == self.buffer.len() {
+            self.buffer.clear();
+        } else if cursor > 0 {
+            self.buffer.drain(..cursor);
+        }
+
+        if self.buffer.is_empty() && self.buffer.capacity() > Self::INITIAL_CAPACITY {
+            self.buffer.shrink_to(Self::INITIAL_CAPACITY);
+        }
     }
 }
 
@@ -725,19 +1396,28 @@ pub enum ServerResp {
     ForceCloseSocket,
 
     // Events
-    Connected(bool),
+    Connected(bool, Negotiated),
     Disconnected(DisconnectedReason),
     HeartbeatAck,
-    NewMail,
+    NewMail(Option<ChildId>),
 
     // Transactions
     PulseResp(Result<u64, (u64, PulseErrorReason)>),
+    PulseBatchResp(u64, Vec<Result<(), PulseErrorReason>>),
     AckMailResp(Result<(u128, bool), (u128, MailAckType)>),
     MailboxNext(Result<(u64, Option<Mail>), u64>),
 }
 
 impl ServerResp {
-    fn handle_packet(packet: P) -> ServerResp {
+    pub fn handle_packet(frame: Frame) -> ServerResp {
+        let Frame { packet, flags } = frame;
+
+        // No extended flag is defined yet, so any that are set
+        // belong to a newer protocol and don't change the packet
+        if flags.is_extended() && log::enabled(Level::Debug) {
+            println!("codec: unknown extended flags={:?}", flags.extended());
+        }
+
         match packet {
             P::CloseConnection { server: true } => {
                 ServerResp::Disconnected(DisconnectedReason::NormalDisconnect)
@@ -751,7 +1431,20 @@ impl ServerResp {
                 ServerResp::Disconnected(DisconnectedReason::Unauthorized(reason))
             }
 
-            P::Connected { mail_available, .. } => ServerResp::Connected(mail_available),
+            P::Connected {
+                negotiated: true,
+                mail_available,
+                protocol_version: Some(protocol_version),
+                capabilities: Some(capabilities),
+                ..
+            } => ServerResp::Connected(
+                mail_available,
+                Negotiated::select(protocol_version, capabilities),
+            ),
+
+            P::Connected { mail_available, .. } => {
+                ServerResp::Connected(mail_available, Negotiated::LEGACY)
+            }
 
             P::HeartbeatAck { .. } => ServerResp::HeartbeatAck,
 
@@ -771,6 +1464,13 @@ impl ServerResp {
                 ServerResp::PulseResp(r)
             }
 
+            P::PulseBatchResp {
+                txn_id, results, ..
+            } => ServerResp::PulseBatchResp(
+                txn_id,
+                results.iter().map(BatchPulseResult::result).collect(),
+            ),
+
             P::AckMailResp {
                 successful,
                 mailbox_size,
@@ -823,10 +1523,7 @@ impl ServerResp {
                         ServerResp::MailboxNext(Ok((txn_id, Some(mail))))
                     } else {
                         mail.payload = match payload {
-                            Some(pl) => match String::from_utf8(pl) {
-                                Ok(pl) => serde_json::from_str(&pl).unwrap_or_default(),
-                                _ => None,
-                            },
+                            Some(pl) => serde_json::from_slice(&pl).unwrap_or_default(),
                             None => None,
                         };
 
@@ -839,7 +1536,15 @@ impl ServerResp {
                 }
             }
 
-            P::NewMailEvent(0) => ServerResp::NewMail,
+            P::NewMailEvent {
+                virtual_device: false,
+                ..
+            } => ServerResp::NewMail(None),
+
+            P::NewMailEvent {
+                virtual_device: true,
+                child_id: Some(id),
+            } => ServerResp::NewMail(Some(ChildId(id))),
 
             _ => ServerResp::ForceCloseSocket,
         }
@@ -850,8 +1555,11 @@ impl ServerResp {
 // --- CLIENT LOGIC ---
 // --------------------
 
+use crate::diagnostics::Diagnostics;
 use std::collections::HashMap;
+use std::mem::take;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc::{Receiver, Sender, channel};
 use std::thread::sleep;
@@ -864,8 +1572,14 @@ pub enum ReturnChanResult {
     Timeout,
     Mail(Option<Mail>),
 
+    /// Too many requests are awaiting a response, try again shortly
+    Busy(String),
+
     /// the bool is more-mail-available
     MailAckSuccessful(bool),
+
+    /// The result of each pulse in a batch, in the same order
+    BatchResults(Vec<Result<(), PulseErrorReason>>),
 }
 
 use ReturnChanResult as R;
@@ -875,17 +1589,152 @@ use ReturnChanResult as R;
 /// For any other operation, the Option is always None when successful.
 type ReturnChan = Sender<ReturnChanResult>;
 
-/// Commands that users of the client can send
+/// Commands that users of the client can send.
+/// The ChildId is set when the command is for a virtual child device.
 #[derive(Debug, Clone)]
 pub enum ClientCmd {
-    /// SendPulse(PulseType, name, payload)
-    SendPulse(PulseType, String, Option<Value>, ReturnChan),
+    /// SendPulse(child, PulseType, name, payload)
+    SendPulse(
+        Option<ChildId>,
+        PulseType,
+        String,
+        Option<Value>,
+        ReturnChan,
+    ),
+
+    /// SendPulseBatch(child, [(PulseType, name, payload)])
+    SendPulseBatch(
+        Option<ChildId>,
+        Vec<(PulseType, String, Option<Value>)>,
+        ReturnChan,
+    ),
+
+    /// MailboxNext(child, header_only?)
+    MailboxNext(Option<ChildId>, bool, ReturnChan),
+
+    /// MailOp(child, MailAckType, mail_id)
+    MailOp(Option<ChildId>, MailAckType, u128, ReturnChan),
+}
 
-    /// MailboxNext(header_only?)
-    MailboxNext(bool, ReturnChan),
+impl ClientCmd {
+    fn child(&self) -> Option<ChildId> {
+        match self {
+            Self::SendPulse(child, ..)
+            | Self::SendPulseBatch(child, ..)
+            | Self::MailboxNext(child, ..)
+            | Self::MailOp(child, ..) => *child,
+        }
+    }
+
+    fn return_chan(&self) -> &ReturnChan {
+        match self {
+            Self::SendPulse(.., return_chan)
+            | Self::SendPulseBatch(.., return_chan)
+            | Self::MailboxNext(.., return_chan)
+            | Self::MailOp(.., return_chan) => return_chan,
+        }
+    }
+}
+
+/// How long a command waits for a response from the server, unless set otherwise
+pub const DEFAULT_CMD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long stopping the client waits for the requests awaiting a response, unless set otherwise
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
 
-    /// MailOp(MailAckType, mail_id)
-    MailOp(MailAckType, u128, ReturnChan),
+/// Per-command options, set by the user of the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdOpts {
+    /// How long to wait for a response from the server before timing out
+    pub timeout: Duration,
+
+    /// An ID set by the caller, written to the logs along with the txn_id
+    /// so that a single request can be traced through to the server's response
+    pub request_id: Option<String>,
+}
+
+impl Default for CmdOpts {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_CMD_TIMEOUT,
+            request_id: None,
+        }
+    }
+}
+
+/// A command awaiting a response from the server
+#[derive(Debug)]
+struct PendingTxn {
+    started: Instant,
+    opts: CmdOpts,
+    return_chan: ReturnChan,
+
+    /// The command, to send again in the next session
+    /// if the connection drops before the response arrives
+    cmd: Option<ClientCmd>,
+
+    /// The txn_id that a pulse was written with, once it has been carried over.
+    /// The pulse is sent again with it, so that the server can drop the duplicate.
+    carried_txn_id: Option<u64>,
+}
+
+impl PendingTxn {
+    fn new(return_chan: ReturnChan, opts: CmdOpts) -> Self {
+        Self {
+            started: Instant::now(),
+            opts,
+            return_chan,
+            cmd: None,
+            carried_txn_id: None,
+        }
+    }
+
+    fn for_cmd(cmd: ClientCmd, opts: CmdOpts) -> Self {
+        Self {
+            cmd: Some(cmd.clone()),
+            ..Self::new(cmd.return_chan().clone(), opts)
+        }
+    }
+
+    fn timed_out(&self, now: Instant) -> bool {
+        now.duration_since(self.started) > self.opts.timeout
+    }
+
+    fn deadline(&self) -> Instant {
+        self.started + self.opts.timeout
+    }
+
+    /// Resolves a command that ran out of time before it could be sent.
+    /// A pulse written in an earlier session may have been recorded,
+    /// so it times out like any other request awaiting a response.
+    fn expire(self) {
+        let result = match self.carried_txn_id {
+            Some(_) => R::Timeout,
+            None => R::Busy(GeneralErrors::NotConnected.to_string()),
+        };
+
+        let _ = self.return_chan.send(result);
+    }
+
+    /// Sends the result to the caller, and logs it if the request is being traced
+    fn resolve(self, txn_id: u128, result: ReturnChanResult) {
+        if let Some(request_id) = &self.opts.request_id
+            && log::enabled(Level::Info)
+        {
+            let outcome = match &result {
+                R::Timeout => "timeout",
+                R::Err(msg) | R::Busy(msg) => msg.split_once(':').map_or(msg.as_str(), |(r, _)| r),
+                _ => "ok",
+            };
+
+            println!(
+                "request: id={request_id} txn_id={txn_id} result={outcome} elapsed_ms={}",
+                self.started.elapsed().as_millis()
+            );
+        }
+
+        let _ = self.return_chan.send(result);
+    }
 }
 
 /// An enum of all possible events that the client can process and receive
@@ -908,10 +1757,29 @@ pub enum ClientEvent {
     /// Sent by the Transport Stream whenever it ends unexpectedly
     TransportClose,
 
+    /// Sent by the Moonlight client when the credentials or the endpoint
+    /// change, to end the session and reconnect with the new settings
+    Reconnect,
+
+    /// Sent by the Moonlight client when it's stopped, to finish the session
+    /// cleanly. The requests awaiting a response get up to the given timeout.
+    Drain(Duration),
+
     /// Sent by the user of the Moonlight client for operations
-    Cmd(ClientCmd),
+    Cmd(ClientCmd, CmdOpts),
 }
 
+/// The most requests awaiting a response from the server at once,
+/// unless $FOSTROM_MAX_PENDING_TXNS is set. Further commands are
+/// turned away until responses arrive or time out.
+pub const DEFAULT_MAX_PENDING_TXNS: usize = 256;
+
+/// The longest the client sleeps between sessions without waking up
+const WAIT_TICK: Duration = Duration::from_secs(1);
+
+/// How long to wait for the server's CloseConnection, once the client sent its own
+const CLOSE_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
 /// ClientLogic is a pure functional and stateful loop,
 /// which handles all client-related logic while accepting
 /// events over a channel and performing side effects.
@@ -938,34 +1806,59 @@ pub struct ClientLogic {
     /// Moonlight Codec struct for the streaming decoder
     codec: Codec,
 
-    /// Pending Txns: the u64 is the pulse_id/txn_id.
-    /// The Instant is tracked to check for timeouts
+    /// Pending Txns: the u128 is the pulse_id/txn_id.
+    /// The start time is tracked to check for timeouts
     next_txn_id: u64,
-    pending_txns: HashMap<u128, (Instant, ReturnChan)>,
+
+    /// Commands are turned away while this many txns are pending
+    max_pending_txns: usize,
+    pending_txns: HashMap<u128, PendingTxn>,
+
+    /// Commands to send once authenticated: those carried over
+    /// from the previous session, and those received meanwhile
+    queued_cmds: Vec<PendingTxn>,
 
     /// Encoded Connect Packet and Creds Struct
-    _creds: Creds,
+    creds: Creds,
     connect_packet_bytes: Vec<u8>,
 
     /// Authenticated
     authenticated: AtomicBool,
+
+    /// Protocol version and capabilities selected by the server
+    negotiated: Negotiated,
+
+    /// When the heartbeat awaiting an ack was sent, to measure the round trip time
+    heartbeat_sent: Option<Instant>,
+
+    diagnostics: Arc<Diagnostics>,
 }
 
 impl ClientLogic {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         fleet_id: String,
         device_id: String,
         device_secret: String,
         prod: bool,
+        codec_limits: CodecLimits,
         notify_chan: Sender<(String, String)>,
         ping_chan: Sender<()>,
         transport_write_chan: Sender<Vec<u8>>,
+        diagnostics: Arc<Diagnostics>,
     ) -> Result<(Sender<ClientEvent>, Self)> {
         let (tx, rx): (Sender<ClientEvent>, Receiver<ClientEvent>) = channel();
-        let (connect_packet, creds) = P::connect(fleet_id, device_id, device_secret, prod)?;
+        let (connect_packet, creds) = P::connect_with_capabilities(
+            fleet_id,
+            device_id,
+            device_secret,
+            prod,
+            PROTOCOL_VERSION,
+            Capabilities::SUPPORTED,
+        )?;
         let connect_packet_bytes = Codec::encode(&connect_packet)?;
 
-        let codec = Codec::new();
+        let codec = Codec::new(codec_limits);
 
         let client_logic = Self {
             proc_mailbox_chan: rx,
@@ -974,10 +1867,15 @@ impl ClientLogic {
             ping_chan,
             codec,
             next_txn_id: 0,
+            max_pending_txns: DEFAULT_MAX_PENDING_TXNS,
             pending_txns: HashMap::with_capacity(32),
-            _creds: creds,
+            queued_cmds: Vec::new(),
+            creds,
             connect_packet_bytes,
             authenticated: AtomicBool::new(false),
+            negotiated: Negotiated::LEGACY,
+            heartbeat_sent: None,
+            diagnostics,
         };
 
         Ok((tx, client_logic))
@@ -1017,6 +1915,37 @@ impl ClientLogic {
         )
     }
 
+    /// Connects with the legacy CONNECT packet, which advertises protocol
+    /// version 1 and no capabilities, for servers that only speak version 1.
+    fn use_legacy_connect(&mut self) -> Result<()> {
+        let (connect_packet, _) = P::connect(
+            self.creds.fleet_id.clone(),
+            self.creds.device_id.clone(),
+            self.creds.device_secret.clone(),
+            self.creds.prod,
+        )?;
+
+        self.connect_packet_bytes = Codec::encode(&connect_packet)?;
+        Ok(())
+    }
+
+    /// Whether the server may have turned away the CONNECT packet for its
+    /// protocol version or capabilities. A server that only speaks version 1
+    /// fails the connection for an unknown reason, or closes the socket.
+    pub fn connect_rejected(reason: DisconnectedReason) -> bool {
+        matches!(
+            reason,
+            DisconnectedReason::ConnectFailed(ConnectFailedError::Unknown)
+                | DisconnectedReason::ForceCloseSocket
+        )
+    }
+
+    /// The protocol version and capabilities in effect for this session.
+    /// Until authentication completes, this is the legacy protocol.
+    pub fn negotiated(&self) -> Negotiated {
+        self.negotiated
+    }
+
     fn wait_for_authentication(&mut self) -> Result<(), DisconnectedReason> {
         self.wait_for_authentication_with_timeout(Duration::from_secs(10))
     }
@@ -1032,7 +1961,7 @@ impl ClientLogic {
     ) -> Result<(), DisconnectedReason> {
         let connect_packet = self.connect_packet_bytes.clone();
 
-        match self.transport_write_chan.send(connect_packet) {
+        match self.send_to_transport(connect_packet) {
             Ok(_) => (),
             Err(_) => return Err(DisconnectedReason::ForceCloseSocket),
         }
@@ -1040,24 +1969,22 @@ impl ClientLogic {
         // Commands can arrive before authentication is complete (e.g. SDK calls immediately after
         // starting the agent). These must not abort authentication.
         //
-        // We buffer them and replay after the server confirms the connection.
-        let mut buffered_cmds: Vec<ClientCmd> = Vec::new();
-
+        // We queue them, after any carried over from the previous session,
+        // and replay them after the server confirms the connection.
         // Read the process mailbox until we can form a complete connect packet response.
         while let Ok(client_event) = self.proc_mailbox_chan.recv_timeout(timeout) {
             match client_event {
                 ClientEvent::TransportRecv(bytes) => {
-                    self.codec.feed(&bytes);
-
-                    let mut packets = match self.codec.process_packets() {
-                        Ok(packets) => packets.into_iter(),
-                        Err(_) => return Err(DisconnectedReason::ForceCloseSocket),
+                    let mut frames = match self.recv_frames(&bytes) {
+                        Ok(frames) => frames.into_iter(),
+                        Err(disconnected_reason) => return Err(disconnected_reason),
                     };
 
-                    if let Some(packet) = packets.next() {
-                        match ServerResp::handle_packet(packet) {
-                            ServerResp::Connected(mail_available) => {
+                    if let Some(frame) = frames.next() {
+                        match ServerResp::handle_packet(frame) {
+                            ServerResp::Connected(mail_available, negotiated) => {
                                 self.authenticated.store(true, Ordering::SeqCst);
+                                self.negotiated = negotiated;
 
                                 let notification = ("connected".to_string(), "".to_string());
                                 let _ = self.notify_chan.send(notification);
@@ -1069,20 +1996,18 @@ impl ClientLogic {
 
                                 // If there are remaining packets sent along with connect
                                 // we can process them here before moving forward.
-                                if packets.len() > 0 {
-                                    for packet in packets {
-                                        let server_resp = ServerResp::handle_packet(packet);
-                                        if let Some(disconnected_reason) =
-                                            self.handle_server_resp(server_resp)
-                                        {
-                                            return Err(disconnected_reason);
-                                        }
+                                for frame in frames {
+                                    let server_resp = ServerResp::handle_packet(frame);
+                                    if let Some(disconnected_reason) =
+                                        self.handle_server_resp(server_resp)
+                                    {
+                                        return Err(disconnected_reason);
                                     }
                                 }
 
-                                // Replay any commands received during authentication.
-                                for cmd in buffered_cmds.drain(..) {
-                                    if self.handle_cmd(cmd).is_err() {
+                                // Replay the queued commands
+                                for txn in take(&mut self.queued_cmds) {
+                                    if self.resend(txn).is_err() {
                                         return Err(DisconnectedReason::ForceCloseSocket);
                                     }
                                 }
@@ -1098,12 +2023,21 @@ impl ClientLogic {
                         continue;
                     }
                 }
-                ClientEvent::Cmd(cmd) => {
-                    buffered_cmds.push(cmd);
+                ClientEvent::Cmd(cmd, opts) => {
+                    self.queued_cmds.push(PendingTxn::for_cmd(cmd, opts));
                     continue;
                 }
                 ClientEvent::Refresh | ClientEvent::HeartbeatTick => continue,
                 ClientEvent::TransportClose => return Err(DisconnectedReason::ForceCloseSocket),
+                ClientEvent::Reconnect => return Err(DisconnectedReason::Reconfigured),
+                ClientEvent::Drain(_) => {
+                    for txn in take(&mut self.queued_cmds) {
+                        let e = GeneralErrors::ShuttingDown.to_string();
+                        let _ = txn.return_chan.send(R::Busy(e));
+                    }
+
+                    return Err(DisconnectedReason::ForceCloseSocket);
+                }
             }
         }
 
@@ -1117,7 +2051,10 @@ impl ClientLogic {
         while !shutdown_flag.load(Ordering::SeqCst)
             && let Ok(client_event) = self.proc_mailbox_chan.recv()
         {
-            if let Some(disconnected_reason) = self.process_client_event(client_event) {
+            let disconnected_reason = self.process_client_event(client_event);
+            self.diagnostics.set_pending_txns(self.pending_txns.len());
+
+            if let Some(disconnected_reason) = disconnected_reason {
                 return disconnected_reason;
             }
         }
@@ -1125,6 +2062,19 @@ impl ClientLogic {
         DisconnectedReason::ForceCloseSocket
     }
 
+    /// Feeds bytes from the transport into the codec and decodes any complete frames.
+    /// Exceeding the codec limits closes the connection with a distinct reason.
+    fn recv_frames(&mut self, bytes: &[u8]) -> Result<Vec<Frame>, DisconnectedReason> {
+        self.codec.feed(bytes)?;
+
+        self.codec
+            .process_packets()
+            .map_err(|e| match e.downcast::<FrameLimitError>() {
+                Ok(e) => DisconnectedReason::FrameLimitExceeded(e),
+                Err(_) => DisconnectedReason::ForceCloseSocket,
+            })
+    }
+
     fn process_client_event(&mut self, client_event: ClientEvent) -> Option<DisconnectedReason> {
         match client_event {
             ClientEvent::Refresh => self.refresh(),
@@ -1132,19 +2082,20 @@ impl ClientLogic {
                 // Write heartbeat packet to transport
                 let p = Codec::encode(&P::heartbeat()).unwrap();
 
-                if self.transport_write_chan.send(p).is_err() {
+                if self.send_to_transport(p).is_err() {
                     return Some(DisconnectedReason::ForceCloseSocket);
                 }
+
+                self.heartbeat_sent.get_or_insert_with(Instant::now);
             }
             ClientEvent::TransportRecv(bytes) => {
-                self.codec.feed(&bytes);
-                let packets = match self.codec.process_packets() {
-                    Ok(packets) => packets,
-                    Err(_) => return Some(DisconnectedReason::ForceCloseSocket),
+                let frames = match self.recv_frames(&bytes) {
+                    Ok(frames) => frames,
+                    Err(disconnected_reason) => return Some(disconnected_reason),
                 };
 
-                for packet in packets {
-                    let server_resp = ServerResp::handle_packet(packet);
+                for frame in frames {
+                    let server_resp = ServerResp::handle_packet(frame);
                     if let Some(disconnected_reason) = self.handle_server_resp(server_resp) {
                         return Some(disconnected_reason);
                     }
@@ -1154,8 +2105,10 @@ impl ClientLogic {
                 // Close the loop, causing a full client restart
                 return Some(DisconnectedReason::ForceCloseSocket);
             }
-            ClientEvent::Cmd(cmd) => {
-                if self.handle_cmd(cmd).is_err() {
+            ClientEvent::Reconnect => return Some(DisconnectedReason::Reconfigured),
+            ClientEvent::Drain(timeout) => return Some(self.drain(timeout)),
+            ClientEvent::Cmd(cmd, opts) => {
+                if self.handle_cmd(cmd, opts, None).is_err() {
                     return Some(DisconnectedReason::ForceCloseSocket);
                 }
             }
@@ -1164,6 +2117,159 @@ impl ClientLogic {
         None
     }
 
+    /// Finishes the session when the client is stopped. New commands are
+    /// turned away, the requests awaiting a response get up to `timeout`
+    /// to complete, and then the connection is closed with the server.
+    /// Any requests still waiting are resolved with a timeout.
+    fn drain(&mut self, timeout: Duration) -> DisconnectedReason {
+        let reason = self
+            .wait_for_pending_txns(timeout)
+            .unwrap_or_else(|| self.close_connection());
+
+        for (txn_id, txn) in self.pending_txns.drain() {
+            txn.resolve(txn_id, R::Timeout);
+        }
+
+        reason
+    }
+
+    /// Returns early only if the session ended while waiting
+    fn wait_for_pending_txns(&mut self, timeout: Duration) -> Option<DisconnectedReason> {
+        let deadline = Instant::now() + timeout;
+
+        while !self.pending_txns.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(client_event) = self.proc_mailbox_chan.recv_timeout(remaining) else {
+                break;
+            };
+
+            if let Some(disconnected_reason) = self.process_draining_event(client_event) {
+                return Some(disconnected_reason);
+            }
+        }
+
+        None
+    }
+
+    /// Sends CloseConnection, and waits for the server to send its own
+    fn close_connection(&mut self) -> DisconnectedReason {
+        if self
+            .write_packet_to_transport(P::client_close_connection())
+            .is_err()
+        {
+            return DisconnectedReason::ForceCloseSocket;
+        }
+
+        let deadline = Instant::now() + CLOSE_ACK_TIMEOUT;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(client_event) = self.proc_mailbox_chan.recv_timeout(remaining) else {
+                return DisconnectedReason::ForceCloseSocket;
+            };
+
+            if let Some(disconnected_reason) = self.process_draining_event(client_event) {
+                return disconnected_reason;
+            }
+        }
+    }
+
+    fn process_draining_event(&mut self, client_event: ClientEvent) -> Option<DisconnectedReason> {
+        match client_event {
+            ClientEvent::Cmd(cmd, _) => {
+                let e = GeneralErrors::ShuttingDown.to_string();
+                let _ = cmd.return_chan().send(R::Busy(e));
+                None
+            }
+            ClientEvent::Drain(_) => None,
+            client_event => self.process_client_event(client_event),
+        }
+    }
+
+    /// Queues the commands left unanswered by the previous session
+    fn carry_over(&mut self, txns: Vec<PendingTxn>) {
+        if !txns.is_empty() && log::enabled(Level::Info) {
+            println!("session: resending commands={}", txns.len());
+        }
+
+        self.queued_cmds.extend(txns);
+    }
+
+    /// Sends a command again, within what's left of its timeout
+    fn resend(&mut self, txn: PendingTxn) -> Result<()> {
+        let remaining = txn.opts.timeout.saturating_sub(txn.started.elapsed());
+
+        if remaining.is_zero() {
+            txn.expire();
+            return Ok(());
+        }
+
+        let Some(cmd) = txn.cmd else {
+            return Ok(());
+        };
+
+        let opts = CmdOpts {
+            timeout: remaining,
+            ..txn.opts
+        };
+
+        self.handle_cmd(cmd, opts, txn.carried_txn_id)
+    }
+
+    /// Takes the commands that are still unanswered once the session has
+    /// ended, to carry them over to the next session. These are the commands
+    /// that were queued, or left in the mailbox, and the ones awaiting a response.
+    ///
+    /// Fetching the next mail and acknowledging a mail by its ID are safe to
+    /// repeat. A pulse that was already written keeps its txn_id, which lets
+    /// the server drop the duplicate when it's sent again. That is only done
+    /// once, so a pulse that was already carried over fails with an unknown
+    /// outcome instead.
+    ///
+    /// When the credentials or the endpoint were changed, nothing is carried
+    /// over, as the commands were meant for the previous device.
+    fn unresolved(&mut self, reason: DisconnectedReason) -> Vec<PendingTxn> {
+        let mut txns = take(&mut self.queued_cmds);
+
+        for (txn_id, mut txn) in self.pending_txns.drain() {
+            let pulse = matches!(
+                txn.cmd,
+                Some(ClientCmd::SendPulse(..) | ClientCmd::SendPulseBatch(..))
+            );
+
+            if pulse && txn.carried_txn_id.is_some() {
+                let e = GeneralErrors::PulseOutcomeUnknown.to_string();
+                txn.resolve(txn_id, R::Err(e));
+                continue;
+            }
+
+            if pulse {
+                txn.carried_txn_id = Some(txn_id as u64);
+            }
+
+            txns.push(txn);
+        }
+
+        while let Ok(client_event) = self.proc_mailbox_chan.try_recv() {
+            if let ClientEvent::Cmd(cmd, opts) = client_event {
+                txns.push(PendingTxn::for_cmd(cmd, opts));
+            }
+        }
+
+        if reason == DisconnectedReason::Reconfigured {
+            for txn in txns {
+                let _ = txn
+                    .return_chan
+                    .send(R::Err(GeneralErrors::Reconfigured.to_string()));
+            }
+            return Vec::new();
+        }
+
+        // Sent again in the order they were first received
+        txns.sort_by_key(|txn| txn.started);
+        txns
+    }
+
     fn next_txn_id(&mut self) -> u64 {
         let current_id = self.next_txn_id;
         self.next_txn_id = current_id.wrapping_add(1);
@@ -1173,7 +2279,14 @@ impl ClientLogic {
     /// Encode a MoonlightPacket into bytes and send it to the transport channel
     fn write_packet_to_transport(&mut self, packet: MoonlightPacket) -> Result<()> {
         let bytes = Codec::encode(&packet)?;
+        self.send_to_transport(bytes)
+    }
+
+    /// Send bytes to the transport channel, counting them as queued until written
+    fn send_to_transport(&self, bytes: Vec<u8>) -> Result<()> {
+        let len = bytes.len();
         self.transport_write_chan.send(bytes)?;
+        self.diagnostics.bytes_queued(len);
         Ok(())
     }
 
@@ -1181,33 +2294,30 @@ impl ClientLogic {
     fn refresh(&mut self) {
         // Check for any timeouts in the pending_txns list
         let now = Instant::now();
-        // Timeout duration for pending transactions
-        let timeout = Duration::from_secs(10);
 
         // Collect timed-out transaction IDs to avoid mutating the map while iterating
         let timed_out: Vec<u128> = self
             .pending_txns
             .iter()
-            .filter_map(|(txn_id, (ts, _))| {
-                if now.duration_since(*ts) > timeout {
-                    Some(*txn_id)
-                } else {
-                    None
-                }
-            })
+            .filter_map(|(txn_id, txn)| txn.timed_out(now).then_some(*txn_id))
             .collect();
 
         // Remove timed-out entries and notify the waiting caller with a timeout error
         for txn_id in timed_out {
-            if let Some((_ts, chan)) = self.pending_txns.remove(&txn_id) {
-                let _ = chan.send(ReturnChanResult::Timeout);
+            if let Some(txn) = self.pending_txns.remove(&txn_id) {
+                txn.resolve(txn_id, ReturnChanResult::Timeout);
             }
         }
+
+        self.diagnostics.refreshed();
     }
 
-    fn push_txn(&mut self, return_chan: ReturnChan) -> Result<u64> {
-        let now = Instant::now();
-        let mut txn_id = self.next_txn_id();
+    fn push_txn(&mut self, txn: PendingTxn) -> Result<u64> {
+        // A pulse carried over from the previous session keeps its txn_id
+        let mut txn_id = match txn.carried_txn_id {
+            Some(txn_id) => txn_id,
+            None => self.next_txn_id(),
+        };
 
         for _ in 0..3 {
             if self.pending_txns.contains_key(&(txn_id as u128)) {
@@ -1219,17 +2329,56 @@ impl ClientLogic {
 
         #[allow(clippy::map_entry)]
         if self.pending_txns.contains_key(&(txn_id as u128)) {
-            let _ = return_chan.send(R::Err("txn_failed: Transaction ID Exhaustion".to_string()));
+            let e = "txn_failed: Transaction ID Exhaustion".to_string();
+            let _ = txn.return_chan.send(R::Err(e));
             Err(anyhow!("txn_id_exhaustion"))
         } else {
-            self.pending_txns.insert(txn_id as u128, (now, return_chan));
+            if let Some(request_id) = &txn.opts.request_id
+                && log::enabled(Level::Info)
+            {
+                println!("request: id={request_id} txn_id={txn_id} sent");
+            }
+
+            self.pending_txns.insert(txn_id as u128, txn);
             Ok(txn_id)
         }
     }
 
-    fn handle_cmd(&mut self, cmd: ClientCmd) -> Result<()> {
+    fn handle_cmd(
+        &mut self,
+        cmd: ClientCmd,
+        opts: CmdOpts,
+        carried_txn_id: Option<u64>,
+    ) -> Result<()> {
+        if cmd.child().is_some()
+            && !self
+                .negotiated
+                .capabilities
+                .contains(Capabilities::VIRTUAL_DEVICES)
+        {
+            let e = GeneralErrors::VirtualDevicesUnsupported.to_string();
+            let _ = cmd.return_chan().send(R::Err(e));
+            return Ok(());
+        }
+
+        if matches!(cmd, ClientCmd::SendPulseBatch(..))
+            && !self.negotiated.capabilities.contains(Capabilities::BATCH)
+        {
+            let e = GeneralErrors::BatchUnsupported.to_string();
+            let _ = cmd.return_chan().send(R::Err(e));
+            return Ok(());
+        }
+
+        if self.pending_txns.len() >= self.max_pending_txns {
+            let e = GeneralErrors::TooManyPendingTxns.to_string();
+            let _ = cmd.return_chan().send(R::Busy(e));
+            return Ok(());
+        }
+
+        let resend = cmd.clone();
+
         match cmd {
-            ClientCmd::SendPulse(pulse_type, name, payload, return_chan) => {
+            ClientCmd::SendPulse(child, pulse_type, name, payload, return_chan) => {
                 if name.len() > 255 {
                     let _ = return_chan.send(ReturnChanResult::Err(
                         "invalid_name: Pulse Name needs to be under 255 characters.".to_string(),
@@ -1243,16 +2392,46 @@ impl ClientLogic {
                     "".to_string()
                 };
 
-                let txn_id = self.push_txn(return_chan)?;
-                let p = P::pulse(pulse_type, txn_id, name, pl);
+                let txn = PendingTxn {
+                    carried_txn_id,
+                    ..PendingTxn::for_cmd(resend, opts)
+                };
+
+                let txn_id = self.push_txn(txn)?;
+                let p = P::pulse(pulse_type, txn_id, name, pl).for_child(child);
                 self.write_packet_to_transport(p)
             }
-            ClientCmd::MailboxNext(header_only, return_chan) => {
-                let txn_id = self.push_txn(return_chan)?;
-                let p = P::mailbox_next(header_only, txn_id);
+            ClientCmd::SendPulseBatch(child, pulses, return_chan) => {
+                if pulses.iter().any(|(_, name, _)| name.len() > 255) {
+                    let _ = return_chan.send(ReturnChanResult::Err(
+                        "invalid_name: Pulse Name needs to be under 255 characters.".to_string(),
+                    ));
+                    return Ok(());
+                }
+
+                let pulses = pulses
+                    .into_iter()
+                    .map(|(pulse_type, name, payload)| {
+                        let pl = payload.map_or(String::new(), |p| p.to_string());
+                        BatchPulse::new(pulse_type, name, pl)
+                    })
+                    .collect();
+
+                let txn = PendingTxn {
+                    carried_txn_id,
+                    ..PendingTxn::for_cmd(resend, opts)
+                };
+
+                let txn_id = self.push_txn(txn)?;
+                let p = P::pulse_batch(txn_id, pulses).for_child(child);
                 self.write_packet_to_transport(p)
             }
-            ClientCmd::MailOp(ack_type, pulse_id, return_chan) => {
+            ClientCmd::MailboxNext(child, header_only, _) => {
+                let txn_id = self.push_txn(PendingTxn::for_cmd(resend, opts))?;
+                let p = P::mailbox_next(header_only, txn_id).for_child(child);
+                self.write_packet_to_transport(p)
+            }
+            ClientCmd::MailOp(child, ack_type, pulse_id, return_chan) => {
                 // Clippy has a known inference issue here,
                 // even though we aren't doing an insert in the if branch.
                 #[allow(clippy::map_entry)]
@@ -1260,9 +2439,15 @@ impl ClientLogic {
                     let _ = return_chan.send(R::Err(GeneralErrors::DuplicateReq.to_string()));
                     Ok(())
                 } else {
-                    let p = P::ack_mail(pulse_id, ack_type);
-                    self.pending_txns
-                        .insert(pulse_id, (Instant::now(), return_chan));
+                    if let Some(request_id) = &opts.request_id
+                        && log::enabled(Level::Info)
+                    {
+                        println!("request: id={request_id} txn_id={pulse_id} sent");
+                    }
+
+                    let p = P::ack_mail(pulse_id, ack_type).for_child(child);
+                    let txn = PendingTxn::for_cmd(resend, opts);
+                    self.pending_txns.insert(pulse_id, txn);
                     self.write_packet_to_transport(p)
                 }
             }
@@ -1274,7 +2459,7 @@ impl ClientLogic {
             ServerResp::ForceCloseSocket => return Some(DisconnectedReason::ForceCloseSocket),
             ServerResp::Disconnected(disconnected_reason) => return Some(disconnected_reason),
 
-            ServerResp::Connected(_mail_available) => {
+            ServerResp::Connected(..) => {
                 // This branch is unreachable because start_loop needs to be called
                 // after successful authentication only.
                 unreachable!();
@@ -1282,13 +2467,20 @@ impl ClientLogic {
 
             ServerResp::HeartbeatAck => {
                 let _ = self.ping_chan.send(());
+                let rtt = self.heartbeat_sent.take().map(|sent| sent.elapsed());
+                self.diagnostics.heartbeat_ack(rtt);
             }
 
-            ServerResp::NewMail => {
+            ServerResp::NewMail(None) => {
                 let notification = ("new_mail".to_string(), "".to_string());
                 let _ = self.notify_chan.send(notification);
             }
 
+            ServerResp::NewMail(Some(child)) => {
+                let data = json!({"device_id": child.to_string()}).to_string();
+                let _ = self.notify_chan.send(("device_new_mail".to_string(), data));
+            }
+
             ServerResp::PulseResp(pulse_result) => match pulse_result {
                 Ok(txn_id) => self.resolve_txn(txn_id, R::Ok),
                 Err((txn_id, pulse_error_reason)) => {
@@ -1296,6 +2488,10 @@ impl ClientLogic {
                 }
             },
 
+            ServerResp::PulseBatchResp(txn_id, results) => {
+                self.resolve_txn(txn_id, R::BatchResults(results))
+            }
+
             ServerResp::AckMailResp(ack_result) => match ack_result {
                 Ok((pulse_id, false)) => self.resolve_mail(pulse_id, R::MailAckSuccessful(false)),
                 Ok((pulse_id, true)) => self.resolve_mail(pulse_id, R::MailAckSuccessful(true)),
@@ -1325,16 +2521,14 @@ impl ClientLogic {
     }
 
     fn resolve_txn(&mut self, txn_id: u64, return_value: ReturnChanResult) {
-        if let Some((_, return_chan)) = self.pending_txns.get(&(txn_id as u128)) {
-            let _ = return_chan.send(return_value);
-            self.pending_txns.remove(&(txn_id as u128));
+        if let Some(txn) = self.pending_txns.remove(&(txn_id as u128)) {
+            txn.resolve(txn_id as u128, return_value);
         }
     }
 
     fn resolve_mail(&mut self, pulse_id: u128, return_value: ReturnChanResult) {
-        if let Some((_, return_chan)) = self.pending_txns.get(&pulse_id) {
-            let _ = return_chan.send(return_value);
-            self.pending_txns.remove(&pulse_id);
+        if let Some(txn) = self.pending_txns.remove(&pulse_id) {
+            txn.resolve(pulse_id, return_value);
         }
     }
 }
@@ -1343,9 +2537,9 @@ impl ClientLogic {
 // --- CLIENT PROCESS ---
 // ----------------------
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 
-use crate::moonlight_socket;
+use crate::{moonlight_socket, systemd};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ConnectMode {
@@ -1353,25 +2547,69 @@ pub enum ConnectMode {
     Local(u16),
 }
 
+/// The credentials and endpoint that the client connects with.
+/// They can be changed while running, with MoonlightClient::reconfigure().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientSettings {
+    pub fleet_id: String,
+    pub device_id: String,
+    pub device_secret: String,
+    pub connect_mode: ConnectMode,
+    pub codec_limits: CodecLimits,
+
+    /// How long stop() waits for the requests awaiting a response
+    pub drain_timeout: Duration,
+
+    /// Applies from the next session onwards, like the codec limits
+    pub max_pending_txns: usize,
+}
+
+impl ClientSettings {
+    /// Whether the current session has to be replaced to apply the other settings.
+    /// Codec and pending txn limits apply from the next session onwards.
+    fn needs_reconnect(&self, other: &Self) -> bool {
+        self.fleet_id != other.fleet_id
+            || self.device_id != other.device_id
+            || self.device_secret != other.device_secret
+            || self.connect_mode != other.connect_mode
+    }
+}
+
 // The Moonlight Client implements the functionality that covers
 // managing the connection and restarting of side-effect threads
 // while initializing the ClientLogic and starting its tight-loop.
 #[derive(Debug, Clone)]
 pub struct MoonlightClient {
-    // Constants
-    pub fleet_id: String,
-    pub device_id: String,
-    device_secret: String,
-    connect_mode: ConnectMode,
+    // Read at the start of every session
+    settings: Arc<Mutex<ClientSettings>>,
 
     // Global
     shutdown_flag: Arc<AtomicBool>,
 
+    /// Set to cut the wait before reconnecting short
+    reconnect_now: Arc<(Mutex<bool>, Condvar)>,
+
+    /// Connect with the legacy CONNECT packet, after the server
+    /// turned away the one that negotiates the protocol version
+    legacy_connect: Arc<AtomicBool>,
+
     // Session Dependent
     authenticated: Arc<AtomicBool>,
     disconnected_reason: Arc<Mutex<Option<DisconnectedReason>>>,
     reconnect_in: Arc<Mutex<Option<Duration>>>,
     mailbox_chan: Arc<Mutex<Option<Sender<ClientEvent>>>>,
+    negotiated: Arc<Mutex<Option<Negotiated>>>,
+
+    /// The commands left unanswered by the last session, or sent while there
+    /// was none, waiting for the next session. Guarded by the mailbox_chan lock.
+    carried: Arc<Mutex<Vec<PendingTxn>>>,
+
+    /// Carried across sessions, so that a txn_id isn't reused
+    /// while the server may still recognise it
+    next_txn_id: Arc<AtomicU64>,
+
+    // Reported in the agent status
+    diagnostics: Arc<Diagnostics>,
 }
 
 impl MoonlightClient {
@@ -1380,31 +2618,120 @@ impl MoonlightClient {
         device_id: String,
         device_secret: String,
         connect_mode: ConnectMode,
+        codec_limits: CodecLimits,
+        drain_timeout: Duration,
+        max_pending_txns: usize,
     ) -> Self {
-        Self {
+        let settings = ClientSettings {
             fleet_id,
             device_id,
             device_secret,
             connect_mode,
+            codec_limits,
+            drain_timeout,
+            max_pending_txns,
+        };
+
+        Self {
+            settings: Arc::new(Mutex::new(settings)),
             shutdown_flag: Arc::new(AtomicBool::new(false)),
+            reconnect_now: Arc::new((Mutex::new(false), Condvar::new())),
+            legacy_connect: Arc::new(AtomicBool::new(false)),
             authenticated: Arc::new(AtomicBool::new(false)),
             disconnected_reason: Arc::new(Mutex::new(None)),
             reconnect_in: Arc::new(Mutex::new(None)),
             mailbox_chan: Arc::new(Mutex::new(None)),
+            negotiated: Arc::new(Mutex::new(None)),
+            carried: Arc::new(Mutex::new(Vec::new())),
+            next_txn_id: Arc::new(AtomicU64::new(0)),
+            diagnostics: Arc::new(Diagnostics::new()),
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        self.authenticated.load(Ordering::SeqCst)
+    }
+
+    pub fn fleet_id(&self) -> String {
+        self.settings.lock().unwrap().fleet_id.clone()
+    }
+
+    pub fn device_id(&self) -> String {
+        self.settings.lock().unwrap().device_id.clone()
+    }
+
+    /// Applies new settings. If the credentials or the endpoint changed,
+    /// the current session is ended and the client reconnects right away
+    /// with the new settings, in which case true is returned.
+    pub fn reconfigure(&self, settings: ClientSettings) -> bool {
+        let reconnect = {
+            let mut current = self.settings.lock().unwrap();
+            let reconnect = current.needs_reconnect(&settings);
+            *current = settings;
+            reconnect
+        };
+
+        if reconnect {
+            *self.reconnect_in.lock().unwrap() = None;
+
+            // A session that is still being set up checks
+            // the settings once its mailbox is in place.
+            let mailbox_chan = self.mailbox_chan.lock().unwrap();
+            match mailbox_chan.as_ref() {
+                Some(mailbox_chan) => {
+                    let _ = mailbox_chan.send(ClientEvent::Reconnect);
+                }
+                // Between sessions, the commands waiting for the next one
+                // were meant for the previous device
+                None => {
+                    for txn in take(&mut *self.carried.lock().unwrap()) {
+                        let e = GeneralErrors::Reconfigured.to_string();
+                        let _ = txn.return_chan.send(R::Err(e));
+                    }
+                }
+            }
+            drop(mailbox_chan);
+
+            self.wake();
         }
-    }
 
-    pub fn connected(&self) -> bool {
-        self.authenticated.load(Ordering::SeqCst)
+        reconnect
     }
 
     pub fn start(&mut self, notify_chan_tx: Sender<(String, String)>) -> Result<()> {
+        let watchdog_stop = Arc::new(AtomicBool::new(false));
+        let watchdog_handle = systemd::watchdog_interval().map(|interval| {
+            let stop = watchdog_stop.clone();
+            let diagnostics = self.diagnostics.clone();
+            std::thread::spawn(move || Self::watchdog_proc(stop, diagnostics, interval))
+        });
+
+        let result = self.run_sessions(notify_chan_tx);
+
+        watchdog_stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = watchdog_handle {
+            let _ = handle.join();
+        }
+
+        result
+    }
+
+    fn run_sessions(&mut self, notify_chan_tx: Sender<(String, String)>) -> Result<()> {
         while !self.shutdown_flag.load(Ordering::SeqCst) {
+            // Starting another session counts as the client loop keeping up
+            self.diagnostics.refreshed();
             let disconnect_reason = self.session_lifecycle(notify_chan_tx.clone())?;
 
+            if log::enabled(Level::Warn) {
+                eprintln!("session: ended reason={disconnect_reason}");
+            }
+
+            self.diagnostics
+                .session_ended(disconnect_reason.to_string());
+
             // Perform cleanup of session-related variables
             *self.disconnected_reason.lock().unwrap() = Some(disconnect_reason);
-            *self.mailbox_chan.lock().unwrap() = None;
+            *self.negotiated.lock().unwrap() = None;
             self.authenticated.store(false, Ordering::SeqCst);
 
             // Change the backoff interval for reconnecting.
@@ -1427,23 +2754,87 @@ impl MoonlightClient {
 
             // Sleep if we don't have to shutdown
             if !self.shutdown_flag.load(Ordering::SeqCst) {
-                sleep(sleep_time);
+                systemd::status(&format!(
+                    "Disconnected ({disconnect_reason}), reconnecting in {}s",
+                    sleep_time.as_secs()
+                ));
+                self.wait_to_reconnect(sleep_time);
             }
         }
 
+        let _mailbox_chan = self.mailbox_chan.lock().unwrap();
+        for txn in take(&mut *self.carried.lock().unwrap()) {
+            let e = GeneralErrors::ShuttingDown.to_string();
+            let _ = txn.return_chan.send(R::Busy(e));
+        }
+
         Ok(())
     }
 
+    /// Stops the client once the current session is drained.
+    /// New commands are turned away from now on.
     pub fn stop(&self) {
         self.shutdown_flag.store(true, Ordering::SeqCst);
+        self.wake();
 
         let mailbox_chan = self.mailbox_chan.lock().unwrap();
         if mailbox_chan.is_some() {
+            let drain_timeout = self.settings.lock().unwrap().drain_timeout;
             let _ = mailbox_chan
                 .as_ref()
                 .unwrap()
-                .send(ClientEvent::TransportClose);
+                .send(ClientEvent::Drain(drain_timeout));
+        }
+    }
+
+    /// Sleeps until it's time to reconnect, or until woken up
+    /// by a change in the settings or by stop(). The commands waiting
+    /// for the next session are timed out along the way.
+    fn wait_to_reconnect(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &*self.reconnect_now;
+        let mut woken = lock.lock().unwrap();
+
+        loop {
+            let next_expiry = self.expire_carried();
+            let now = Instant::now();
+
+            if *woken || now >= deadline {
+                break;
+            }
+
+            // Waking up regularly, which keeps the systemd watchdog pinged
+            self.diagnostics.refreshed();
+            let until = next_expiry.map_or(deadline, |expiry| expiry.min(deadline));
+            let until = until.min(now + WAIT_TICK);
+            woken = cvar.wait_timeout(woken, until - now).unwrap().0;
+        }
+
+        *woken = false;
+    }
+
+    /// Times out the carried commands that have run out of time,
+    /// and returns when the next of the others runs out
+    fn expire_carried(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut carried = self.carried.lock().unwrap();
+
+        let (expired, waiting) = take(&mut *carried)
+            .into_iter()
+            .partition(|txn: &PendingTxn| txn.deadline() <= now);
+        *carried = waiting;
+
+        for txn in expired {
+            txn.expire();
         }
+
+        carried.iter().map(PendingTxn::deadline).min()
+    }
+
+    fn wake(&self) {
+        let (lock, cvar) = &*self.reconnect_now;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
     }
 
     fn session_lifecycle(
@@ -1453,39 +2844,87 @@ impl MoonlightClient {
         let (ping_chan_tx, ping_chan_rx) = channel();
         let (transport_write_chan_tx, transport_write_chan_rx) = channel();
 
-        let prod = match self.connect_mode {
+        let settings = self.settings.lock().unwrap().clone();
+
+        let prod = match settings.connect_mode {
             ConnectMode::Prod => true,
             ConnectMode::Local(_) => false,
         };
 
         let (mailbox_chan, mut logic) = ClientLogic::new(
-            self.fleet_id.clone(),
-            self.device_id.clone(),
-            self.device_secret.clone(),
+            settings.fleet_id.clone(),
+            settings.device_id.clone(),
+            settings.device_secret.clone(),
             prod,
+            settings.codec_limits,
             notify_chan_tx,
             ping_chan_tx,
             transport_write_chan_tx.clone(),
+            self.diagnostics.clone(),
         )?;
 
+        logic.next_txn_id = self.next_txn_id.load(Ordering::SeqCst);
+        logic.max_pending_txns = settings.max_pending_txns;
+
+        let legacy_connect = self.legacy_connect.load(Ordering::SeqCst);
+        if legacy_connect {
+            logic.use_legacy_connect()?;
+        }
+
+        systemd::status("Connecting to Fostrom");
+
         // Starts the transport process
         let (socket_handle, socket_close) = match moonlight_socket::connect(
-            self.connect_mode.clone(),
+            settings.connect_mode.clone(),
             mailbox_chan.clone(),
             transport_write_chan_rx,
+            self.diagnostics.clone(),
         ) {
             Err(_) => return Ok(DisconnectedReason::ForceCloseSocket),
             Ok((handle, close)) => (handle, close),
         };
 
-        *self.mailbox_chan.lock().unwrap() = Some(mailbox_chan.clone());
+        // Commands sent from now on go through the mailbox
+        {
+            let mut mailbox = self.mailbox_chan.lock().unwrap();
+            logic.carry_over(take(&mut *self.carried.lock().unwrap()));
+            *mailbox = Some(mailbox_chan.clone());
+        }
+
+        // The settings may have changed while connecting, before the mailbox was in place
+        if settings.needs_reconnect(&self.settings.lock().unwrap()) {
+            let _ = mailbox_chan.send(ClientEvent::Reconnect);
+        }
+
+        // Likewise, the client may have been stopped
+        if self.shutdown_flag.load(Ordering::SeqCst) {
+            let _ = mailbox_chan.send(ClientEvent::Drain(settings.drain_timeout));
+        }
 
         let disconnect_reason = match logic.wait_for_authentication() {
-            Err(disconnected_reason) => disconnected_reason,
+            Err(disconnected_reason) => {
+                // Alternate between the two CONNECT packets while they are turned away,
+                // so that neither an older server nor a flaky network locks in either
+                if ClientLogic::connect_rejected(disconnected_reason) {
+                    self.legacy_connect.store(!legacy_connect, Ordering::SeqCst);
+
+                    if log::enabled(Level::Warn) {
+                        let protocol_version = if legacy_connect { PROTOCOL_VERSION } else { 1 };
+                        eprintln!(
+                            "session: connect rejected, retrying protocol_version={protocol_version}"
+                        );
+                    }
+                }
+
+                disconnected_reason
+            }
             Ok(()) => {
+                *self.negotiated.lock().unwrap() = Some(logic.negotiated());
                 self.authenticated.store(true, Ordering::SeqCst);
+                self.diagnostics.session_started();
                 *self.reconnect_in.lock().unwrap() = None;
                 *self.disconnected_reason.lock().unwrap() = None;
+                systemd::status("Connected to Fostrom");
 
                 let shutdown_flag = Arc::new(AtomicBool::new(false));
                 let shutdown_flag_1 = shutdown_flag.clone();
@@ -1516,6 +2955,19 @@ impl MoonlightClient {
         // Wait for the socket thread to close
         let _ = socket_handle.join();
 
+        // No more commands can reach this session once the mailbox is gone,
+        // so the ones still unanswered are carried over to the next session,
+        // along with any sent until then
+        let mut mailbox = self.mailbox_chan.lock().unwrap();
+        *mailbox = None;
+
+        let mut carried = self.carried.lock().unwrap();
+        carried.extend(logic.unresolved(disconnect_reason));
+        carried.sort_by_key(|txn| txn.started);
+        self.next_txn_id.store(logic.next_txn_id, Ordering::SeqCst);
+        drop(carried);
+        drop(mailbox);
+
         // At this point the cleanup is complete,
         // and the start() function will create a new session_lifecycle again.
         Ok(disconnect_reason)
@@ -1544,6 +2996,29 @@ impl MoonlightClient {
         }
     }
 
+    /// Pings the systemd watchdog for as long as the client runs, whether
+    /// connected or not, but only if the client loop has refreshed since the
+    /// last ping. That's the session's loop, keeping up with the timer's
+    /// refreshes, or the wait between sessions.
+    fn watchdog_proc(
+        shutdown_flag: Arc<AtomicBool>,
+        diagnostics: Arc<Diagnostics>,
+        interval: Duration,
+    ) {
+        let mut last_watchdog_sent = Instant::now();
+
+        while !shutdown_flag.load(Ordering::SeqCst) {
+            if last_watchdog_sent.elapsed() >= interval {
+                if diagnostics.take_refreshed() {
+                    systemd::watchdog();
+                }
+                last_watchdog_sent = Instant::now();
+            }
+
+            sleep(Duration::from_millis(100));
+        }
+    }
+
     /// To make it easier to test the timer logic separately
     /// the logic is extracted into this function and called
     /// from timer_proc() above.
@@ -1584,9 +3059,24 @@ impl MoonlightClient {
         }
     }
 
+    /// The connection status, along with the diagnostics of the agent
     pub fn status(&self) -> Value {
+        let mut status = self.connection_status();
+        status["diagnostics"] = self.diagnostics.to_json();
+        status
+    }
+
+    fn connection_status(&self) -> Value {
         if self.authenticated.load(Ordering::SeqCst) {
-            json!({"connected": true})
+            match *self.negotiated.lock().unwrap() {
+                None => json!({"connected": true}),
+                Some(negotiated) => json!({
+                    "connected": true,
+                    "protocol_version": negotiated.protocol_version,
+                    "capabilities": negotiated.capabilities.names(),
+                    "serialization_format": SerializationFormat::JSON.to_string(),
+                }),
+            }
         } else {
             match *self.disconnected_reason.lock().unwrap() {
                 None => json!({"connected": false}),
@@ -1651,35 +3141,28 @@ impl MoonlightClient {
         Duration::from_millis(reconnect_in as u64)
     }
 
-    pub fn send_cmd(&self, cmd: ClientCmd) {
-        let mailbox_guard = self.mailbox_chan.lock();
-
-        let sent = if let Ok(mailbox_guard) = mailbox_guard
-            && mailbox_guard.is_some()
-        {
-            let mailbox_chan = mailbox_guard.as_ref().unwrap();
-            let send_event = mailbox_chan.send(ClientEvent::Cmd(cmd.clone()));
-
-            match send_event {
-                Err(_) => None,
-                Ok(_) => Some(()),
-            }
-        } else {
-            None
-        };
+    pub fn send_cmd(&self, cmd: ClientCmd, opts: CmdOpts) {
+        let mailbox_chan = self.mailbox_chan.lock().unwrap();
 
-        // If there's a failure to send or the mailbox_chan
-        // is not available, we need to immediately write an
-        // error on the return channel
-        if sent.is_none() {
-            let chan = match cmd {
-                ClientCmd::SendPulse(_, _, _, return_chan) => return_chan,
-                ClientCmd::MailboxNext(_, return_chan) => return_chan,
-                ClientCmd::MailOp(_, _, return_chan) => return_chan,
-            };
+        // Checked while holding the mailbox, so that nothing
+        // is carried over once start() has turned the rest away
+        if self.shutdown_flag.load(Ordering::SeqCst) {
+            let e = GeneralErrors::ShuttingDown.to_string();
+            let _ = cmd.return_chan().send(ReturnChanResult::Busy(e));
+            return;
+        }
 
-            let _ = chan.send(ReturnChanResult::Err("mailbox write failed".to_string()));
+        if let Some(mailbox_chan) = mailbox_chan.as_ref()
+            && mailbox_chan
+                .send(ClientEvent::Cmd(cmd.clone(), opts.clone()))
+                .is_ok()
+        {
+            return;
         }
+
+        // Between sessions, the command waits for the next one
+        let txn = PendingTxn::for_cmd(cmd, opts);
+        self.carried.lock().unwrap().push(txn);
     }
 }
 
@@ -1809,11 +3292,11 @@ mod tests {
         assert_eq!(len, bytes.len()); // All bytes consumed
         assert_eq!(packet, decoded);
 
-        let mut codec = Codec::new();
-        codec.feed(&bytes);
+        let mut codec = Codec::new(CodecLimits::default());
+        codec.feed(&bytes).unwrap();
         let packets = codec.process_packets().unwrap();
         assert_eq!(packets.len(), 1);
-        assert_eq!(packets[0], packet);
+        assert_eq!(packets[0].packet, packet);
     }
 
     #[test]
@@ -1856,6 +3339,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_connect_with_capabilities() {
+        let fleet_id = gen_fleet_id();
+        let device_id = gen_device_id();
+        let device_secret = gen_device_secret();
+
+        let mut bytes: Vec<u8> = vec![
+            2,           // Packet Number
+            0b0000_0101, // Flags: Capabilities = Batch, Keep Alive = True
+            2,           // Highest Protocol Version
+            2,           // Serialization Format = Default JSON
+        ];
+
+        bytes.extend_from_slice(fleet_id.as_bytes());
+        bytes.extend_from_slice(device_id.as_bytes());
+        bytes.extend_from_slice(device_secret.as_bytes());
+
+        let (packet, _creds) = P::connect_with_capabilities(
+            fleet_id,
+            device_id,
+            device_secret,
+            true,
+            PROTOCOL_VERSION,
+            Capabilities::BATCH,
+        )
+        .unwrap();
+
+        cmp(packet, &bytes);
+    }
+
     #[test]
     fn test_connected() {
         cmp(P::connected(false, false), &[3, 0]);
@@ -1864,6 +3377,49 @@ mod tests {
         cmp(P::connected(true, true), &[3, 3]);
     }
 
+    #[test]
+    fn test_connected_negotiated() {
+        cmp(
+            P::connected_negotiated(false, true, 2, Capabilities::NONE),
+            &[3, 5, 2, 0],
+        );
+        cmp(
+            P::connected_negotiated(true, true, 2, Capabilities::EXTENDED_FLAGS),
+            &[3, 7, 2, 1],
+        );
+    }
+
+    #[test]
+    fn test_negotiation() {
+        assert_eq!(
+            ServerResp::handle_packet(P::connected(true, true).into()),
+            ServerResp::Connected(true, Negotiated::LEGACY)
+        );
+
+        // The server can never select a newer version or a capability
+        // that the client did not advertise.
+        let packet = P::connected_negotiated(false, true, 9, Capabilities::from_bits(0b11111));
+        let ServerResp::Connected(false, negotiated) = ServerResp::handle_packet(packet.into())
+        else {
+            panic!("expected Connected");
+        };
+        assert_eq!(negotiated.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities, Capabilities::SUPPORTED);
+
+        let packet = P::connected_negotiated(false, true, 0, Capabilities::NONE);
+        let ServerResp::Connected(false, negotiated) = ServerResp::handle_packet(packet.into())
+        else {
+            panic!("expected Connected");
+        };
+        assert_eq!(negotiated.protocol_version, 1);
+
+        let caps = Capabilities::EXTENDED_FLAGS;
+        assert!(caps.contains(Capabilities::EXTENDED_FLAGS));
+        assert!(!caps.contains(Capabilities::BATCH));
+        assert_eq!(caps.names(), vec!["extended_flags"]);
+        assert!(Capabilities::NONE.names().is_empty());
+    }
+
     #[test]
     fn test_unauthorized() {
         use super::UnauthorizedError as UE;
@@ -1967,6 +3523,54 @@ mod tests {
         cmp_pulse_resp_error(PulseErrorReason::PacketSchemaTypeMismatch);
     }
 
+    #[test]
+    fn test_pulse_batch() {
+        let txn_id = txn_id();
+        let pulses = vec![
+            BatchPulse::new(PulseType::Data, "temp".into(), "{}".into()),
+            BatchPulse::new(PulseType::Msg, "hi".into(), "".into()),
+        ];
+
+        let mut bytes = vec![12, 0];
+        bytes.extend_from_slice(&txn_id.to_be_bytes());
+        bytes.extend_from_slice(&2u16.to_be_bytes());
+        bytes.extend_from_slice(&[PulseType::Data as u8, 4, b't', b'e', b'm', b'p']);
+        bytes.extend_from_slice(&2u32.to_be_bytes());
+        bytes.extend_from_slice(b"{}");
+        bytes.extend_from_slice(&[PulseType::Msg as u8, 2, b'h', b'i']);
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+        cmp(P::pulse_batch(txn_id, pulses.clone()), &bytes);
+
+        let device_id = gen_device_id();
+        let child = ChildId::new(&device_id).unwrap();
+        let mut bytes = vec![12, 0b0100_0000];
+        bytes.extend_from_slice(device_id.as_bytes());
+        bytes.extend_from_slice(&txn_id.to_be_bytes());
+        bytes.extend_from_slice(&0u16.to_be_bytes());
+        cmp(
+            P::pulse_batch(txn_id, vec![]).for_child(Some(child)),
+            &bytes,
+        );
+    }
+
+    #[test]
+    fn test_pulse_batch_resp() {
+        let txn_id = txn_id();
+        let results = vec![Ok(()), Err(PulseErrorReason::PacketSchemaNotFound), Ok(())];
+
+        let mut bytes = vec![13, 0];
+        bytes.extend_from_slice(&txn_id.to_be_bytes());
+        bytes.extend_from_slice(&3u16.to_be_bytes());
+        bytes.extend_from_slice(&[1, 0, PulseErrorReason::PacketSchemaNotFound as u8, 1]);
+        cmp(P::pulse_batch_resp(txn_id, results.clone()), &bytes);
+
+        let (p, _) = Codec::decode(&bytes).unwrap().unwrap();
+        assert_eq!(
+            ServerResp::handle_packet(p.into()),
+            ServerResp::PulseBatchResp(txn_id, results)
+        );
+    }
+
     fn make_vec_with_txn_id(magic: u8, flag: u8, txn_id: u64) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&[magic, flag]);
@@ -2086,6 +3690,47 @@ mod tests {
         cmp_ack_mail_resp(false, AT::Requeue);
     }
 
+    #[test]
+    fn test_virtual_device_packets() {
+        let device_id = gen_device_id();
+        let child = ChildId::new(&device_id).unwrap();
+        assert_eq!(child.to_string(), device_id);
+        assert!(ChildId::new("short").is_err());
+
+        let txn_id = txn_id();
+        let pulse_id = pulse_id();
+
+        let mut bytes = vec![10, 0b0100_0000];
+        bytes.extend_from_slice(device_id.as_bytes());
+        bytes.push(PulseType::Data as u8);
+        bytes.extend_from_slice(&txn_id.to_be_bytes());
+        bytes.extend_from_slice(&[4, b'n', b'a', b'm', b'e']);
+        bytes.extend_from_slice(&2u32.to_be_bytes());
+        bytes.extend_from_slice(b"{}");
+        let pulse = P::pulse(PulseType::Data, txn_id, "name".into(), "{}".into());
+        cmp(pulse.for_child(Some(child)), &bytes);
+
+        let mut bytes = vec![21, 0b0100_0001];
+        bytes.extend_from_slice(device_id.as_bytes());
+        bytes.extend_from_slice(&txn_id.to_be_bytes());
+        cmp(P::mailbox_next(true, txn_id).for_child(Some(child)), &bytes);
+
+        let mut bytes = vec![25, 0b0100_0000];
+        bytes.extend_from_slice(device_id.as_bytes());
+        bytes.extend_from_slice(&pulse_id.to_be_bytes());
+        bytes.push(MailAckType::Reject as u8);
+        let ack = P::ack_mail(pulse_id, MailAckType::Reject);
+        cmp(ack.for_child(Some(child)), &bytes);
+
+        let mut bytes = vec![20, 0b0100_0000];
+        bytes.extend_from_slice(device_id.as_bytes());
+        cmp(P::new_mail_event().for_child(Some(child)), &bytes);
+
+        // Packets that aren't device-scoped are left untouched
+        assert_eq!(P::heartbeat().for_child(Some(child)), P::heartbeat());
+        assert_eq!(P::new_mail_event().for_child(None), P::new_mail_event());
+    }
+
     #[test]
     fn test_partial_message() {
         // let (mut client, server) = duplex(1024);
@@ -2106,18 +3751,18 @@ mod tests {
 
         let packet_bytes = packet.to_bytes().unwrap();
 
-        let mut codec = Codec::new();
-        codec.feed(&packet_bytes[..10]);
+        let mut codec = Codec::new(CodecLimits::default());
+        codec.feed(&packet_bytes[..10]).unwrap();
 
         // Ensure process_packets() succeeds but returns an empty vec
         let packets = codec.process_packets().unwrap();
         assert_eq!(packets.len(), 0);
 
         // Write the rest
-        codec.feed(&packet_bytes[10..]);
+        codec.feed(&packet_bytes[10..]).unwrap();
         let packets = codec.process_packets().unwrap();
         assert_eq!(packets.len(), 1);
-        assert_eq!(packets[0], packet);
+        assert_eq!(packets[0].packet, packet);
     }
 
     #[test]
@@ -2137,13 +3782,88 @@ mod tests {
         bytes.extend_from_slice(&heartbeat.to_bytes().unwrap());
         bytes.extend_from_slice(&pulse.to_bytes().unwrap());
 
-        let mut codec = Codec::new();
-        codec.feed(&bytes);
+        let mut codec = Codec::new(CodecLimits::default());
+        codec.feed(&bytes).unwrap();
         let packets = codec.process_packets().unwrap();
 
-        assert_eq!(packets[0], connect);
-        assert_eq!(packets[1], heartbeat);
-        assert_eq!(packets[2], pulse);
+        assert_eq!(packets[0].packet, connect);
+        assert_eq!(packets[1].packet, heartbeat);
+        assert_eq!(packets[2].packet, pulse);
+    }
+
+    #[test]
+    fn test_burst_of_packets() {
+        let pulse = P::pulse(PulseType::Data, txn_id(), "a".into(), "x".repeat(1000));
+        let pulse_bytes = Codec::encode(&pulse).unwrap();
+
+        let mut bytes = Vec::new();
+        for txn_id in 0..10_000 {
+            bytes.extend_from_slice(&Codec::encode(&P::pulse_resp_success(txn_id)).unwrap());
+        }
+        bytes.extend_from_slice(&pulse_bytes[..500]);
+
+        let mut codec = Codec::new(CodecLimits::default());
+        codec.feed(&bytes).unwrap();
+        let frames = codec.process_packets().unwrap();
+        assert_eq!(frames.len(), 10_000);
+        assert_eq!(frames[9_999].packet, P::pulse_resp_success(9_999));
+
+        // Only the partial pulse is left behind
+        assert_eq!(codec.buffer, pulse_bytes[..500]);
+
+        codec.feed(&pulse_bytes[500..]).unwrap();
+        let frames = codec.process_packets().unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].packet, pulse);
+
+        // Memory held for the burst is released once the buffer is empty
+        assert!(codec.buffer.is_empty());
+        assert!(codec.buffer.capacity() <= Codec::INITIAL_CAPACITY);
+    }
+
+    /// Decoding throughput for a burst of small packets, compared with
+    /// shifting the buffer after every packet as the decoder used to.
+    /// Run on target boards with `cargo test --release -- --ignored bench_decode_burst --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_decode_burst() {
+        const PACKETS: usize = 200_000;
+        const CHUNK: usize = 64 * 1024;
+
+        let packet = Codec::encode(&P::pulse_resp_success(1)).unwrap();
+        let bytes = packet.repeat(PACKETS);
+
+        let start = std::time::Instant::now();
+        let mut codec = Codec::new(CodecLimits::default());
+        let mut decoded = 0;
+        for chunk in bytes.chunks(CHUNK) {
+            codec.feed(chunk).unwrap();
+            decoded += codec.process_packets().unwrap().len();
+        }
+        let cursor = start.elapsed();
+        assert_eq!(decoded, PACKETS);
+
+        let start = std::time::Instant::now();
+        let mut buffer = Vec::new();
+        let mut decoded = 0;
+        for chunk in bytes.chunks(CHUNK) {
+            buffer.extend_from_slice(chunk);
+            while let Some((_, consumed)) =
+                Codec::decode_frame(&buffer, CodecLimits::default()).unwrap()
+            {
+                buffer.drain(..consumed);
+                decoded += 1;
+            }
+        }
+        let shifting = start.elapsed();
+        assert_eq!(decoded, PACKETS);
+
+        println!(
+            "decoded {PACKETS} packets: cursor={cursor:?} ({:.0} packets/s) shifting={shifting:?} ({:.0} packets/s) speedup={:.1}x",
+            PACKETS as f64 / cursor.as_secs_f64(),
+            PACKETS as f64 / shifting.as_secs_f64(),
+            shifting.as_secs_f64() / cursor.as_secs_f64()
+        );
     }
 
     #[test]
@@ -2151,12 +3871,187 @@ mod tests {
         let e = Codec::decode(&[255, 0]).unwrap_err();
         assert!(e.to_string().contains("Failed to decode packet: "));
 
-        let mut codec = Codec::new();
-        codec.feed(&[255, 0]);
+        let mut codec = Codec::new(CodecLimits::default());
+        codec.feed(&[255, 0]).unwrap();
         let e = codec.process_packets().unwrap_err();
         assert!(e.to_string().contains("Failed to decode packet: "));
     }
 
+    #[test]
+    fn test_frame_limits() {
+        let limits = CodecLimits {
+            max_frame_size: 64,
+            max_buffer_size: 128,
+        };
+
+        // A pulse is rejected as soon as its payload length is known
+        let pulse = P::pulse(PulseType::Data, txn_id(), "name".into(), "x".repeat(100));
+        let bytes = Codec::encode(&pulse).unwrap();
+        let size = bytes.len();
+        let e = Codec::decode_frame(&bytes[..20], limits).unwrap_err();
+        let e = e.downcast::<FrameLimitError>().unwrap();
+        assert_eq!(e, FrameLimitError::FrameTooLarge { size, limit: 64 });
+
+        // Until then, the pulse is simply incomplete
+        assert!(Codec::decode_frame(&bytes[..15], limits).unwrap().is_none());
+
+        // Frames within the limit decode as usual
+        let pulse = P::pulse(PulseType::Data, txn_id(), "name".into(), "x".repeat(10));
+        let bytes = Codec::encode(&pulse).unwrap();
+        let (frame, _) = Codec::decode_frame(&bytes, limits).unwrap().unwrap();
+        assert_eq!(frame.packet, pulse);
+
+        // A mail's payload length is checked the same way
+        let resp = P::mailbox_next_resp_full(txn_id(), 1, pulse_id(), "n".into(), "x".repeat(100));
+        let bytes = Codec::encode(&resp).unwrap();
+        let e = Codec::decode_frame(&bytes[..40], limits).unwrap_err();
+        assert!(e.to_string().starts_with("frame_too_large"));
+
+        // A corrupt length prefix doesn't leave the decoder waiting for 4 GiB
+        let mut bytes = vec![10, 0, PulseType::Data as u8];
+        bytes.extend_from_slice(&txn_id().to_be_bytes());
+        bytes.extend_from_slice(&[0]);
+        bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+        let e = Codec::decode_frame(&bytes, CodecLimits::default()).unwrap_err();
+        assert!(e.to_string().starts_with("frame_too_large"));
+
+        // The buffer is bounded regardless of what it contains
+        let mut codec = Codec::new(limits);
+        codec.feed(&[0; 100]).unwrap();
+        let e = codec.feed(&[0; 29]).unwrap_err();
+        assert_eq!(
+            e,
+            FrameLimitError::BufferOverflow {
+                size: 129,
+                limit: 128
+            }
+        );
+    }
+
+    #[test]
+    fn test_extended_flags() {
+        let device_id = gen_device_id();
+        let child = ChildId::new(&device_id).unwrap();
+        let packet = P::new_mail_event().for_child(Some(child));
+
+        // The first flags byte has the continuation bit set,
+        // followed by two extended flags bytes.
+        let mut bytes = vec![20, 0b1100_0000, 0b1000_0001, 0b0100_0000];
+        bytes.extend_from_slice(device_id.as_bytes());
+
+        let (frame, consumed) = Codec::decode_frame(&bytes, CodecLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(consumed, bytes.len());
+        assert_eq!(frame.packet, packet);
+        assert_eq!(
+            frame.flags,
+            Flags(vec![0b0100_0000, 0b0000_0001, 0b0100_0000])
+        );
+
+        let encoded = Codec::encode_with_flags(&frame.packet, &frame.flags).unwrap();
+        assert_eq!(encoded, bytes);
+
+        // Packets without extended flags are encoded as before
+        let flags = Flags(vec![0b0100_0000]);
+        let encoded = Codec::encode_with_flags(&packet, &flags).unwrap();
+        assert_eq!(encoded, Codec::encode(&packet).unwrap());
+
+        // Extended flags split across reads
+        let mut codec = Codec::new(CodecLimits::default());
+        codec.feed(&bytes[..3]).unwrap();
+        assert!(codec.process_packets().unwrap().is_empty());
+        codec.feed(&bytes[3..]).unwrap();
+        codec
+            .feed(&Codec::encode(&P::heartbeat_ack(true)).unwrap())
+            .unwrap();
+        let frames = codec.process_packets().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], frame);
+        assert_eq!(frames[1].packet, P::heartbeat_ack(true));
+        assert_eq!(frames[1].flags, Flags(vec![0b0000_0001]));
+
+        // Back to back extended frames, larger than the frame limit together
+        let limits = CodecLimits {
+            max_frame_size: 1024,
+            max_buffer_size: 8192,
+        };
+
+        let pulses = [
+            P::pulse(PulseType::Data, 1, "a".into(), "x".repeat(700)),
+            P::pulse(PulseType::Data, 2, "b".into(), "y".repeat(700)),
+        ];
+
+        let mut bytes = Vec::new();
+        for pulse in &pulses {
+            let flags = Flags(vec![pulse.to_bytes().unwrap()[1], 0b0000_0001]);
+            bytes.extend(Codec::encode_with_flags(pulse, &flags).unwrap());
+        }
+        assert!(bytes.len() > limits.max_frame_size);
+
+        let mut codec = Codec::new(limits);
+        codec.feed(&bytes).unwrap();
+        let frames = codec.process_packets().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].packet, pulses[0]);
+        assert_eq!(frames[1].packet, pulses[1]);
+        assert!(codec.buffer.is_empty());
+
+        // A partial extended frame waits for the rest of its declared length
+        let flags = Flags(vec![pulses[0].to_bytes().unwrap()[1], 0b0000_0001]);
+        let bytes = Codec::encode_with_flags(&pulses[0], &flags).unwrap();
+        assert_eq!(Codec::decode_frame(&bytes[..600], limits).unwrap(), None);
+        let (frame, consumed) = Codec::decode_frame(&bytes, limits).unwrap().unwrap();
+        assert_eq!(consumed, bytes.len());
+        assert_eq!(frame.packet, pulses[0]);
+
+        // An extended frame without a declared length, past the first copy
+        let packet = P::pulse_batch_resp(3, vec![Ok(()); 2000]);
+        let flags = Flags(vec![packet.to_bytes().unwrap()[1], 0b0000_0001]);
+        let bytes = Codec::encode_with_flags(&packet, &flags).unwrap();
+        assert!(bytes.len() > Codec::HEADER_PEEK);
+        assert_eq!(Codec::decode_frame(&bytes[..1000], limits).unwrap(), None);
+
+        let limits = CodecLimits::default();
+        let (frame, consumed) = Codec::decode_frame(&bytes, limits).unwrap().unwrap();
+        assert_eq!(consumed, bytes.len());
+        assert_eq!(frame.packet, packet);
+
+        // Too many flags bytes
+        let mut bytes = vec![9];
+        bytes.extend_from_slice(&[0b1000_0000; MAX_FLAGS_BYTES]);
+        bytes.push(0);
+        let e = Codec::decode(&bytes).unwrap_err();
+        assert!(e.to_string().contains("Too many flags bytes"));
+    }
+
+    #[test]
+    fn test_unknown_extended_flags() {
+        // An extended flag from a newer protocol on a pulse response
+        let packet = P::pulse_resp_success(7);
+        let mut bytes = Codec::encode(&packet).unwrap();
+        bytes[1] |= 0b1000_0000;
+        bytes.insert(2, 0b0010_0000);
+
+        let mut codec = Codec::new(CodecLimits::default());
+        codec.feed(&bytes).unwrap();
+        let mut frames = codec.process_packets().unwrap();
+        assert_eq!(frames.len(), 1);
+        let frame = frames.remove(0);
+
+        // Is kept on the frame, and written back out as it was
+        assert_eq!(frame.packet, packet);
+        assert_eq!(frame.flags.extended(), [0b0010_0000]);
+        let encoded = Codec::encode_with_flags(&frame.packet, &frame.flags).unwrap();
+        assert_eq!(encoded, bytes);
+
+        // And doesn't change how the packet is handled
+        assert_eq!(
+            ServerResp::handle_packet(frame),
+            ServerResp::PulseResp(Ok(7))
+        );
+    }
+
     struct Client {
         notify_chan_rx: Receiver<(String, String)>,
         ping_chan_rx: Receiver<()>,
@@ -2179,9 +4074,11 @@ mod tests {
             device_id,
             device_secret,
             prod,
+            CodecLimits::default(),
             notify_chan_tx,
             ping_chan_tx,
             transport_write_chan_tx,
+            Arc::new(Diagnostics::new()),
         )
         .unwrap();
 
@@ -2220,7 +4117,8 @@ mod tests {
         assert_eq!(bytes, &[8, 0]);
 
         let (ret_tx, ret_rx) = channel();
-        logic.pending_txns.insert(1, (Instant::now(), ret_tx));
+        let txn = PendingTxn::new(ret_tx, CmdOpts::default());
+        logic.pending_txns.insert(1, txn);
         assert!(!logic.pending_txns.is_empty());
         logic.resolve_txn(1, R::Ok);
         assert!(logic.pending_txns.is_empty());
@@ -2247,18 +4145,93 @@ mod tests {
         let ago = now - Duration::from_secs(20);
         let (ret_tx, ret_rx) = channel();
         let (ret_tx_2, _ret_rx_2) = channel();
-        logic.pending_txns.insert(1, (ago, ret_tx));
-        logic.pending_txns.insert(2, (now, ret_tx_2));
+        let (ret_tx_3, ret_rx_3) = channel();
+
+        let mut txn = PendingTxn::new(ret_tx, CmdOpts::default());
+        txn.started = ago;
+        logic.pending_txns.insert(1, txn);
+
+        let txn = PendingTxn::new(ret_tx_2, CmdOpts::default());
+        logic.pending_txns.insert(2, txn);
+
+        // A txn with a shorter timeout of its own
+        let opts = CmdOpts {
+            timeout: Duration::from_millis(100),
+            request_id: Some("req-1".to_string()),
+        };
+        let mut txn = PendingTxn::new(ret_tx_3, opts);
+        txn.started = now - Duration::from_millis(200);
+        logic.pending_txns.insert(3, txn);
 
         assert_eq!(logic.process_client_event(ClientEvent::Refresh), None);
         assert!(logic.pending_txns.len() == 1);
 
         let timeout = ret_rx.recv().unwrap();
         assert_eq!(timeout, R::Timeout);
+        assert_eq!(ret_rx_3.recv().unwrap(), R::Timeout);
 
         assert!(logic.pending_txns.contains_key(&2));
     }
 
+    #[test]
+    fn test_client_logic_drain() {
+        let (client, mut logic) = make_client_logic();
+
+        let pulse = |ret_tx| {
+            let cmd = ClientCmd::SendPulse(None, PulseType::Data, "temp".into(), None, ret_tx);
+            ClientEvent::Cmd(cmd, CmdOpts::default())
+        };
+
+        let (ret_tx, ret_rx) = channel();
+        assert_eq!(logic.process_client_event(pulse(ret_tx)), None);
+        let b = client.transport_write_chan_rx.recv().unwrap();
+        assert!(matches!(
+            Codec::decode(&b).unwrap().unwrap().0,
+            P::Pulse { .. }
+        ));
+
+        // A command after the drain started is turned away,
+        // while the pending pulse gets its response before the close
+        let (ret_tx_2, ret_rx_2) = channel();
+        client.chan.send(pulse(ret_tx_2)).unwrap();
+        let pulse_resp = Codec::encode(&P::pulse_resp_success(0)).unwrap();
+        client
+            .chan
+            .send(ClientEvent::TransportRecv(pulse_resp))
+            .unwrap();
+        let server_close = Codec::encode(&P::server_close_connection()).unwrap();
+        client
+            .chan
+            .send(ClientEvent::TransportRecv(server_close))
+            .unwrap();
+
+        let drain = ClientEvent::Drain(Duration::from_secs(1));
+        assert_eq!(
+            logic.process_client_event(drain),
+            Some(DisconnectedReason::NormalDisconnect)
+        );
+
+        assert_eq!(ret_rx.recv().unwrap(), R::Ok);
+        assert!(matches!(ret_rx_2.recv().unwrap(), R::Busy(e) if e.starts_with("shutting_down")));
+
+        let b = client.transport_write_chan_rx.recv().unwrap();
+        let (p, _) = Codec::decode(&b).unwrap().unwrap();
+        assert_eq!(p, P::client_close_connection());
+
+        // A pending pulse is resolved when the session ends during the drain
+        let (ret_tx, ret_rx) = channel();
+        logic.process_client_event(pulse(ret_tx));
+        client.chan.send(ClientEvent::TransportClose).unwrap();
+
+        let drain = ClientEvent::Drain(Duration::from_secs(1));
+        assert_eq!(
+            logic.process_client_event(drain),
+            Some(DisconnectedReason::ForceCloseSocket)
+        );
+        assert_eq!(ret_rx.recv().unwrap(), R::Timeout);
+        assert!(logic.pending_txns.is_empty());
+    }
+
     #[test]
     fn test_client_logic_heartbeat_tick() {
         let (client, mut logic) = make_client_logic();
@@ -2270,15 +4243,43 @@ mod tests {
         let r = logic.start_loop(s);
         assert_eq!(r, DisconnectedReason::ForceCloseSocket);
 
-        let b = client.transport_write_chan_rx.recv().unwrap();
-        let (p, _) = Codec::decode(&b).unwrap().unwrap();
-        assert_eq!(p, P::heartbeat());
+        let b = client.transport_write_chan_rx.recv().unwrap();
+        let (p, _) = Codec::decode(&b).unwrap().unwrap();
+        assert_eq!(p, P::heartbeat());
+
+        let heartbeat_ack = Codec::encode(&P::heartbeat_ack(true)).unwrap();
+        let transport_recv = ClientEvent::TransportRecv(heartbeat_ack);
+        assert_eq!(logic.process_client_event(transport_recv), None);
+
+        client.ping_chan_rx.recv().unwrap();
+    }
+
+    #[test]
+    fn test_client_logic_diagnostics() {
+        let (client, mut logic) = make_client_logic();
+
+        assert_eq!(logic.process_client_event(ClientEvent::HeartbeatTick), None);
+        assert_eq!(logic.diagnostics.to_json()["queued_bytes"], 2);
 
         let heartbeat_ack = Codec::encode(&P::heartbeat_ack(true)).unwrap();
         let transport_recv = ClientEvent::TransportRecv(heartbeat_ack);
         assert_eq!(logic.process_client_event(transport_recv), None);
 
-        client.ping_chan_rx.recv().unwrap();
+        let diagnostics = logic.diagnostics.to_json();
+        assert!(diagnostics["heartbeat_rtt_ms"].is_u64());
+        assert!(diagnostics["last_heartbeat_ack_ms"].is_u64());
+        assert!(logic.heartbeat_sent.is_none());
+
+        // The pending txn count is updated by the event loop
+        let (ret_tx, _ret_rx) = channel();
+        let cmd = ClientCmd::MailboxNext(None, false, ret_tx);
+        let cmd = ClientEvent::Cmd(cmd, CmdOpts::default());
+        client.chan.send(cmd).unwrap();
+        client.chan.send(ClientEvent::TransportClose).unwrap();
+
+        let s = Arc::new(AtomicBool::new(false));
+        assert_eq!(logic.start_loop(s), DisconnectedReason::ForceCloseSocket);
+        assert_eq!(logic.diagnostics.to_json()["pending_txns"], 1);
     }
 
     #[test]
@@ -2287,6 +4288,7 @@ mod tests {
         let (ret_tx, ret_rx) = channel();
 
         let cmd_pulse = ClientCmd::SendPulse(
+            None,
             PulseType::Data,
             "hello".to_string(),
             Some(json!({"world": true})),
@@ -2294,7 +4296,7 @@ mod tests {
         );
 
         assert_eq!(
-            logic.process_client_event(ClientEvent::Cmd(cmd_pulse)),
+            logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default())),
             None
         );
 
@@ -2316,13 +4318,14 @@ mod tests {
         let (ret_tx, ret_rx) = channel();
 
         let cmd_pulse = ClientCmd::SendPulse(
+            None,
             PulseType::Data,
             "bad_packet".to_string(),
             Some(json!({"bad_world": true})),
             ret_tx,
         );
 
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
         let b = client.transport_write_chan_rx.recv().unwrap();
         assert_eq!(b[0], 10);
 
@@ -2340,8 +4343,9 @@ mod tests {
         );
 
         let (ret_tx, _ret_rx) = channel();
-        let cmd_pulse = ClientCmd::SendPulse(PulseType::Msg, "empty_pl".to_string(), None, ret_tx);
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
+        let cmd_pulse =
+            ClientCmd::SendPulse(None, PulseType::Msg, "empty_pl".to_string(), None, ret_tx);
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
         let b = client.transport_write_chan_rx.recv().unwrap();
         assert_eq!(b[0], 10);
     }
@@ -2351,10 +4355,10 @@ mod tests {
         let (client, mut logic) = make_client_logic();
         let (ret_tx, ret_rx) = channel();
 
-        let cmd_pulse = ClientCmd::MailOp(MailAckType::Ack, 1, ret_tx);
+        let cmd_pulse = ClientCmd::MailOp(None, MailAckType::Ack, 1, ret_tx);
 
         assert_eq!(
-            logic.process_client_event(ClientEvent::Cmd(cmd_pulse)),
+            logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default())),
             None
         );
 
@@ -2374,16 +4378,16 @@ mod tests {
         assert_eq!(ret_rx.recv().unwrap(), R::MailAckSuccessful(false));
 
         let (ret_tx, ret_rx) = channel();
-        let cmd_pulse = ClientCmd::MailOp(MailAckType::Ack, 2, ret_tx);
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
+        let cmd_pulse = ClientCmd::MailOp(None, MailAckType::Ack, 2, ret_tx);
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
         let pulse_resp = Codec::encode(&P::ack_mail_resp(1, 2, MailAckType::Ack)).unwrap();
         let transport_recv = ClientEvent::TransportRecv(pulse_resp);
         assert_eq!(logic.process_client_event(transport_recv), None);
         assert_eq!(ret_rx.recv().unwrap(), R::MailAckSuccessful(true));
 
         let (ret_tx, ret_rx) = channel();
-        let cmd_pulse = ClientCmd::MailOp(MailAckType::Ack, 3, ret_tx);
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
+        let cmd_pulse = ClientCmd::MailOp(None, MailAckType::Ack, 3, ret_tx);
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
         let pulse_resp = Codec::encode(&P::ack_mail_resp_failed(3, MailAckType::Ack)).unwrap();
         let transport_recv = ClientEvent::TransportRecv(pulse_resp);
         assert_eq!(logic.process_client_event(transport_recv), None);
@@ -2395,12 +4399,12 @@ mod tests {
         ));
 
         let (ret_tx, _ret_rx) = channel();
-        let cmd_pulse = ClientCmd::MailOp(MailAckType::Ack, 100, ret_tx);
+        let cmd_pulse = ClientCmd::MailOp(None, MailAckType::Ack, 100, ret_tx);
 
         let (ret_tx_2, ret_rx_2) = channel();
-        let cmd_pulse_2 = ClientCmd::MailOp(MailAckType::Ack, 100, ret_tx_2);
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse_2));
+        let cmd_pulse_2 = ClientCmd::MailOp(None, MailAckType::Ack, 100, ret_tx_2);
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse_2, CmdOpts::default()));
 
         let return_value = ret_rx_2.recv().unwrap();
 
@@ -2415,10 +4419,10 @@ mod tests {
 
         // Test mailbox resp empty
         let (ret_tx, ret_rx) = channel();
-        let cmd_pulse = ClientCmd::MailboxNext(true, ret_tx);
+        let cmd_pulse = ClientCmd::MailboxNext(None, true, ret_tx);
 
         assert_eq!(
-            logic.process_client_event(ClientEvent::Cmd(cmd_pulse)),
+            logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default())),
             None
         );
 
@@ -2436,8 +4440,8 @@ mod tests {
 
         // Test mailbox resp failed
         let (ret_tx, ret_rx) = channel();
-        let cmd_pulse = ClientCmd::MailboxNext(true, ret_tx);
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
+        let cmd_pulse = ClientCmd::MailboxNext(None, true, ret_tx);
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
         let pulse_resp = Codec::encode(&P::mailbox_next_resp_failed(1)).unwrap();
         let transport_recv = ClientEvent::TransportRecv(pulse_resp);
         assert_eq!(logic.process_client_event(transport_recv), None);
@@ -2449,8 +4453,8 @@ mod tests {
 
         // Test mailbox resp header-only
         let (ret_tx, ret_rx) = channel();
-        let cmd_pulse = ClientCmd::MailboxNext(true, ret_tx);
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
+        let cmd_pulse = ClientCmd::MailboxNext(None, true, ret_tx);
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
         let pulse_resp = Codec::encode(&P::mailbox_next_resp_header_only(
             2,
             3,
@@ -2469,8 +4473,8 @@ mod tests {
         // Test mailbox resp full
 
         let (ret_tx, ret_rx) = channel();
-        let cmd_pulse = ClientCmd::MailboxNext(false, ret_tx);
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
+        let cmd_pulse = ClientCmd::MailboxNext(None, false, ret_tx);
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
         let pulse_resp = Codec::encode(&P::mailbox_next_resp_full(
             3,
             3,
@@ -2496,8 +4500,8 @@ mod tests {
 
         // Test mailbox resp full with empty payload
         let (ret_tx, ret_rx) = channel();
-        let cmd_pulse = ClientCmd::MailboxNext(false, ret_tx);
-        logic.process_client_event(ClientEvent::Cmd(cmd_pulse));
+        let cmd_pulse = ClientCmd::MailboxNext(None, false, ret_tx);
+        logic.process_client_event(ClientEvent::Cmd(cmd_pulse, CmdOpts::default()));
         let pulse_resp = Codec::encode(&P::mailbox_next_resp_full(
             4,
             3,
@@ -2515,6 +4519,191 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_client_logic_pending_txns_limit() {
+        let (client, mut logic) = make_client_logic();
+        logic.max_pending_txns = 8;
+
+        let mut return_chans = Vec::new();
+        for _ in 0..8 {
+            let (ret_tx, ret_rx) = channel();
+            let cmd = ClientCmd::MailboxNext(None, false, ret_tx);
+            assert_eq!(
+                logic.process_client_event(ClientEvent::Cmd(cmd, CmdOpts::default())),
+                None
+            );
+            return_chans.push(ret_rx);
+        }
+
+        assert_eq!(logic.pending_txns.len(), 8);
+
+        let (ret_tx, ret_rx) = channel();
+        let cmd = ClientCmd::SendPulse(None, PulseType::Data, "a".into(), None, ret_tx);
+        assert_eq!(
+            logic.process_client_event(ClientEvent::Cmd(cmd, CmdOpts::default())),
+            None
+        );
+        assert!(
+            matches!(ret_rx.recv().unwrap(), R::Busy(str) if str.starts_with("too_many_pending_requests"))
+        );
+        assert_eq!(client.transport_write_chan_rx.try_iter().count(), 8);
+
+        // Room frees up once a response arrives
+        let bytes = Codec::encode(&P::mailbox_next_resp_empty(0)).unwrap();
+        assert_eq!(
+            logic.process_client_event(ClientEvent::TransportRecv(bytes)),
+            None
+        );
+        assert_eq!(return_chans[0].recv().unwrap(), R::Mail(None));
+
+        let (ret_tx, _ret_rx) = channel();
+        let cmd = ClientCmd::SendPulse(None, PulseType::Data, "a".into(), None, ret_tx);
+        assert_eq!(
+            logic.process_client_event(ClientEvent::Cmd(cmd, CmdOpts::default())),
+            None
+        );
+        assert_eq!(logic.pending_txns.len(), 8);
+    }
+
+    #[test]
+    fn test_client_logic_virtual_device() {
+        let (client, mut logic) = make_client_logic();
+        let child = ChildId::new(&gen_device_id()).unwrap();
+
+        // Rejected until the server accepts the virtual_devices capability
+        let (ret_tx, ret_rx) = channel();
+        let cmd = ClientCmd::MailboxNext(Some(child), false, ret_tx);
+        assert_eq!(
+            logic.process_client_event(ClientEvent::Cmd(cmd, CmdOpts::default())),
+            None
+        );
+        assert!(
+            matches!(ret_rx.recv().unwrap(), R::Err(str) if str.starts_with("virtual_devices_unsupported"))
+        );
+        assert!(logic.pending_txns.is_empty());
+        assert!(client.transport_write_chan_rx.try_recv().is_err());
+
+        logic.negotiated = Negotiated {
+            protocol_version: 2,
+            capabilities: Capabilities::VIRTUAL_DEVICES,
+        };
+
+        let (ret_tx, ret_rx) = channel();
+        let cmd = ClientCmd::SendPulse(Some(child), PulseType::Data, "a".into(), None, ret_tx);
+        assert_eq!(
+            logic.process_client_event(ClientEvent::Cmd(cmd, CmdOpts::default())),
+            None
+        );
+
+        let b = client.transport_write_chan_rx.recv().unwrap();
+        let (p, _) = Codec::decode(&b).unwrap().unwrap();
+        assert!(matches!(
+            p,
+            P::Pulse { virtual_device: true, child_id: Some(id), .. } if id == child.0
+        ));
+
+        let pulse_resp = Codec::encode(&P::pulse_resp_success(0)).unwrap();
+        let transport_recv = ClientEvent::TransportRecv(pulse_resp);
+        assert_eq!(logic.process_client_event(transport_recv), None);
+        assert_eq!(ret_rx.recv().unwrap(), R::Ok);
+
+        let bytes = Codec::encode(&P::new_mail_event().for_child(Some(child))).unwrap();
+        let transport_recv = ClientEvent::TransportRecv(bytes);
+        assert_eq!(logic.process_client_event(transport_recv), None);
+        let (event, data) = client.notify_chan_rx.recv().unwrap();
+        assert_eq!(event, "device_new_mail");
+        assert_eq!(data, json!({"device_id": child.to_string()}).to_string());
+    }
+
+    #[test]
+    fn test_client_logic_pulse_batch() {
+        let (client, mut logic) = make_client_logic();
+        let pulses = vec![
+            (PulseType::Data, "temp".to_string(), Some(json!({"c": 21}))),
+            (PulseType::Msg, "hello".to_string(), None),
+        ];
+
+        // Rejected until the server accepts the batch capability
+        let (ret_tx, ret_rx) = channel();
+        let cmd = ClientCmd::SendPulseBatch(None, pulses.clone(), ret_tx);
+        assert_eq!(
+            logic.process_client_event(ClientEvent::Cmd(cmd, CmdOpts::default())),
+            None
+        );
+        assert!(
+            matches!(ret_rx.recv().unwrap(), R::Err(str) if str.starts_with("batch_unsupported"))
+        );
+        assert!(client.transport_write_chan_rx.try_recv().is_err());
+
+        logic.negotiated = Negotiated {
+            protocol_version: 2,
+            capabilities: Capabilities::BATCH,
+        };
+
+        let (ret_tx, ret_rx) = channel();
+        let cmd = ClientCmd::SendPulseBatch(None, pulses, ret_tx);
+        assert_eq!(
+            logic.process_client_event(ClientEvent::Cmd(cmd, CmdOpts::default())),
+            None
+        );
+
+        let b = client.transport_write_chan_rx.recv().unwrap();
+        let (p, _) = Codec::decode(&b).unwrap().unwrap();
+        let expected = P::pulse_batch(
+            0,
+            vec![
+                BatchPulse::new(PulseType::Data, "temp".into(), r#"{"c":21}"#.into()),
+                BatchPulse::new(PulseType::Msg, "hello".into(), "".into()),
+            ],
+        );
+        assert_eq!(p, expected);
+
+        let results = vec![Ok(()), Err(PulseErrorReason::PacketSchemaTypeMismatch)];
+        let resp = Codec::encode(&P::pulse_batch_resp(0, results.clone())).unwrap();
+        assert_eq!(
+            logic.process_client_event(ClientEvent::TransportRecv(resp)),
+            None
+        );
+        assert_eq!(ret_rx.recv().unwrap(), R::BatchResults(results));
+        assert!(logic.pending_txns.is_empty());
+    }
+
+    #[test]
+    fn test_client_logic_frame_limits() {
+        let (_client, mut logic) = make_client_logic();
+        logic.codec = Codec::new(CodecLimits {
+            max_frame_size: 64,
+            max_buffer_size: 128,
+        });
+
+        let pulse = P::pulse(PulseType::Msg, txn_id(), "a".into(), "x".repeat(100));
+        let bytes = Codec::encode(&pulse).unwrap();
+        let transport_recv = ClientEvent::TransportRecv(bytes[..20].to_vec());
+
+        assert_eq!(
+            logic.process_client_event(transport_recv),
+            Some(DisconnectedReason::FrameLimitExceeded(
+                FrameLimitError::FrameTooLarge {
+                    size: bytes.len(),
+                    limit: 64
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_client_logic_extended_flags() {
+        let (client, mut logic) = make_client_logic();
+        let flags = Flags(vec![0, 0b0010_0000]);
+        let bytes = Codec::encode_with_flags(&P::new_mail_event(), &flags).unwrap();
+        assert_eq!(bytes, vec![20, 0b1000_0000, 0b0010_0000]);
+
+        let transport_recv = ClientEvent::TransportRecv(bytes);
+        assert_eq!(logic.process_client_event(transport_recv), None);
+        let notification = client.notify_chan_rx.recv().unwrap();
+        assert_eq!(notification, ("new_mail".to_string(), "".to_string()));
+    }
+
     #[test]
     fn test_client_logic_new_mail_available() {
         let (client, mut logic) = make_client_logic();
@@ -2590,6 +4779,169 @@ mod tests {
         let (connect_packet, _) = Codec::decode(&bytes).unwrap().unwrap();
         assert!(matches!(connect_packet, MoonlightPacket::Connect { .. }));
         assert!(logic.authenticated.load(Ordering::SeqCst));
+        assert_eq!(logic.negotiated(), Negotiated::LEGACY);
+    }
+
+    #[test]
+    fn test_client_logic_legacy_connect() {
+        // A server that only speaks version 1 turns away the negotiating CONNECT
+        let (client, mut logic) = make_client_logic();
+        let p = Codec::encode(&P::connect_failed(ConnectFailedError::Unknown)).unwrap();
+        client.chan.send(ClientEvent::TransportRecv(p)).unwrap();
+
+        let reason = logic.wait_for_authentication().unwrap_err();
+        assert!(ClientLogic::connect_rejected(reason));
+        let bytes = client.transport_write_chan_rx.recv().unwrap();
+        assert_eq!(bytes[2], PROTOCOL_VERSION);
+        assert_ne!(bytes[1], 1);
+
+        // Other failures aren't down to the CONNECT packet
+        let reason = DisconnectedReason::ConnectFailed(ConnectFailedError::ServiceUnavailable);
+        assert!(!ClientLogic::connect_rejected(reason));
+        let reason = DisconnectedReason::Unauthorized(UnauthorizedError::DeviceSecretIncorrect);
+        assert!(!ClientLogic::connect_rejected(reason));
+
+        // And accepts the legacy one
+        let (client, mut logic) = make_client_logic();
+        logic.use_legacy_connect().unwrap();
+        let p = Codec::encode(&P::connected(false, true)).unwrap();
+        client.chan.send(ClientEvent::TransportRecv(p)).unwrap();
+        logic.wait_for_authentication().unwrap();
+
+        let bytes = client.transport_write_chan_rx.recv().unwrap();
+        let (connect_packet, _) = Codec::decode(&bytes).unwrap().unwrap();
+        assert!(matches!(connect_packet, MoonlightPacket::Connect { .. }));
+        assert_eq!(bytes[1..3], [1, 1]);
+        assert!(logic.authenticated.load(Ordering::SeqCst));
+        assert_eq!(logic.negotiated(), Negotiated::LEGACY);
+    }
+
+    #[test]
+    fn test_client_logic_carry_over() {
+        let (client, mut logic) = make_client_logic();
+
+        let pulse = |name: &str, ret_tx| {
+            let cmd = ClientCmd::SendPulse(None, PulseType::Data, name.into(), None, ret_tx);
+            ClientEvent::Cmd(cmd, CmdOpts::default())
+        };
+
+        // A pulse and a mail fetch awaiting a response,
+        // and a pulse left in the mailbox
+        let (ret_tx_1, ret_rx_1) = channel();
+        let (ret_tx_2, ret_rx_2) = channel();
+        let (ret_tx_3, ret_rx_3) = channel();
+        logic.process_client_event(pulse("first", ret_tx_1));
+        let next = ClientCmd::MailboxNext(None, false, ret_tx_2);
+        logic.process_client_event(ClientEvent::Cmd(next, CmdOpts::default()));
+        client.chan.send(pulse("second", ret_tx_3)).unwrap();
+
+        // And one that has timed out while reconnecting
+        let (ret_tx_4, ret_rx_4) = channel();
+        let cmd = ClientCmd::MailboxNext(None, false, ret_tx_4);
+        let mut expired = PendingTxn::for_cmd(cmd, CmdOpts::default());
+        expired.started -= Duration::from_secs(20);
+
+        let mut carried = logic.unresolved(DisconnectedReason::ForceCloseSocket);
+        assert_eq!(carried.len(), 3);
+        assert!(logic.pending_txns.is_empty());
+        assert!(ret_rx_1.try_recv().is_err());
+        carried.push(expired);
+
+        // The next session continues the txn_ids
+        let next_txn_id = logic.next_txn_id;
+        let (client, mut logic) = make_client_logic();
+        logic.next_txn_id = next_txn_id;
+        logic.carry_over(carried);
+
+        let p = Codec::encode(&P::connected(false, true)).unwrap();
+        client.chan.send(ClientEvent::TransportRecv(p)).unwrap();
+        logic.wait_for_authentication().unwrap();
+
+        let mut sent = vec![];
+        for _ in 0..4 {
+            let b = client.transport_write_chan_rx.recv().unwrap();
+            match Codec::decode(&b).unwrap().unwrap().0 {
+                P::Pulse { name, txn_id, .. } => {
+                    sent.push((String::from_utf8(name).unwrap(), txn_id))
+                }
+                P::MailboxNext { txn_id, .. } => sent.push(("mailbox_next".to_string(), txn_id)),
+                p => assert!(matches!(p, P::Connect { .. })),
+            }
+        }
+
+        // The pulse that was already written is sent again with the same txn_id,
+        // for the server to drop if it was recorded
+        let sent: Vec<(&str, u64)> = sent.iter().map(|(n, id)| (n.as_str(), *id)).collect();
+        assert_eq!(sent, [("first", 0), ("mailbox_next", 2), ("second", 3)]);
+        assert!(client.transport_write_chan_rx.try_recv().is_err());
+        let e = GeneralErrors::NotConnected.to_string();
+        assert_eq!(ret_rx_4.recv().unwrap(), R::Busy(e));
+
+        // Resolved on the original return channels
+        let resp = Codec::encode(&P::mailbox_next_resp_empty(2)).unwrap();
+        logic.process_client_event(ClientEvent::TransportRecv(resp));
+        let resp = Codec::encode(&P::pulse_resp_success(3)).unwrap();
+        logic.process_client_event(ClientEvent::TransportRecv(resp));
+        assert_eq!(ret_rx_2.recv().unwrap(), R::Mail(None));
+        assert_eq!(ret_rx_3.recv().unwrap(), R::Ok);
+
+        // A pulse is only sent again the once
+        assert!(
+            logic
+                .unresolved(DisconnectedReason::ForceCloseSocket)
+                .is_empty()
+        );
+        let e = GeneralErrors::PulseOutcomeUnknown.to_string();
+        assert_eq!(ret_rx_1.recv().unwrap(), R::Err(e));
+    }
+
+    #[test]
+    fn test_client_logic_carry_over_reconfigured() {
+        let (client, mut logic) = make_client_logic();
+
+        // A mail fetch awaiting a response, and a pulse left in the mailbox
+        let (ret_tx_1, ret_rx_1) = channel();
+        let (ret_tx_2, ret_rx_2) = channel();
+        let next = ClientCmd::MailboxNext(None, false, ret_tx_1);
+        logic.process_client_event(ClientEvent::Cmd(next, CmdOpts::default()));
+        let pulse = ClientCmd::SendPulse(None, PulseType::Data, "a".into(), None, ret_tx_2);
+        client
+            .chan
+            .send(ClientEvent::Cmd(pulse, CmdOpts::default()))
+            .unwrap();
+
+        // Neither is sent on behalf of the reconfigured device
+        assert!(
+            logic
+                .unresolved(DisconnectedReason::Reconfigured)
+                .is_empty()
+        );
+
+        let e = GeneralErrors::Reconfigured.to_string();
+        assert_eq!(ret_rx_1.recv().unwrap(), R::Err(e.clone()));
+        assert_eq!(ret_rx_2.recv().unwrap(), R::Err(e));
+    }
+
+    #[test]
+    fn test_client_logic_authentication_negotiated() {
+        let (client, mut logic) = make_client_logic();
+        let p = Codec::encode(&P::connected_negotiated(false, true, 2, Capabilities::NONE));
+        client
+            .chan
+            .send(ClientEvent::TransportRecv(p.unwrap()))
+            .unwrap();
+        logic.wait_for_authentication().unwrap();
+
+        let bytes = client.transport_write_chan_rx.recv().unwrap();
+        let (connect_packet, _) = Codec::decode(&bytes).unwrap().unwrap();
+        assert!(matches!(
+            connect_packet,
+            MoonlightPacket::Connect {
+                protocol_version: PROTOCOL_VERSION,
+                ..
+            }
+        ));
+        assert_eq!(logic.negotiated().protocol_version, 2);
     }
 
     #[test]
@@ -2645,12 +4997,10 @@ mod tests {
         let (ret_tx, ret_rx) = channel();
         client
             .chan
-            .send(ClientEvent::Cmd(ClientCmd::SendPulse(
-                PulseType::Msg,
-                "hello".to_string(),
-                None,
-                ret_tx,
-            )))
+            .send(ClientEvent::Cmd(
+                ClientCmd::SendPulse(None, PulseType::Msg, "hello".to_string(), None, ret_tx),
+                CmdOpts::default(),
+            ))
             .unwrap();
 
         let p = Codec::encode(&P::connected(false, false)).unwrap();
@@ -2784,15 +5134,15 @@ mod tests {
 
     #[test]
     fn test_codec_can_handle_partial_packets_correctly() {
-        let mut codec = Codec::new();
-        codec.feed(&[3]);
+        let mut codec = Codec::new(CodecLimits::default());
+        codec.feed(&[3]).unwrap();
         let packets = codec.process_packets().unwrap();
         assert!(packets.is_empty());
-        codec.feed(&[3]);
+        codec.feed(&[3]).unwrap();
         let packets = codec.process_packets().unwrap();
         assert!(!packets.is_empty());
         let target = P::connected(true, true);
-        assert_eq!(*packets.first().unwrap(), target);
+        assert_eq!(packets.first().unwrap().packet, target);
     }
 
     #[test]
@@ -2846,6 +5196,9 @@ mod tests {
             gen_device_id(),
             gen_device_secret(),
             ConnectMode::Local(8484),
+            CodecLimits::default(),
+            DEFAULT_DRAIN_TIMEOUT,
+            DEFAULT_MAX_PENDING_TXNS,
         );
 
         assert!(!m.shutdown_flag.load(Ordering::SeqCst));
@@ -2859,6 +5212,12 @@ mod tests {
         assert!(m.shutdown_flag.load(Ordering::SeqCst));
         assert!(m.status()["connected"] == false);
 
+        // Commands are turned away once stopped
+        let (ret_tx, ret_rx) = channel();
+        let cmd = ClientCmd::MailboxNext(None, false, ret_tx);
+        m.send_cmd(cmd, CmdOpts::default());
+        assert!(matches!(ret_rx.recv().unwrap(), R::Busy(e) if e.starts_with("shutting_down")));
+
         *m.disconnected_reason.lock().unwrap() = Some(DisconnectedReason::ForceCloseSocket);
         assert!(m.status()["connected"] == false);
         assert!(m.status()["error"] == "connect_failed");
@@ -2902,35 +5261,188 @@ mod tests {
         *m.disconnected_reason.lock().unwrap() = None;
         m.authenticated.store(true, Ordering::SeqCst);
         assert!(m.status()["connected"] == true);
+
+        *m.negotiated.lock().unwrap() = Some(Negotiated {
+            protocol_version: 2,
+            capabilities: Capabilities::EXTENDED_FLAGS,
+        });
+        assert_eq!(m.status()["protocol_version"], 2);
+        assert_eq!(m.status()["capabilities"], json!(["extended_flags"]));
+    }
+
+    #[test]
+    fn test_moonlight_client_reconfigure() {
+        let m = MoonlightClient::new(
+            gen_fleet_id(),
+            gen_device_id(),
+            gen_device_secret(),
+            ConnectMode::Local(8484),
+            CodecLimits::default(),
+            DEFAULT_DRAIN_TIMEOUT,
+            DEFAULT_MAX_PENDING_TXNS,
+        );
+
+        let (mailbox_tx, mailbox_rx) = channel();
+        *m.mailbox_chan.lock().unwrap() = Some(mailbox_tx);
+        let settings = m.settings.lock().unwrap().clone();
+
+        // Only the codec limits changed, which apply from the next session
+        let limits = ClientSettings {
+            codec_limits: CodecLimits {
+                max_frame_size: 1024,
+                max_buffer_size: 2048,
+            },
+            ..settings.clone()
+        };
+        assert!(!settings.needs_reconnect(&limits));
+        assert!(!m.reconfigure(limits.clone()));
+        assert!(mailbox_rx.try_recv().is_err());
+        assert_eq!(*m.settings.lock().unwrap(), limits);
+
+        // New credentials end the current session
+        let creds = ClientSettings {
+            device_secret: gen_device_secret(),
+            ..limits
+        };
+        *m.reconnect_in.lock().unwrap() = Some(Duration::from_secs(30));
+        assert!(m.reconfigure(creds));
+        assert!(matches!(mailbox_rx.try_recv(), Ok(ClientEvent::Reconnect)));
+        assert_eq!(*m.reconnect_in.lock().unwrap(), None);
+
+        // And cut the wait before reconnecting short
+        let start = Instant::now();
+        m.wait_to_reconnect(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Which the session ends with, whether connected or still connecting
+        let (_client, mut logic) = make_client_logic();
+        assert_eq!(
+            logic.process_client_event(ClientEvent::Reconnect),
+            Some(DisconnectedReason::Reconfigured)
+        );
+
+        let (client, mut logic) = make_client_logic();
+        client.chan.send(ClientEvent::Reconnect).unwrap();
+        assert_eq!(
+            logic.wait_for_authentication(),
+            Err(DisconnectedReason::Reconfigured)
+        );
+    }
+
+    #[test]
+    fn test_moonlight_client_legacy_connect() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        // A version 1 server, which fails any CONNECT other than the legacy one
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut versions = vec![];
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 256];
+                let n = stream.read(&mut buf).unwrap();
+                assert_eq!(buf[0], 2);
+                versions.push(buf[2]);
+
+                if n > 2 && buf[1..3] == [1, 1] {
+                    let p = Codec::encode(&P::connected(false, true)).unwrap();
+                    stream.write_all(&p).unwrap();
+                    // Hang up once the client closes the connection
+                    let _ = stream.read(&mut buf);
+                } else {
+                    let p = Codec::encode(&P::connect_failed(ConnectFailedError::Unknown)).unwrap();
+                    stream.write_all(&p).unwrap();
+                }
+            }
+            versions
+        });
+
+        let m = MoonlightClient::new(
+            gen_fleet_id(),
+            gen_device_id(),
+            gen_device_secret(),
+            ConnectMode::Local(port),
+            CodecLimits::default(),
+            DEFAULT_DRAIN_TIMEOUT,
+            DEFAULT_MAX_PENDING_TXNS,
+        );
+
+        let mut client = m.clone();
+        let (notify_tx, _notify_rx) = channel();
+        let handle = std::thread::spawn(move || client.start(notify_tx));
+
+        // The first reconnect is immediate
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while m.status()["connected"] != true && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(m.status()["connected"] == true);
+        assert_eq!(m.status()["protocol_version"], 1);
+
+        m.stop();
+        handle.join().unwrap().unwrap();
+        assert_eq!(server.join().unwrap(), [PROTOCOL_VERSION, 1]);
     }
 
     #[test]
-    fn test_moonlight_client_send_cmd_failures() {
+    fn test_moonlight_client_send_cmd_between_sessions() {
         let m = MoonlightClient::new(
             gen_fleet_id(),
             gen_device_id(),
             gen_device_secret(),
             ConnectMode::Local(8484),
+            CodecLimits::default(),
+            DEFAULT_DRAIN_TIMEOUT,
+            DEFAULT_MAX_PENDING_TXNS,
         );
 
-        let e = ReturnChanResult::Err("mailbox write failed".to_string());
+        let short = CmdOpts {
+            timeout: Duration::from_millis(50),
+            ..CmdOpts::default()
+        };
 
-        let (ret_tx, ret_rx) = channel();
-        m.send_cmd(ClientCmd::SendPulse(
-            PulseType::Data,
-            "name".to_string(),
-            Some(json!(null)),
-            ret_tx,
+        // Commands wait for the next session, instead of failing
+        let (ret_tx_1, ret_rx_1) = channel();
+        let cmd = ClientCmd::SendPulse(None, PulseType::Data, "a".into(), None, ret_tx_1);
+        m.send_cmd(cmd, CmdOpts::default());
+        let (ret_tx_2, ret_rx_2) = channel();
+        m.send_cmd(ClientCmd::MailboxNext(None, true, ret_tx_2), short);
+        assert_eq!(m.carried.lock().unwrap().len(), 2);
+        assert!(ret_rx_1.try_recv().is_err());
+        assert!(ret_rx_2.try_recv().is_err());
+
+        // Until they run out of time while reconnecting
+        let start = Instant::now();
+        m.wait_to_reconnect(Duration::from_millis(200));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let e = GeneralErrors::NotConnected.to_string();
+        assert_eq!(ret_rx_2.try_recv().unwrap(), R::Busy(e));
+        assert_eq!(m.carried.lock().unwrap().len(), 1);
+
+        // The next session sends them
+        let (mailbox_tx, mailbox_rx) = channel();
+        *m.mailbox_chan.lock().unwrap() = Some(mailbox_tx);
+        let (ret_tx_3, _ret_rx_3) = channel();
+        let cmd = ClientCmd::MailOp(None, MailAckType::Ack, 1, ret_tx_3);
+        m.send_cmd(cmd, CmdOpts::default());
+        assert!(matches!(
+            mailbox_rx.try_recv(),
+            Ok(ClientEvent::Cmd(ClientCmd::MailOp(..), _))
         ));
-        assert_eq!(ret_rx.recv().unwrap(), e);
-
-        let (ret_tx, ret_rx) = channel();
-        m.send_cmd(ClientCmd::MailboxNext(true, ret_tx));
-        assert_eq!(ret_rx.recv().unwrap(), e);
+        *m.mailbox_chan.lock().unwrap() = None;
 
-        let (ret_tx, ret_rx) = channel();
-        m.send_cmd(ClientCmd::MailOp(MailAckType::Ack, 1, ret_tx));
-        assert_eq!(ret_rx.recv().unwrap(), e);
+        // Unless the device was reconfigured in the meantime
+        let settings = ClientSettings {
+            device_secret: gen_device_secret(),
+            ..m.settings.lock().unwrap().clone()
+        };
+        assert!(m.reconfigure(settings));
+        let e = GeneralErrors::Reconfigured.to_string();
+        assert_eq!(ret_rx_1.recv().unwrap(), R::Err(e));
+        assert!(m.carried.lock().unwrap().is_empty());
     }
 
     #[test]
@@ -2940,6 +5452,9 @@ mod tests {
             gen_device_id(),
             gen_device_secret(),
             ConnectMode::Local(8484),
+            CodecLimits::default(),
+            DEFAULT_DRAIN_TIMEOUT,
+            DEFAULT_MAX_PENDING_TXNS,
         );
 
         assert_eq!(m.backoff(false), Duration::from_millis(0));
@@ -3022,6 +5537,43 @@ mod tests {
         handle.join().unwrap();
     }
 
+    #[test]
+    fn test_watchdog_between_sessions() {
+        let m = MoonlightClient::new(
+            gen_fleet_id(),
+            gen_device_id(),
+            gen_device_secret(),
+            ConnectMode::Local(8484),
+            CodecLimits::default(),
+            DEFAULT_DRAIN_TIMEOUT,
+            DEFAULT_MAX_PENDING_TXNS,
+        );
+
+        // The wait between sessions keeps refreshing, without a connection
+        let diagnostics = m.diagnostics.clone();
+        let waiting = std::thread::spawn(move || m.wait_to_reconnect(Duration::from_millis(2500)));
+
+        sleep(Duration::from_millis(200));
+        assert!(diagnostics.take_refreshed());
+        sleep(Duration::from_millis(1200));
+        assert!(diagnostics.take_refreshed());
+        waiting.join().unwrap();
+
+        // The watchdog takes the refreshes as it pings
+        let stop = Arc::new(AtomicBool::new(false));
+        let (stop_1, diagnostics_1) = (stop.clone(), diagnostics.clone());
+        let watchdog = std::thread::spawn(move || {
+            MoonlightClient::watchdog_proc(stop_1, diagnostics_1, Duration::from_millis(100))
+        });
+
+        diagnostics.refreshed();
+        sleep(Duration::from_millis(300));
+        assert!(!diagnostics.take_refreshed());
+
+        stop.store(true, Ordering::SeqCst);
+        watchdog.join().unwrap();
+    }
+
     fn call_timer_logic(
         last_refresh_sent: Option<Instant>,
         last_heartbeat_sent: Option<Instant>,
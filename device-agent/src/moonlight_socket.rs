@@ -15,15 +15,19 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::moonlight_codec::{ClientEvent, ConnectMode, GeneralErrors};
+use crate::{
+    diagnostics::Diagnostics,
+    moonlight_codec::{ClientEvent, ConnectMode, GeneralErrors},
+};
 
-type TlsStream = StreamOwned<ClientConnection, TcpStream>;
-type Stream = Either<TcpStream, TlsStream>;
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+pub type Stream = Either<TcpStream, TlsStream>;
 
 pub fn connect(
     connect_mode: ConnectMode,
     mailbox_chan: Sender<ClientEvent>,
     write_chan: Receiver<Vec<u8>>,
+    diagnostics: Arc<Diagnostics>,
 ) -> Result<(JoinHandle<()>, impl FnOnce())> {
     // Shutdown Flag
     // An AtomicBool shared between the socket thread and the caller.
@@ -33,11 +37,21 @@ pub fn connect(
     let shutdown_flag_for_thread = shutdown_flag.clone();
     let close = move || shutdown_flag.store(true, Ordering::SeqCst);
 
-    let mut stream: Stream = match connect_mode {
-        ConnectMode::Local(port) => Either::Left(tcp_open(port)?),
-        ConnectMode::Prod => Either::Right(tls_open(8484)?), // default Prod port
+    let (mut stream, endpoint): (Stream, String) = match connect_mode {
+        ConnectMode::Local(port) => (Either::Left(tcp_open(port)?), format!("127.0.0.1:{port}")),
+        ConnectMode::Prod => (
+            Either::Right(tls_open(8484)?), // default Prod port
+            "device.fostrom.dev:8484".to_string(),
+        ),
+    };
+
+    let peer_addr = match &stream {
+        Either::Left(stream) => stream.peer_addr().ok(),
+        Either::Right(stream) => stream.sock.peer_addr().ok(),
     };
 
+    diagnostics.transport_opened(endpoint, peer_addr);
+
     let handle = std::thread::spawn(move || {
         // Main Transport Loop:
         // 1. Reads from write_chan and writes to socket
@@ -50,6 +64,8 @@ pub fn connect(
         let mut pending_buf: Option<Vec<u8>> = None;
         let mut pending_offset: usize = 0;
         let mut pending_since = Instant::now();
+        let mut tls_recorded = false;
+        let mut failed = false;
 
         while !shutdown_flag_for_thread.load(Ordering::SeqCst) {
             if push_bytes_to_socket(
@@ -58,17 +74,44 @@ pub fn connect(
                 &mut pending_buf,
                 &mut pending_offset,
                 &mut pending_since,
+                &diagnostics,
             )
             .is_err()
             {
+                failed = true;
                 break;
             }
 
+            // The TLS handshake completes during the first reads and writes
+            if !tls_recorded
+                && let Either::Right(stream) = &stream
+                && !stream.conn.is_handshaking()
+            {
+                record_tls(&stream.conn, &diagnostics);
+                tls_recorded = true;
+            }
+
             if pull_bytes_from_socket(&mailbox_chan, &mut stream).is_err() {
+                failed = true;
                 break;
             }
         }
 
+        // Closed by the client rather than by a failure,
+        // so write out what's still queued before closing
+        let flush_start = Instant::now();
+        while !failed && diagnostics.queued_bytes() > 0 && flush_start.elapsed() < FLUSH_TIMEOUT {
+            failed = push_bytes_to_socket(
+                &write_chan,
+                &mut stream,
+                &mut pending_buf,
+                &mut pending_offset,
+                &mut pending_since,
+                &diagnostics,
+            )
+            .is_err();
+        }
+
         match stream {
             Either::Left(mut stream) => tcp_close(&mut stream),
             Either::Right(mut stream) => tls_close(&mut stream),
@@ -113,18 +156,37 @@ fn make_tcp_socket(addr: String) -> Result<TcpStream> {
     Ok(socket)
 }
 
-fn tcp_open(port: u16) -> Result<TcpStream> {
+/// Open a plain TCP connection to a local Fostrom server at the given port.
+/// This function is public because it is also directly called by `test-conn`
+pub fn tcp_open(port: u16) -> Result<TcpStream> {
     make_tcp_socket(format!("127.0.0.1:{port}"))
 }
 
 /// Open a TLS connection to device.fostrom.dev at the given port.
-/// This function is public because it is also directly called by `test-conn`
 pub fn tls_open(port: u16) -> Result<TlsStream> {
-    let socket = make_tcp_socket(format!("device.fostrom.dev:{port}"))?;
-    let conn = ClientConnection::new(tls_conf(), "device.fostrom.dev".try_into()?)?;
+    tls_open_host("device.fostrom.dev", port)
+}
+
+/// Open a TLS connection to the given host and port, such as a custom
+/// endpoint passed to `test-conn`.
+pub fn tls_open_host(host: &str, port: u16) -> Result<TlsStream> {
+    let socket = make_tcp_socket(format!("{host}:{port}"))?;
+    let conn = ClientConnection::new(tls_conf(), host.to_string().try_into()?)?;
     Ok(StreamOwned::new(conn, socket))
 }
 
+fn record_tls(conn: &ClientConnection, diagnostics: &Diagnostics) {
+    let version = conn
+        .protocol_version()
+        .map_or("unknown".to_string(), |v| format!("{v:?}"));
+
+    let cipher = conn
+        .negotiated_cipher_suite()
+        .map_or("unknown".to_string(), |cs| format!("{:?}", cs.suite()));
+
+    diagnostics.tls_established(version, cipher);
+}
+
 fn tcp_close(stream: &mut TcpStream) {
     let _ = stream.flush();
     let _ = stream.shutdown(Shutdown::Both);
@@ -155,6 +217,7 @@ fn socket_read(stream: &mut Stream) -> Result<Option<Vec<u8>>> {
 // -----------------------
 
 const MAX_PENDING_WRITE_AGE: Duration = Duration::from_secs(10);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 const WRITE_TICK_MAX_BYTES: usize = 64 * 1024;
 const WRITE_TICK_MAX_MESSAGES: usize = 32;
 const WRITE_CALL_MAX_BYTES: usize = 16 * 1024;
@@ -166,6 +229,7 @@ fn push_bytes_to_socket(
     pending_buf: &mut Option<Vec<u8>>,
     pending_offset: &mut usize,
     pending_since: &mut Instant,
+    diagnostics: &Diagnostics,
 ) -> Result<()> {
     let mut bytes_written = 0usize;
     let mut messages_completed = 0usize;
@@ -211,6 +275,7 @@ fn push_bytes_to_socket(
             Ok(n) => {
                 *pending_offset += n;
                 bytes_written += n;
+                diagnostics.bytes_written(n);
 
                 if *pending_offset >= buf.len() {
                     *pending_buf = None;
@@ -312,8 +377,9 @@ mod tests {
         // Client side channels and connection
         let (mailbox_tx, mailbox_rx) = channel();
         let (write_tx, write_rx) = channel();
-        let (handle, close) =
-            connect(ConnectMode::Local(port), mailbox_tx, write_rx).expect("client connect");
+        let diagnostics = Arc::new(Diagnostics::new());
+        let (handle, close) = connect(ConnectMode::Local(port), mailbox_tx, write_rx, diagnostics)
+            .expect("client connect");
 
         // Send data to server via the client's write channel
         write_tx
@@ -377,8 +443,9 @@ mod tests {
 
         let (mailbox_tx, mailbox_rx) = channel();
         let (write_tx, write_rx) = channel();
-        let (handle, close) =
-            connect(ConnectMode::Local(port), mailbox_tx, write_rx).expect("client connect");
+        let diagnostics = Arc::new(Diagnostics::new());
+        let (handle, close) = connect(ConnectMode::Local(port), mailbox_tx, write_rx, diagnostics)
+            .expect("client connect");
 
         // Queue a large write so the transport loop must balance writes and reads.
         write_tx
@@ -43,6 +43,18 @@ impl NotifyCast {
         (token, rx)
     }
 
+    /// Sends a notification straight to all subscribers
+    pub fn publish(&self, event: String, data: String) {
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|_token, listener| listener.send((event.clone(), data.clone())).is_ok());
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.listeners.lock().unwrap().len()
+    }
+
     pub fn unsubscribe(&self, token: u64) {
         let mut listeners = self.listeners.lock().unwrap();
         listeners.remove(&token);
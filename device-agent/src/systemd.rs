@@ -0,0 +1,315 @@
+// ------------------------
+// --- SYSTEMD SERVICES ---
+// ------------------------
+
+// Integration with systemd, for running the agent in the foreground with
+// `fostrom-device-agent run` as a `Type=notify` service:
+//
+// ```ini
+// [Service]
+// Type=notify
+// ExecStart=/usr/local/bin/fostrom-device-agent run
+// WatchdogSec=180
+// Restart=on-failure
+// ```
+//
+// The agent sends `READY=1` once its servers are listening, `STATUS=`
+// updates as the connection changes, and `WATCHDOG=1` as long as the
+// client loop keeps up, whether connected or not. `WatchdogSec` should
+// be longer than a single connection attempt, which can take a couple of
+// minutes while the kernel retries the TCP handshake.
+//
+// The Unix socket can also be passed in by systemd, with a `.socket` unit
+// whose `ListenStream=` is the agent's socket path.
+//
+// Without `$NOTIFY_SOCKET` or `$LISTEN_FDS`, all of this is a no-op.
+
+use anyhow::{Result, anyhow};
+use nix::{
+    sys::socket::{UnixAddr, getsockname},
+    unistd::getppid,
+};
+use std::{
+    env::var,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::{UnixDatagram, UnixListener},
+    },
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// The first file descriptor passed with socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+static LISTEN_FD_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Set in the worker started by `--supervise`
+static ACTS_FOR_PARENT: AtomicBool = AtomicBool::new(false);
+
+/// Tells systemd that the agent has started
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Updates the status shown by `systemctl status`
+pub fn status(status: &str) {
+    notify(&format!("STATUS={status}"));
+}
+
+/// Tells systemd that the agent is shutting down
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// How often to send `WATCHDOG=1`, which is half of `WatchdogSec`,
+/// or None if the watchdog isn't enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_with(env_var)
+}
+
+fn watchdog_interval_with(var: impl Fn(&str) -> Option<String>) -> Option<Duration> {
+    // WATCHDOG_PID is optional
+    if let Some(pid) = var("WATCHDOG_PID")
+        && !is_this_process(&pid)
+    {
+        return None;
+    }
+
+    let usec = var("WATCHDOG_USEC")?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Whether the agent's Unix socket was passed in by systemd
+pub fn socket_activated() -> bool {
+    socket_activated_with(env_var)
+}
+
+fn socket_activated_with(var: impl Fn(&str) -> Option<String>) -> bool {
+    var("LISTEN_PID").is_some_and(|pid| is_this_process(&pid))
+        && var("LISTEN_FDS")
+            .and_then(|fds| fds.parse::<u32>().ok())
+            .is_some_and(|fds| fds >= 1)
+}
+
+/// Takes the Unix socket passed in by systemd, if any.
+/// Only the first socket is used, and it can only be taken once.
+pub fn take_listener() -> Result<Option<UnixListener>> {
+    if !socket_activated() || LISTEN_FD_TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+
+    if getsockname::<UnixAddr>(LISTEN_FDS_START).is_err() {
+        return Err(anyhow!(
+            "The socket passed with $LISTEN_FDS is not a Unix socket"
+        ));
+    }
+
+    // SAFETY: systemd passes the listening socket as fd 3 to the process
+    // in $LISTEN_PID, and the swap above ensures it is only owned once.
+    let listener = unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) };
+    Ok(Some(listener))
+}
+
+/// Lets the worker take the socket and send the watchdog pings in place
+/// of its supervisor, which is the process that systemd started.
+pub fn act_for_parent() {
+    ACTS_FOR_PARENT.store(true, Ordering::SeqCst);
+}
+
+/// The variables set by systemd are inherited by child processes,
+/// so they only apply when the PID matches this process.
+fn is_this_process(pid: &str) -> bool {
+    let Ok(pid) = pid.parse::<u32>() else {
+        return false;
+    };
+
+    pid == process::id()
+        || (ACTS_FOR_PARENT.load(Ordering::SeqCst) && pid == getppid().as_raw() as u32)
+}
+
+/// The variables are read through this, so that tests don't need to change them
+fn env_var(name: &str) -> Option<String> {
+    var(name).ok()
+}
+
+fn notify(state: &str) {
+    if let Some(path) = env_var("NOTIFY_SOCKET") {
+        let _ = notify_to(&path, state);
+    }
+}
+
+/// Sends the state to the socket at the path,
+/// or in the abstract namespace if the path starts with `@`.
+fn notify_to(path: &str, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    match path.strip_prefix('@') {
+        Some(name) => send_abstract(&socket, name, state),
+        None => socket.send_to(state.as_bytes(), path).map(|_| ()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> std::io::Result<()> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    socket.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, _name: &str, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+// -------------
+// --- TESTS ---
+// -------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// No process has this PID, nor is it the supervisor's
+    const OTHER_PID: &str = "4294967295";
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_is_this_process() {
+        let pid = process::id().to_string();
+        let parent = getppid().as_raw().to_string();
+
+        assert!(is_this_process(&pid));
+        assert!(!is_this_process(OTHER_PID));
+        assert!(!is_this_process(""));
+        assert!(!is_this_process("abc"));
+        assert!(!is_this_process(&format!(" {pid}")));
+
+        // The supervisor's variables apply to its worker
+        assert!(!is_this_process(&parent));
+        act_for_parent();
+        assert!(is_this_process(&parent));
+        assert!(is_this_process(&pid));
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        let pid = process::id().to_string();
+
+        assert_eq!(watchdog_interval_with(env(&[])), None);
+
+        // Half of WatchdogSec, with or without WATCHDOG_PID
+        let interval = Some(Duration::from_secs(60));
+        let vars = [("WATCHDOG_USEC", "120000000")];
+        assert_eq!(watchdog_interval_with(env(&vars)), interval);
+        let vars = [("WATCHDOG_USEC", "120000000"), ("WATCHDOG_PID", &pid)];
+        assert_eq!(watchdog_interval_with(env(&vars)), interval);
+
+        // Meant for another process
+        let vars = [("WATCHDOG_USEC", "120000000"), ("WATCHDOG_PID", OTHER_PID)];
+        assert_eq!(watchdog_interval_with(env(&vars)), None);
+        let vars = [("WATCHDOG_USEC", "120000000"), ("WATCHDOG_PID", "x")];
+        assert_eq!(watchdog_interval_with(env(&vars)), None);
+
+        // Disabled or invalid
+        assert_eq!(watchdog_interval_with(env(&[("WATCHDOG_USEC", "0")])), None);
+        assert_eq!(
+            watchdog_interval_with(env(&[("WATCHDOG_USEC", "-1")])),
+            None
+        );
+        assert_eq!(watchdog_interval_with(env(&[("WATCHDOG_USEC", "")])), None);
+    }
+
+    #[test]
+    fn test_socket_activated() {
+        let pid = process::id().to_string();
+
+        assert!(!socket_activated_with(env(&[])));
+        assert!(socket_activated_with(env(&[
+            ("LISTEN_PID", &pid),
+            ("LISTEN_FDS", "1")
+        ])));
+        assert!(socket_activated_with(env(&[
+            ("LISTEN_PID", &pid),
+            ("LISTEN_FDS", "2")
+        ])));
+
+        // Meant for another process
+        assert!(!socket_activated_with(env(&[
+            ("LISTEN_PID", OTHER_PID),
+            ("LISTEN_FDS", "1")
+        ])));
+        assert!(!socket_activated_with(env(&[("LISTEN_FDS", "1")])));
+
+        // No sockets passed in
+        assert!(!socket_activated_with(env(&[
+            ("LISTEN_PID", &pid),
+            ("LISTEN_FDS", "0")
+        ])));
+        assert!(!socket_activated_with(env(&[
+            ("LISTEN_PID", &pid),
+            ("LISTEN_FDS", "x")
+        ])));
+        assert!(!socket_activated_with(env(&[("LISTEN_PID", &pid)])));
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = std::env::temp_dir().join(format!("fostrom-systemd-test-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = [0; 256];
+
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        notify_to(path.to_str().unwrap(), "STATUS=Connected to Fostrom").unwrap();
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=Connected to Fostrom");
+
+        // Nobody listening at the path
+        drop(socket);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(notify_to(path.to_str().unwrap(), "WATCHDOG=1").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify_abstract() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("fostrom-systemd-test-{}", process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        notify_to(&format!("@{name}"), "WATCHDOG=1").unwrap();
+        let mut buf = [0; 256];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+    }
+}